@@ -0,0 +1,79 @@
+//! Compares the bytes sent when appending to a large note: the whole model, as it was sent
+//! before, against the chunks and manifests of `Transfer`, the manifests being counted apart.
+//!
+//! Run with `cargo run --release --example append_transfer`.
+
+use linux_files::model::{Model, Note};
+use linux_files::networking::Message;
+use linux_files::sync_state::Version;
+use linux_files::transfer::Transfer;
+use std::collections::HashMap;
+use websocket::OwnedMessage;
+
+const NOTE_SIZE: usize = 4 * 1024 * 1024;
+const APPENDS: usize = 100;
+
+fn main() {
+    let mut model = Model::new();
+    let mut note = Note::new();
+    note.title = String::from("Journal");
+    let mut line = 0;
+    while note.body.len() < NOTE_SIZE {
+        note.body
+            .push_str(&format!("line {} of the journal, nothing special\n", line));
+        line += 1;
+    }
+    model.notes.insert(String::from("journal.txt"), note);
+
+    let mut transfer = Transfer::new();
+    let blobs = HashMap::new();
+    let (chunk_bytes, manifest_bytes) = sent_bytes(&mut transfer, &model, &blobs);
+    println!(
+        "initial model: {} bytes as a whole model, {} bytes with chunks ({} of manifest)",
+        serde_json::to_vec(&model).unwrap().len(),
+        chunk_bytes + manifest_bytes,
+        manifest_bytes
+    );
+
+    let mut model_bytes = 0;
+    let mut chunk_bytes = 0;
+    let mut manifest_bytes = 0;
+    for append in 0..APPENDS {
+        let note = model.notes.get_mut("journal.txt").unwrap();
+        note.body.push_str(&format!("appended line {}\n", append));
+        model_bytes += serde_json::to_vec(&model).unwrap().len();
+        let (append_chunk_bytes, append_manifest_bytes) = sent_bytes(&mut transfer, &model, &blobs);
+        chunk_bytes += append_chunk_bytes;
+        manifest_bytes += append_manifest_bytes;
+    }
+    println!(
+        "{} appends: {} bytes as whole models, {} bytes with chunks ({} of manifests, {:.1}x less)",
+        APPENDS,
+        model_bytes,
+        chunk_bytes + manifest_bytes,
+        manifest_bytes,
+        model_bytes as f64 / (chunk_bytes + manifest_bytes) as f64
+    );
+}
+
+/// Bytes of the chunks and of the manifest sending the model, in the frames they are sent in.
+fn sent_bytes(
+    transfer: &mut Transfer,
+    model: &Model,
+    blobs: &HashMap<String, Vec<u8>>,
+) -> (usize, usize) {
+    let mut chunk_bytes = 0;
+    let mut manifest_bytes = 0;
+    for message in transfer.send(0, model, &Version::default(), blobs).unwrap() {
+        let bytes = match message.to_frame().unwrap() {
+            OwnedMessage::Text(text) => text.len(),
+            OwnedMessage::Binary(data) => data.len(),
+            _ => 0,
+        };
+        match message {
+            Message::Manifest(_) => manifest_bytes += bytes,
+            _ => chunk_bytes += bytes,
+        }
+    }
+    (chunk_bytes, manifest_bytes)
+}
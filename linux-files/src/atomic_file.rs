@@ -0,0 +1,215 @@
+use std::fs::{metadata, remove_file, rename, File, OpenOptions, Permissions};
+use std::io::Write;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::SystemTime;
+use tracing::warn;
+
+/// Suffix of the temporary files written before being renamed over the files of the workspace.
+pub const TEMP_FILE_SUFFIX: &str = ".notes-tmp";
+
+/// How much of a write is flushed to the disk before it is considered done.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Durability {
+    /// Leave flushing to the operating system, after a crash the file can be empty.
+    None,
+    /// Flush the contents before renaming, after a crash the file has either version.
+    File,
+    /// Also flush the folder, after a crash the file has the new version.
+    Folder,
+}
+
+impl Durability {
+    pub fn parse(text: &str) -> Result<Durability, String> {
+        match text {
+            "none" => Ok(Durability::None),
+            "file" => Ok(Durability::File),
+            "folder" => Ok(Durability::Folder),
+            _ => Err(format!(
+                "invalid fsync mode '{}', expected 'none', 'file' or 'folder'",
+                text
+            )),
+        }
+    }
+}
+
+/// Whether a path is a temporary file of `write`, which is never synced.
+pub fn is_temp_file(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|file_name| file_name.to_str())
+        .map(|file_name| file_name.ends_with(TEMP_FILE_SUFFIX))
+        .unwrap_or(false)
+}
+
+/// Replaces the contents of a file at once: they are written to a temporary file of the same
+/// folder which is then renamed over the file, so readers never see a partial write. The
+/// permissions of the replaced file are kept, the temporary file has them from its creation.
+pub fn write(
+    path: &Path,
+    data: &[u8],
+    modified: Option<SystemTime>,
+    durability: Durability,
+) -> Result<(), String> {
+    let permissions = metadata(path).map(|metadata| metadata.permissions()).ok();
+    write_with_permissions(path, data, modified, permissions, durability)
+}
+
+/// Like `write`, for a file only its owner can read and write, like the keys of the peers.
+pub fn write_private(path: &Path, data: &[u8], durability: Durability) -> Result<(), String> {
+    let permissions = Permissions::from_mode(0o600);
+    write_with_permissions(path, data, None, Some(permissions), durability)
+}
+
+fn write_with_permissions(
+    path: &Path,
+    data: &[u8],
+    modified: Option<SystemTime>,
+    permissions: Option<Permissions>,
+    durability: Durability,
+) -> Result<(), String> {
+    let temp_path = temp_path(path)?;
+    if let Err(error) = write_temp_file(&temp_path, data, modified, permissions, durability) {
+        if let Err(remove_error) = remove_file(&temp_path) {
+            warn!(path = ?temp_path, error = %remove_error, "could not remove temporary file");
+        }
+        return Err(error);
+    }
+    if let Err(error) = rename(&temp_path, path) {
+        if let Err(remove_error) = remove_file(&temp_path) {
+            warn!(path = ?temp_path, error = %remove_error, "could not remove temporary file");
+        }
+        return Err(format!("cannot rename '{:?}' -> {}", temp_path, error));
+    }
+    if durability == Durability::Folder {
+        let folder = match path.parent() {
+            Some(folder) if !folder.as_os_str().is_empty() => folder,
+            _ => Path::new("."),
+        };
+        match File::open(folder) {
+            Ok(folder_file) => {
+                if let Err(error) = folder_file.sync_all() {
+                    return Err(format!("cannot sync folder '{:?}' -> {}", folder, error));
+                }
+            }
+            Err(error) => return Err(format!("cannot open folder '{:?}' -> {}", folder, error)),
+        }
+    }
+    Ok(())
+}
+
+fn temp_path(path: &Path) -> Result<PathBuf, String> {
+    match path.file_name().and_then(|file_name| file_name.to_str()) {
+        // hidden and unique to this process, so two instances writing the same folder don't
+        // share temporary files
+        Some(file_name) => Ok(path.with_file_name(format!(
+            ".{}.{}{}",
+            file_name,
+            process::id(),
+            TEMP_FILE_SUFFIX
+        ))),
+        None => Err(format!("'{:?}' is not a valid file name", path)),
+    }
+}
+
+fn write_temp_file(
+    temp_path: &Path,
+    data: &[u8],
+    modified: Option<SystemTime>,
+    permissions: Option<Permissions>,
+    durability: Durability,
+) -> Result<(), String> {
+    let mut file = match OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(
+            permissions
+                .as_ref()
+                .map_or(0o666, |permissions| permissions.mode()),
+        )
+        .open(temp_path)
+    {
+        Ok(file) => file,
+        Err(error) => return Err(format!("cannot create '{:?}' -> {}", temp_path, error)),
+    };
+    // the mode of a new file is masked by the umask, and a temporary file left by a crash
+    // keeps its own
+    if let Some(permissions) = permissions {
+        if let Err(error) = file.set_permissions(permissions) {
+            return Err(format!(
+                "cannot set permissions of '{:?}' -> {}",
+                temp_path, error
+            ));
+        }
+    }
+    if let Err(error) = file.write_all(data) {
+        return Err(format!("cannot write '{:?}' -> {}", temp_path, error));
+    }
+    if let Some(modified) = modified {
+        if let Err(error) = file.set_modified(modified) {
+            return Err(format!(
+                "cannot set modification time of '{:?}' -> {}",
+                temp_path, error
+            ));
+        }
+    }
+    if durability != Durability::None {
+        if let Err(error) = file.sync_all() {
+            return Err(format!("cannot sync '{:?}' -> {}", temp_path, error));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn permissions_of_the_replaced_file_are_kept() {
+        let folder = tempfile::tempdir().unwrap();
+        let path = folder.path().join("note.txt");
+        fs::write(&path, "first").unwrap();
+        fs::set_permissions(&path, Permissions::from_mode(0o640)).unwrap();
+        write(&path, b"second", None, Durability::File).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"second");
+        let mode = metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o640);
+    }
+
+    #[test]
+    fn private_files_are_only_for_their_owner() {
+        let folder = tempfile::tempdir().unwrap();
+        let path = folder.path().join("keys");
+        fs::write(&path, "first").unwrap();
+        fs::set_permissions(&path, Permissions::from_mode(0o644)).unwrap();
+        write_private(&path, b"second", Durability::File).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"second");
+        let mode = metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn no_temporary_file_is_left_on_error() {
+        let folder = tempfile::tempdir().unwrap();
+        // a file can't be renamed over a folder that isn't empty
+        let path = folder.path().join("folder");
+        fs::create_dir(&path).unwrap();
+        fs::write(path.join("note.txt"), "note").unwrap();
+        assert!(write(&path, b"data", None, Durability::File).is_err());
+        let file_names: Vec<_> = fs::read_dir(folder.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(file_names, ["folder"]);
+        assert!(write(
+            &folder.path().join("missing/note.txt"),
+            b"data",
+            None,
+            Durability::None
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,46 @@
+use std::path::Path;
+use std::str;
+
+use crate::chunks;
+use crate::model::Attachment;
+
+/// Extensions of the files that are attachments even when their contents are valid UTF-8,
+/// with their media type.
+const MEDIA_TYPES: [(&str, &str); 10] = [
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("svg", "image/svg+xml"),
+    ("pdf", "application/pdf"),
+    ("zip", "application/zip"),
+    ("mp3", "audio/mpeg"),
+    ("mp4", "video/mp4"),
+];
+
+const DEFAULT_MEDIA_TYPE: &str = "application/octet-stream";
+
+/// Files are attachments when they have the extension of a binary format or their contents
+/// are not valid UTF-8, other files are notes.
+pub fn is_attachment(path: &Path, data: &[u8]) -> bool {
+    known_media_type(path).is_some() || str::from_utf8(data).is_err()
+}
+
+pub fn attachment(path: &Path, data: &[u8]) -> Attachment {
+    Attachment {
+        hash: chunks::hash(data),
+        size: data.len() as u64,
+        media_type: known_media_type(path)
+            .unwrap_or(DEFAULT_MEDIA_TYPE)
+            .to_string(),
+    }
+}
+
+fn known_media_type(path: &Path) -> Option<&'static str> {
+    let extension = path.extension()?.to_str()?.to_lowercase();
+    MEDIA_TYPES
+        .iter()
+        .find(|(known_extension, _)| *known_extension == extension)
+        .map(|(_, media_type)| *media_type)
+}
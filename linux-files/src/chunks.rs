@@ -0,0 +1,233 @@
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+/// Chunks are never smaller than this, except for the last one of some contents.
+const MIN_CHUNK_SIZE: usize = 2 * 1024;
+/// Chunks are cut at this size when no boundary was found before.
+const MAX_CHUNK_SIZE: usize = 64 * 1024;
+/// Number of high bits of the rolling hash that must be zero at a boundary, which gives
+/// chunks of 8 KiB on average after the minimum size.
+const BOUNDARY_BITS: u32 = 13;
+/// Number of bytes the gear hash depends on, since each byte is shifted out after 64 steps.
+const WINDOW_SIZE: usize = 64;
+
+/// Random values added to the rolling hash for each byte value.
+static GEAR: [u64; 256] = gear_table();
+
+/// Piece of contents addressed by its hash. Only its data is sent, in a binary frame, the
+/// hash is computed again by the peer.
+#[derive(Clone)]
+pub struct Chunk {
+    pub hash: String,
+    pub data: Vec<u8>,
+}
+
+impl Chunk {
+    pub fn new(data: Vec<u8>) -> Chunk {
+        Chunk {
+            hash: hash(&data),
+            data,
+        }
+    }
+}
+
+/// Hexadecimal SHA-256 of some data.
+pub fn hash(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+/// Splits contents in chunks whose boundaries depend on the contents themselves (with a gear
+/// rolling hash), so that inserting or appending data only changes the chunks around it.
+pub fn split(data: &[u8]) -> Vec<&[u8]> {
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    while start < data.len() {
+        let end = start + boundary(&data[start..]);
+        chunks.push(&data[start..end]);
+        start = end;
+    }
+    chunks
+}
+
+/// Size of the first chunk of the data.
+fn boundary(data: &[u8]) -> usize {
+    if data.len() <= MIN_CHUNK_SIZE {
+        return data.len();
+    }
+    let mut hash: u64 = 0;
+    let end = data.len().min(MAX_CHUNK_SIZE);
+    for (index, byte) in data
+        .iter()
+        .enumerate()
+        .take(end)
+        .skip(MIN_CHUNK_SIZE - WINDOW_SIZE)
+    {
+        hash = (hash << 1).wrapping_add(GEAR[*byte as usize]);
+        // the high bits depend on the whole window, the low ones only on the last bytes
+        if index >= MIN_CHUNK_SIZE && hash >> (64 - BOUNDARY_BITS) == 0 {
+            return index + 1;
+        }
+    }
+    end
+}
+
+const fn gear_table() -> [u64; 256] {
+    // splitmix64, any fixed pseudo-random values work as long as every instance uses the same
+    let mut table = [0; 256];
+    let mut state: u64 = 0x9e3779b97f4a7c15;
+    let mut index = 0;
+    while index < table.len() {
+        state = state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut value = state;
+        value = (value ^ (value >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        value = (value ^ (value >> 27)).wrapping_mul(0x94d049bb133111eb);
+        table[index] = value ^ (value >> 31);
+        index += 1;
+    }
+    table
+}
+
+/// Chunks by hash.
+pub struct ChunkStore {
+    chunks: HashMap<String, Vec<u8>>,
+}
+
+impl ChunkStore {
+    pub fn new() -> ChunkStore {
+        ChunkStore {
+            chunks: HashMap::new(),
+        }
+    }
+
+    /// Splits contents in chunks, stores them and returns their hashes in order.
+    pub fn insert(&mut self, data: &[u8]) -> Vec<String> {
+        split(data)
+            .into_iter()
+            .map(|chunk| {
+                let hash = hash(chunk);
+                self.chunks
+                    .entry(hash.clone())
+                    .or_insert_with(|| chunk.to_vec());
+                hash
+            })
+            .collect()
+    }
+
+    /// Stores a chunk received from a peer after checking its hash.
+    pub fn insert_chunk(&mut self, chunk: Chunk) -> Result<(), String> {
+        if hash(&chunk.data) != chunk.hash {
+            return Err(format!("chunk doesn't match its hash '{}'", chunk.hash));
+        }
+        self.chunks.insert(chunk.hash, chunk.data);
+        Ok(())
+    }
+
+    pub fn get(&self, hash: &str) -> Option<&Vec<u8>> {
+        self.chunks.get(hash)
+    }
+
+    pub fn contains(&self, hash: &str) -> bool {
+        self.chunks.contains_key(hash)
+    }
+
+    /// Drops the chunks for which `keep` is false.
+    pub fn retain(&mut self, mut keep: impl FnMut(&str) -> bool) {
+        self.chunks.retain(|hash, _| keep(hash));
+    }
+
+    /// Puts contents back together from the hashes of their chunks.
+    pub fn assemble(&self, hashes: &[String]) -> Result<Vec<u8>, String> {
+        let mut data = Vec::new();
+        for hash in hashes {
+            match self.chunks.get(hash) {
+                Some(chunk) => data.extend_from_slice(chunk),
+                None => return Err(format!("missing chunk '{}'", hash)),
+            }
+        }
+        Ok(data)
+    }
+}
+
+impl Default for ChunkStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Pseudo-random bytes, the same on every run.
+    fn random_data(size: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..size)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (state >> 56) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn chunk_sizes_stay_within_bounds() {
+        for data in [random_data(1024 * 1024, 1), vec![0; 512 * 1024]] {
+            let chunks = split(&data);
+            assert!(chunks.len() > 1);
+            let (last, others) = chunks.split_last().unwrap();
+            assert!(others.iter().all(|chunk| chunk.len() >= MIN_CHUNK_SIZE));
+            assert!(chunks.iter().all(|chunk| chunk.len() <= MAX_CHUNK_SIZE));
+            assert!(!last.is_empty());
+            assert_eq!(chunks.concat(), data);
+        }
+        assert!(split(&[]).is_empty());
+        assert_eq!(split(b"small"), [b"small"]);
+    }
+
+    #[test]
+    fn boundaries_stay_put_on_append() {
+        let data = random_data(512 * 1024, 2);
+        let chunks = split(&data);
+        let mut appended = data.clone();
+        appended.extend_from_slice(&random_data(1000, 3));
+        let appended_chunks = split(&appended);
+        let kept = chunks.len() - 1;
+        assert_eq!(appended_chunks[..kept], chunks[..kept]);
+    }
+
+    #[test]
+    fn boundaries_stay_put_on_prepend() {
+        let data = random_data(512 * 1024, 4);
+        let chunks = split(&data);
+        let mut prepended = random_data(1000, 5);
+        prepended.extend_from_slice(&data);
+        let prepended_chunks = split(&prepended);
+        // the boundaries are found again after the first chunks
+        let moved = chunks
+            .iter()
+            .filter(|chunk| !prepended_chunks.contains(chunk))
+            .count();
+        assert!(moved <= 2, "{} of {} chunks moved", moved, chunks.len());
+    }
+
+    #[test]
+    fn assembly_gives_back_the_contents() {
+        let data = random_data(256 * 1024, 6);
+        let mut store = ChunkStore::new();
+        let hashes = store.insert(&data);
+        assert_eq!(store.assemble(&hashes).unwrap(), data);
+
+        let mut other_store = ChunkStore::new();
+        for hash in hashes.iter().skip(1) {
+            other_store
+                .insert_chunk(Chunk::new(store.get(hash).unwrap().clone()))
+                .unwrap();
+        }
+        assert!(other_store.assemble(&hashes).is_err());
+        let mut chunk = Chunk::new(store.get(&hashes[0]).unwrap().clone());
+        chunk.data[0] ^= 1;
+        assert!(other_store.insert_chunk(chunk).is_err());
+    }
+}
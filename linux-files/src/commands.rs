@@ -0,0 +1,412 @@
+use linux_files::atomic_file::Durability;
+use linux_files::control;
+use linux_files::directory_store::DirectoryStore;
+use linux_files::discovery::{self, DEFAULT_DISCOVERY_TIME};
+use linux_files::fs_watcher::DEFAULT_DEBOUNCE;
+use linux_files::git_repository::GitRepository;
+use linux_files::history::History;
+use linux_files::model::{self, DiffLine};
+use linux_files::networking::{ConnectionId, Event, Message, Network};
+use linux_files::note_file;
+use linux_files::note_store::{self, NoteStore};
+use linux_files::pairing::{self, Exchange, PairingMessage, PeerKeys, Side};
+use linux_files::sqlite_store::SqliteStore;
+use linux_files::sync_state::SyncState;
+use linux_files::trash;
+
+use chrono::{DateTime, Local};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Commands run on the notes of a workspace instead of syncing it.
+pub const WORKSPACE_COMMANDS: [&str; 4] = ["history", "restore", "trash", "git-import"];
+
+/// Commands run on a SQLite workspace instead of syncing it.
+pub const DATABASE_COMMANDS: [&str; 4] = ["search", "recent", "rewind", "export"];
+
+/// Runs one of `DATABASE_COMMANDS` on the database at `path`, `arguments` being the ones
+/// following the command.
+pub fn run_database_command(
+    path: &Path,
+    command: &str,
+    arguments: &[String],
+) -> Result<(), String> {
+    if !path.is_file() {
+        return Err(format!("'{:?}' is not a SQLite workspace", path));
+    }
+    let mut store = SqliteStore::open(path)?;
+    let argument = match arguments.first() {
+        Some(argument) => argument,
+        None => return Err(format!("'{}' needs an argument", command)),
+    };
+    match command {
+        "search" => {
+            for result in store.search(argument)? {
+                println!("{}: {}", result.key, result.snippet);
+            }
+        }
+        "recent" => {
+            let days: u64 = match argument.parse() {
+                Ok(days) => days,
+                Err(error) => {
+                    return Err(format!(
+                        "invalid number of days '{}' -> {}",
+                        argument, error
+                    ))
+                }
+            };
+            let since = SystemTime::now() - Duration::from_secs(days * 24 * 60 * 60);
+            for key in store.modified_since(since)? {
+                println!("{}", key);
+            }
+        }
+        "rewind" => {
+            let time = store.parse_time(argument)?;
+            store.restore(time)?;
+        }
+        "export" => {
+            let mut folder = DirectoryStore::open(
+                Path::new(argument),
+                Durability::File,
+                DEFAULT_DEBOUNCE,
+                None,
+            )?;
+            note_store::copy(&store, &mut folder)?;
+        }
+        _ => return Err(format!("unknown command '{}'", command)),
+    }
+    Ok(())
+}
+
+/// Runs one of `WORKSPACE_COMMANDS`, `arguments` being the ones following the command.
+pub fn run_workspace_command(
+    store: &mut dyn NoteStore,
+    history: &mut History,
+    workspace_path: &Path,
+    command: &str,
+    arguments: &[String],
+) -> Result<(), String> {
+    if command == "trash" {
+        return run_trash_command(store, arguments);
+    }
+    if command == "git-import" {
+        // the commits made before the workspace was synced become revisions of the notes
+        if !workspace_path.join(".git").exists() {
+            return Err(format!("'{:?}' is not a git repository", workspace_path));
+        }
+        let git_repository = GitRepository::open(workspace_path)?;
+        let count = history.import(git_repository.revisions()?)?;
+        println!("imported {} revisions", count);
+        return Ok(());
+    }
+    let key = match arguments.first() {
+        Some(key) => key,
+        None => return Err(format!("'{}' needs a note", command)),
+    };
+    let revisions = history.revisions(key)?;
+    if revisions.is_empty() {
+        return Err(format!("no revision of '{}'", key));
+    }
+    match command {
+        "history" => {
+            let mut previous_text = String::new();
+            for (index, revision) in revisions.iter().enumerate() {
+                let time = DateTime::<Local>::from(UNIX_EPOCH + Duration::from_secs(revision.time));
+                let change = if revision.note.is_some() {
+                    "revision"
+                } else {
+                    "deleted in revision"
+                };
+                println!(
+                    "{} {} by {}, {}",
+                    change,
+                    index + 1,
+                    revision.author,
+                    time.format("%Y-%m-%d %H:%M:%S")
+                );
+                let text = revision.text();
+                for line in model::diff_lines(&previous_text, &text) {
+                    match line {
+                        DiffLine::Same(_) => {}
+                        DiffLine::Added(line) => println!("+ {}", line),
+                        DiffLine::Removed(line) => println!("- {}", line),
+                    }
+                }
+                println!();
+                previous_text = text;
+            }
+        }
+        "restore" => {
+            let number: usize = match arguments.get(1).map(|number| number.parse()) {
+                Some(Ok(number)) => number,
+                _ => return Err(String::from("'restore' needs a revision number")),
+            };
+            let revision = match number.checked_sub(1).and_then(|index| revisions.get(index)) {
+                Some(revision) => revision,
+                None => return Err(format!("'{}' has no revision {}", key, number)),
+            };
+            // the running instances sync it like any other change
+            match &revision.note {
+                Some(note) => store.write(key, note_file::serialize(note)?.as_bytes(), None)?,
+                None => store.delete(key)?,
+            }
+        }
+        _ => return Err(format!("unknown command '{}'", command)),
+    }
+    Ok(())
+}
+
+/// `trash list` lists the deleted notes and attachments, `trash restore <key>` puts one back.
+fn run_trash_command(store: &mut dyn NoteStore, arguments: &[String]) -> Result<(), String> {
+    match arguments.first().map(|argument| argument.as_str()) {
+        Some("list") => {
+            for entry in store.list()? {
+                let key = match trash::original_key(&entry.key) {
+                    Some(key) if !entry.is_folder => key,
+                    _ => continue,
+                };
+                match store.read(&entry.key)?.modified {
+                    Some(modified) => println!(
+                        "{}, deleted {}",
+                        key,
+                        DateTime::<Local>::from(modified).format("%Y-%m-%d %H:%M:%S")
+                    ),
+                    None => println!("{}", key),
+                }
+            }
+            Ok(())
+        }
+        Some("restore") => {
+            let key = match arguments.get(1) {
+                Some(key) => key,
+                None => return Err(String::from("'trash restore' needs a note")),
+            };
+            if store.list()?.iter().any(|entry| entry.key == *key) {
+                return Err(format!("'{}' already exists", key));
+            }
+            // written as a new modification, so that it is newer than its tombstone on the
+            // peers
+            let file = store.read(&trash::trash_key(key))?;
+            store.write(key, &file.data, None)?;
+            store.delete(&trash::trash_key(key))
+        }
+        _ => Err(String::from("'trash' needs 'list' or 'restore'")),
+    }
+}
+
+/// Lists the instances advertised on the network, waiting `--time <seconds>` for them.
+pub fn run_discover_command(arguments: &[String]) -> Result<(), String> {
+    let time = match arguments.iter().position(|argument| argument == "--time") {
+        Some(index) => match arguments.get(index + 1).map(|value| value.parse()) {
+            Some(Ok(seconds)) => Duration::from_secs(seconds),
+            _ => return Err(String::from("--time needs a number of seconds")),
+        },
+        None => DEFAULT_DISCOVERY_TIME,
+    };
+    let peers = discovery::discover(time)?;
+    if peers.is_empty() {
+        println!("no instance found");
+    }
+    for peer in peers {
+        println!(
+            "{} ({}) workspace '{}': {}",
+            peer.name,
+            peer.instance_id,
+            peer.workspace,
+            peer.urls.join(" ")
+        );
+    }
+    Ok(())
+}
+
+/// Shows the status of the instance running on a workspace, as JSON with `--json`.
+pub fn run_status_command(workspace_path: &Path, arguments: &[String]) -> Result<(), String> {
+    let status = control::request_status(workspace_path)?;
+    if arguments.iter().any(|argument| argument == "--json") {
+        let text = serde_json::to_string_pretty(&status)
+            .map_err(|error| format!("cannot serialize status -> {}", error))?;
+        println!("{}", text);
+        return Ok(());
+    }
+    println!(
+        "{} ({}) workspace '{}'",
+        status.name, status.instance_id, status.workspace
+    );
+    println!("last sync: {}", format_time(status.last_sync));
+    println!("pending changes: {}", status.pending_changes);
+    if status.paused {
+        println!("sync is paused");
+    }
+    println!("peers:");
+    if status.peers.is_empty() {
+        println!("  none");
+    }
+    for peer in status.peers {
+        println!(
+            "  {} ({}) connected since {}, last sync {}",
+            peer.name,
+            peer.instance_id,
+            format_time(Some(peer.connected_since)),
+            format_time(peer.last_sync)
+        );
+    }
+    if !status.conflicts.is_empty() {
+        println!("conflicts:");
+    }
+    for conflict in status.conflicts {
+        println!(
+            "  {} with {}, kept the versions of {} of {}",
+            format_time(Some(conflict.time)),
+            conflict.peer,
+            conflict.kept,
+            conflict.keys.join(", ")
+        );
+    }
+    if !status.errors.is_empty() {
+        println!("errors:");
+    }
+    for error in status.errors {
+        println!("  {} {}", format_time(Some(error.time)), error.message);
+    }
+    Ok(())
+}
+
+fn format_time(seconds: Option<u64>) -> String {
+    match seconds {
+        Some(seconds) => DateTime::<Local>::from(UNIX_EPOCH + Duration::from_secs(seconds))
+            .format("%Y-%m-%d %H:%M:%S")
+            .to_string(),
+        None => String::from("never"),
+    }
+}
+
+/// Pairs the instance of a workspace with another one, so that they can authenticate each
+/// other: `pair --show` shows a code and waits for the peer, advertised on the network with
+/// `--advertise`, `pair <code>` connects to the instance showing it, given with `--peer` or
+/// else found on the network.
+pub fn run_pair_command(
+    workspace_path: &Path,
+    name: &str,
+    listen_address: &str,
+    peer_addresses: &[String],
+    arguments: &[String],
+) -> Result<(), String> {
+    let sync_state = SyncState::open(workspace_path)?;
+    let mut peer_keys = PeerKeys::open(workspace_path)?;
+    let (side, code, network, _advertisement) = match arguments.first().map(|a| a.as_str()) {
+        Some("--show") => {
+            let network = Network::new(Some(listen_address), &[])?;
+            let workspace_name = workspace_path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default();
+            // anyone reaching the instance can try a code, so it is only found when asked
+            let advertise = arguments.iter().any(|argument| argument == "--advertise");
+            let advertisement = match advertise {
+                true => match discovery::advertise(
+                    name,
+                    &sync_state.instance_id,
+                    &workspace_name,
+                    listen_address,
+                    true,
+                ) {
+                    Ok(daemon) => Some(daemon),
+                    Err(error) => {
+                        eprintln!("could not advertise instance: {}", error);
+                        None
+                    }
+                },
+                false => None,
+            };
+            let code = pairing::new_code();
+            println!("pairing code: {}", code);
+            (Side::Show, code, network, advertisement)
+        }
+        Some(code) => {
+            let peer_address = match peer_addresses.first() {
+                Some(peer_address) => peer_address.clone(),
+                None => find_pairing_peer(&sync_state.instance_id)?,
+            };
+            let network = Network::new(None, &[peer_address])?;
+            (Side::Join, code.to_string(), network, None)
+        }
+        None => return Err(String::from("'pair' needs a code or --show")),
+    };
+    let peer_side = match side {
+        Side::Show => Side::Join,
+        Side::Join => Side::Show,
+    };
+    let mut exchange = Some(Exchange::new(side, &code, &sync_state.instance_id));
+    // connection, name, instance id and key of the peer, once its element is received: the
+    // exchange only gives one key, the other connections are ignored
+    let mut peer: Option<(ConnectionId, String, String, Vec<u8>)> = None;
+    for event in network.as_receiver() {
+        match event {
+            Event::Connected(id, _) if peer.is_none() => {
+                let element = match &exchange {
+                    Some(exchange) => exchange.element(),
+                    None => continue,
+                };
+                let start = PairingMessage::Start {
+                    name: name.to_string(),
+                    instance_id: sync_state.instance_id.clone(),
+                    element,
+                };
+                network.send(id, &Message::Pairing(start))?;
+            }
+            Event::Message(
+                id,
+                Message::Pairing(PairingMessage::Start {
+                    name,
+                    instance_id,
+                    element,
+                }),
+            ) if exchange.is_some() => {
+                let exchange = exchange.take().expect("the exchange isn't used yet");
+                let key = exchange.key(&instance_id, &element)?;
+                let confirmation = pairing::confirmation(&key, side);
+                network.send(id, &Message::Pairing(PairingMessage::Confirm(confirmation)))?;
+                peer = Some((id, name, instance_id, key));
+            }
+            Event::Message(id, Message::Pairing(PairingMessage::Confirm(confirmation))) => {
+                let (_, name, instance_id, key) = match &peer {
+                    Some(peer) if peer.0 == id => peer,
+                    Some(_) => continue,
+                    None => return Err(String::from("the peer confirmed before starting")),
+                };
+                // a peer with another code derived another key
+                if !pairing::verify(pairing::confirmation(key, peer_side), &confirmation) {
+                    return Err(String::from("the codes don't match"));
+                }
+                peer_keys.insert(instance_id, name, key)?;
+                println!("paired with {} ({})", name, instance_id);
+                return Ok(());
+            }
+            Event::Connected(_, _) | Event::Message(_, _) => {}
+            Event::Disconnected(id) => {
+                if matches!(peer, Some((peer_id, ..)) if peer_id == id) {
+                    return Err(String::from("the peer left before confirming"));
+                }
+            }
+        }
+    }
+    Err(String::from("the connections are closed"))
+}
+
+/// URL of the only instance of the network showing a pairing code.
+fn find_pairing_peer(instance_id: &str) -> Result<String, String> {
+    let mut urls: Vec<String> = discovery::discover(DEFAULT_DISCOVERY_TIME)?
+        .into_iter()
+        .filter(|peer| peer.pairing && peer.instance_id != instance_id)
+        .filter_map(|peer| peer.urls.into_iter().next())
+        .collect();
+    match urls.len() {
+        0 => Err(String::from(
+            "no instance shows a pairing code, run 'pair --show --advertise' on the other one",
+        )),
+        1 => Ok(urls.remove(0)),
+        _ => Err(String::from(
+            "several instances show a pairing code, give the URL of one with --peer",
+        )),
+    }
+}
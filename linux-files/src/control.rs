@@ -0,0 +1,436 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fs;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tracing::warn;
+
+use crate::status::Status;
+
+/// Methods of the control socket.
+pub const METHODS: [&str; 9] = [
+    "status",
+    "list_peers",
+    "list_notes",
+    "get_note",
+    "put_note",
+    "pause",
+    "resume",
+    "rescan",
+    "resolve_conflict",
+];
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// The command was understood but failed.
+const COMMAND_FAILED: i64 = -32000;
+
+/// How long `request` waits for the response, the commands being run between the changes the
+/// instance syncs.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Commands of the control socket, the JSON-RPC methods with their named params.
+#[derive(Deserialize)]
+#[serde(tag = "method", content = "params", rename_all = "snake_case")]
+pub enum Command {
+    Status,
+    ListPeers,
+    ListNotes,
+    /// Contents of the file of a note.
+    GetNote {
+        key: String,
+    },
+    /// Writes the file of a note, synced like any change made in the workspace.
+    PutNote {
+        key: String,
+        text: String,
+    },
+    /// Stops sending and receiving changes, the local ones are sent once resumed.
+    Pause,
+    Resume,
+    /// Reads the whole workspace again.
+    Rescan,
+    /// Ends a conflict on a note, keeping the version that was kept or putting back the one
+    /// that was discarded.
+    ResolveConflict {
+        key: String,
+        keep: Resolution,
+    },
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Resolution {
+    Kept,
+    Discarded,
+}
+
+/// Note as `list_notes` returns it.
+#[derive(Serialize, Deserialize)]
+pub struct NoteEntry {
+    pub key: String,
+    pub title: String,
+    pub modified: u64,
+}
+
+/// Command sent to the instance, which answers it with its result.
+pub struct Call {
+    pub command: Command,
+    sender: Sender<Result<Value, String>>,
+}
+
+impl Call {
+    pub fn answer(self, result: Result<Value, String>) {
+        // the client may be gone
+        let _ = self.sender.send(result);
+    }
+}
+
+#[derive(Deserialize)]
+struct Request {
+    jsonrpc: String,
+    /// Missing for a notification, which gets no response.
+    #[serde(default)]
+    id: Option<Value>,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Response {
+    jsonrpc: String,
+    id: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<ResponseError>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ResponseError {
+    code: i64,
+    message: String,
+}
+
+/// Folder of the control socket, `<workspace>.control`, that only its owner can open.
+pub fn control_directory(workspace_path: &Path) -> PathBuf {
+    let mut path = workspace_path.as_os_str().to_owned();
+    path.push(".control");
+    PathBuf::from(path)
+}
+
+/// Unix socket of a running instance, `<workspace>.control/socket`, through which it is
+/// controlled with JSON-RPC 2.0, a request and its response being a line of JSON each.
+pub fn control_path(workspace_path: &Path) -> PathBuf {
+    control_directory(workspace_path).join("socket")
+}
+
+/// Answers the requests on the control socket of the workspace, until the instance stops.
+/// `status` and `list_peers` are answered from the status, the other commands are received
+/// from the returned receiver. Fails when another instance answers on the socket already.
+pub fn serve(workspace_path: &Path, status: Arc<Mutex<Status>>) -> Result<Receiver<Call>, String> {
+    // the socket is created with the umask of the process, so it is only reachable through a
+    // folder of its user before its own permissions are set
+    let directory = control_directory(workspace_path);
+    match fs::DirBuilder::new().mode(0o700).create(&directory) {
+        Ok(()) => {}
+        Err(error) if error.kind() == ErrorKind::AlreadyExists => {}
+        Err(error) => return Err(format!("cannot create '{:?}' -> {}", directory, error)),
+    }
+    // only the owner of an existing folder can change its permissions
+    fs::set_permissions(&directory, fs::Permissions::from_mode(0o700))
+        .map_err(|error| format!("cannot protect '{:?}' -> {}", directory, error))?;
+    let path = control_path(workspace_path);
+    // the socket of an instance that stopped is left behind
+    if UnixStream::connect(&path).is_ok() {
+        return Err(format!("another instance runs on '{:?}'", workspace_path));
+    }
+    if let Err(error) = fs::remove_file(&path) {
+        if error.kind() != ErrorKind::NotFound {
+            return Err(format!("cannot remove '{:?}' -> {}", path, error));
+        }
+    }
+    let listener = UnixListener::bind(&path)
+        .map_err(|error| format!("cannot listen on '{:?}' -> {}", path, error))?;
+    // the instance can only be controlled by its user
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o600))
+        .map_err(|error| format!("cannot protect '{:?}' -> {}", path, error))?;
+    let (sender, receiver) = channel();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(error) => {
+                    warn!(%error, "could not accept control connection");
+                    continue;
+                }
+            };
+            let status = status.clone();
+            let sender = sender.clone();
+            thread::spawn(move || {
+                if let Err(error) = answer_requests(stream, &status, &sender) {
+                    warn!(%error, "could not answer control request");
+                }
+            });
+        }
+    });
+    Ok(receiver)
+}
+
+/// Answers the requests of a connection until it is closed. An instance checking whether this
+/// one runs closes it without sending any.
+fn answer_requests(
+    stream: UnixStream,
+    status: &Mutex<Status>,
+    sender: &Sender<Call>,
+) -> Result<(), String> {
+    for line in BufReader::new(&stream).lines() {
+        let line = line.map_err(|error| format!("cannot read request -> {}", error))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let request: Request = match serde_json::from_str::<Value>(&line) {
+            Ok(value) => match serde_json::from_value(value) {
+                Ok(request) => request,
+                Err(error) => {
+                    write_response(
+                        &stream,
+                        Value::Null,
+                        Err((INVALID_REQUEST, error.to_string())),
+                    )?;
+                    continue;
+                }
+            },
+            Err(error) => {
+                write_response(&stream, Value::Null, Err((PARSE_ERROR, error.to_string())))?;
+                continue;
+            }
+        };
+        let result = if request.jsonrpc != "2.0" {
+            Err((
+                INVALID_REQUEST,
+                String::from("only JSON-RPC 2.0 is supported"),
+            ))
+        } else if !METHODS.contains(&request.method.as_str()) {
+            Err((
+                METHOD_NOT_FOUND,
+                format!("unknown method '{}'", request.method),
+            ))
+        } else {
+            run_command(&request, status, sender)
+        };
+        if let Some(id) = request.id {
+            write_response(&stream, id, result)?;
+        }
+    }
+    Ok(())
+}
+
+fn run_command(
+    request: &Request,
+    status: &Mutex<Status>,
+    sender: &Sender<Call>,
+) -> Result<Value, (i64, String)> {
+    let mut value = json!({ "method": request.method });
+    if !request.params.is_null() {
+        value["params"] = request.params.clone();
+    }
+    let command: Command =
+        serde_json::from_value(value).map_err(|error| (INVALID_PARAMS, error.to_string()))?;
+    let result = match command {
+        Command::Status => serde_json::to_value(&*status.lock().unwrap()),
+        Command::ListPeers => serde_json::to_value(&status.lock().unwrap().peers),
+        command => {
+            let (result_sender, result_receiver) = channel();
+            let call = Call {
+                command,
+                sender: result_sender,
+            };
+            if sender.send(call).is_err() {
+                return Err((COMMAND_FAILED, String::from("the instance stopped")));
+            }
+            return match result_receiver.recv() {
+                Ok(result) => result.map_err(|error| (COMMAND_FAILED, error)),
+                Err(_) => Err((COMMAND_FAILED, String::from("the instance stopped"))),
+            };
+        }
+    };
+    result.map_err(|error| {
+        (
+            COMMAND_FAILED,
+            format!("cannot serialize result -> {}", error),
+        )
+    })
+}
+
+fn write_response(
+    stream: &UnixStream,
+    id: Value,
+    result: Result<Value, (i64, String)>,
+) -> Result<(), String> {
+    let response = match result {
+        Ok(result) => Response {
+            jsonrpc: String::from("2.0"),
+            id,
+            result: Some(result),
+            error: None,
+        },
+        Err((code, message)) => Response {
+            jsonrpc: String::from("2.0"),
+            id,
+            result: None,
+            error: Some(ResponseError { code, message }),
+        },
+    };
+    let text = serde_json::to_string(&response)
+        .map_err(|error| format!("cannot serialize response -> {}", error))?;
+    writeln!(&*stream, "{}", text).map_err(|error| format!("cannot write response -> {}", error))
+}
+
+/// Sends a request to the instance running on a workspace and returns its result.
+pub fn request(workspace_path: &Path, method: &str, params: Value) -> Result<Value, String> {
+    let path = control_path(workspace_path);
+    let stream = UnixStream::connect(&path)
+        .map_err(|error| format!("no instance runs on '{:?}' -> {}", workspace_path, error))?;
+    let request = json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params });
+    writeln!(&stream, "{}", request)
+        .map_err(|error| format!("cannot send request -> {}", error))?;
+    stream
+        .set_read_timeout(Some(REQUEST_TIMEOUT))
+        .map_err(|error| format!("cannot set timeout -> {}", error))?;
+    let mut line = String::new();
+    BufReader::new(&stream)
+        .read_line(&mut line)
+        .map_err(|error| match error.kind() {
+            ErrorKind::WouldBlock | ErrorKind::TimedOut => {
+                format!("the instance on '{:?}' doesn't answer", workspace_path)
+            }
+            _ => format!("cannot read response -> {}", error),
+        })?;
+    let response: Response =
+        serde_json::from_str(&line).map_err(|error| format!("invalid response -> {}", error))?;
+    match (response.result, response.error) {
+        (_, Some(error)) => Err(error.message),
+        (Some(result), None) => Ok(result),
+        (None, None) => Ok(Value::Null),
+    }
+}
+
+/// Status of the instance running on a workspace.
+pub fn request_status(workspace_path: &Path) -> Result<Status, String> {
+    let result = request(workspace_path, "status", Value::Null)?;
+    serde_json::from_value(result).map_err(|error| format!("invalid status -> {}", error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    /// Serves the control socket of a new workspace, answering every call with `"done"`.
+    fn serve_workspace() -> (TempDir, PathBuf, Receiver<&'static str>) {
+        let folder = tempfile::tempdir().unwrap();
+        let workspace_path = folder.path().join("workspace");
+        let status = Arc::new(Mutex::new(Status::new("test", "id", "workspace")));
+        let calls = serve(&workspace_path, status).unwrap();
+        let (sender, commands) = channel();
+        thread::spawn(move || {
+            for call in calls {
+                let name = match call.command {
+                    Command::Pause => "pause",
+                    Command::GetNote { .. } => "get_note",
+                    _ => "other",
+                };
+                call.answer(Ok(json!("done")));
+                let _ = sender.send(name);
+            }
+        });
+        (folder, workspace_path, commands)
+    }
+
+    /// Sends lines to the control socket, returns the response to the last one.
+    fn send_lines(workspace_path: &Path, lines: &[&str]) -> Value {
+        let stream = UnixStream::connect(control_path(workspace_path)).unwrap();
+        for line in lines {
+            writeln!(&stream, "{}", line).unwrap();
+        }
+        let mut response = String::new();
+        BufReader::new(&stream).read_line(&mut response).unwrap();
+        serde_json::from_str(&response).unwrap()
+    }
+
+    #[test]
+    fn invalid_requests_get_their_error_code() {
+        let (_folder, workspace_path, _) = serve_workspace();
+        for (line, code) in [
+            ("{", PARSE_ERROR),
+            (r#"{"jsonrpc": "2.0", "id": 1}"#, INVALID_REQUEST),
+            (
+                r#"{"jsonrpc": "1.0", "id": 1, "method": "status"}"#,
+                INVALID_REQUEST,
+            ),
+            (
+                r#"{"jsonrpc": "2.0", "id": 1, "method": "format"}"#,
+                METHOD_NOT_FOUND,
+            ),
+            (
+                r#"{"jsonrpc": "2.0", "id": 1, "method": "get_note"}"#,
+                INVALID_PARAMS,
+            ),
+            (
+                r#"{"jsonrpc": "2.0", "id": 1, "method": "get_note", "params": {"id": 1}}"#,
+                INVALID_PARAMS,
+            ),
+        ] {
+            let response = send_lines(&workspace_path, &[line]);
+            assert_eq!(response["error"]["code"], code, "for {}", line);
+        }
+    }
+
+    #[test]
+    fn commands_are_answered_and_notifications_are_not() {
+        let (_folder, workspace_path, commands) = serve_workspace();
+        let response = send_lines(
+            &workspace_path,
+            &[
+                r#"{"jsonrpc": "2.0", "method": "pause"}"#,
+                r#"{"jsonrpc": "2.0", "id": "get", "method": "get_note", "params": {"key": "a.txt"}}"#,
+            ],
+        );
+        assert_eq!(response["id"], "get");
+        assert_eq!(response["result"], "done");
+        assert_eq!(commands.recv().unwrap(), "pause");
+        assert_eq!(commands.recv().unwrap(), "get_note");
+        assert_eq!(request_status(&workspace_path).unwrap().name, "test");
+    }
+
+    #[test]
+    fn socket_is_only_for_its_owner() {
+        let (_folder, workspace_path, _) = serve_workspace();
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&control_directory(&workspace_path)), 0o700);
+        assert_eq!(mode(&control_path(&workspace_path)), 0o600);
+    }
+
+    #[test]
+    fn stale_sockets_are_replaced() {
+        let folder = tempfile::tempdir().unwrap();
+        let workspace_path = folder.path().join("workspace");
+        fs::create_dir(control_directory(&workspace_path)).unwrap();
+        // left behind by an instance that stopped
+        drop(UnixListener::bind(control_path(&workspace_path)).unwrap());
+        let status = Arc::new(Mutex::new(Status::new("test", "id", "workspace")));
+        let _calls = serve(&workspace_path, status.clone()).unwrap();
+        assert_eq!(request_status(&workspace_path).unwrap().name, "test");
+        assert!(serve(&workspace_path, status).is_err());
+    }
+}
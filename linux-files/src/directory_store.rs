@@ -0,0 +1,199 @@
+use std::fs::{create_dir_all, metadata, read, remove_dir_all, remove_file, rename};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use crate::atomic_file::{self, Durability};
+use crate::fs_watcher::{note_key, watch_workspace};
+use crate::ignore_rules::IgnoreRules;
+use crate::model::Model;
+use crate::note_store::{is_valid_key, NoteStore, StoreEntry, StoredFile};
+
+/// Store keeping each note and attachment in a file of a folder, the folders of the model
+/// being sub-folders.
+pub struct DirectoryStore {
+    path: PathBuf,
+    durability: Durability,
+    debounce: Duration,
+    rescan_interval: Option<Duration>,
+}
+
+impl DirectoryStore {
+    /// Opens a folder, creating it if it doesn't exist. `debounce` and `rescan_interval` are
+    /// the ones of `watch_workspace`.
+    pub fn open(
+        path: &Path,
+        durability: Durability,
+        debounce: Duration,
+        rescan_interval: Option<Duration>,
+    ) -> Result<DirectoryStore, String> {
+        if let Err(error) = create_dir_all(path) {
+            return Err(format!("cannot create folder '{:?}' -> {}", path, error));
+        }
+        Ok(DirectoryStore {
+            path: path.to_path_buf(),
+            durability,
+            debounce,
+            rescan_interval,
+        })
+    }
+
+    /// Path of a file or folder from its key, keys that would leave the folder are refused.
+    pub fn path(&self, key: &str) -> Result<PathBuf, String> {
+        if !is_valid_key(key) {
+            return Err(format!("invalid key '{}'", key));
+        }
+        Ok(key
+            .split('/')
+            .fold(self.path.clone(), |path, component| path.join(component)))
+    }
+}
+
+impl NoteStore for DirectoryStore {
+    fn list(&self) -> Result<Vec<StoreEntry>, String> {
+        let ignore_rules = IgnoreRules::load(&self.path)?;
+        let mut entries = Vec::new();
+        let mut folders = vec![self.path.clone()];
+        while let Some(folder) = folders.pop() {
+            let folder_entries = match folder.read_dir() {
+                Ok(folder_entries) => folder_entries,
+                Err(error) => {
+                    return Err(format!("cannot read folder '{:?}' -> {}", folder, error))
+                }
+            };
+            for entry in folder_entries {
+                let entry = match entry {
+                    Ok(entry) => entry,
+                    Err(error) => return Err(format!("cannot read '{:?}' -> {}", folder, error)),
+                };
+                let path = entry.path();
+                // symbolic links to folders are not followed
+                let is_folder = entry
+                    .file_type()
+                    .map(|file_type| file_type.is_dir())
+                    .unwrap_or(false);
+                if ignore_rules.is_ignored(&path, is_folder) {
+                    continue;
+                }
+                entries.push(StoreEntry {
+                    key: note_key(&self.path, &path)?,
+                    is_folder,
+                });
+                if is_folder {
+                    folders.push(path);
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    fn read(&self, key: &str) -> Result<StoredFile, String> {
+        let path = self.path(key)?;
+        let data = match read(&path) {
+            Ok(data) => data,
+            Err(error) => return Err(format!("cannot read '{:?}' -> {}", path, error)),
+        };
+        let (created, modified) = match metadata(&path) {
+            Ok(metadata) => (metadata.created().ok(), metadata.modified().ok()),
+            Err(error) => return Err(format!("cannot read metadata of '{:?}' -> {}", path, error)),
+        };
+        Ok(StoredFile {
+            data,
+            created,
+            modified,
+        })
+    }
+
+    fn write(
+        &mut self,
+        key: &str,
+        data: &[u8],
+        modified: Option<SystemTime>,
+    ) -> Result<(), String> {
+        let path = self.path(key)?;
+        if let Some(parent) = path.parent() {
+            if let Err(error) = create_dir_all(parent) {
+                return Err(format!("cannot create folder '{:?}' -> {}", parent, error));
+            }
+        }
+        atomic_file::write(&path, data, modified, self.durability)
+    }
+
+    fn create_folder(&mut self, key: &str) -> Result<(), String> {
+        let path = self.path(key)?;
+        match create_dir_all(&path) {
+            Ok(()) => Ok(()),
+            Err(error) => Err(format!("cannot create folder '{:?}' -> {}", path, error)),
+        }
+    }
+
+    fn delete(&mut self, key: &str) -> Result<(), String> {
+        let path = self.path(key)?;
+        let result = if path.is_dir() {
+            remove_dir_all(&path)
+        } else {
+            remove_file(&path)
+        };
+        match result {
+            Ok(()) => Ok(()),
+            Err(error) if error.kind() == ErrorKind::NotFound => Ok(()),
+            Err(error) => Err(format!("cannot delete '{:?}' -> {}", path, error)),
+        }
+    }
+
+    fn rename(&mut self, from: &str, to: &str) -> Result<(), String> {
+        let from_path = self.path(from)?;
+        let to_path = self.path(to)?;
+        if let Some(parent) = to_path.parent() {
+            if let Err(error) = create_dir_all(parent) {
+                return Err(format!("cannot create folder '{:?}' -> {}", parent, error));
+            }
+        }
+        match rename(&from_path, &to_path) {
+            Ok(()) => Ok(()),
+            Err(error) => Err(format!(
+                "cannot rename '{:?}' to '{:?}' -> {}",
+                from_path, to_path, error
+            )),
+        }
+    }
+
+    fn watch(&mut self, model: Arc<Mutex<Model>>) -> Result<Receiver<Model>, String> {
+        watch_workspace(
+            self.path.clone(),
+            model,
+            self.debounce,
+            self.rescan_interval,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs_watcher::DEFAULT_DEBOUNCE;
+    use crate::note_store::contract;
+
+    fn open(path: &Path) -> DirectoryStore {
+        DirectoryStore::open(path, Durability::File, DEFAULT_DEBOUNCE, None).unwrap()
+    }
+
+    #[test]
+    fn contract() {
+        let workspace = tempfile::tempdir().unwrap();
+        contract::check(&mut open(workspace.path()), &mut open(workspace.path()));
+    }
+
+    #[test]
+    fn keys_leaving_the_folder_are_refused() {
+        let workspace = tempfile::tempdir().unwrap();
+        let mut store = open(&workspace.path().join("workspace"));
+        assert!(store.write("../escape.txt", b"", None).is_err());
+        assert!(store.read("../workspace/../escape.txt").is_err());
+        assert!(store.rename("../escape.txt", "note.txt").is_err());
+        assert!(store.delete("..").is_err());
+        assert!(!workspace.path().join("escape.txt").exists());
+    }
+}
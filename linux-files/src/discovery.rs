@@ -0,0 +1,149 @@
+use mdns_sd::{IfKind, ServiceDaemon, ServiceEvent, ServiceInfo};
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+/// DNS-SD service type the instances advertise themselves with.
+pub const SERVICE_TYPE: &str = "_notes-sync._tcp.local.";
+
+/// How long `discover` waits for instances to answer by default.
+pub const DEFAULT_DISCOVERY_TIME: Duration = Duration::from_secs(3);
+
+/// An instance found on the network.
+pub struct DiscoveredPeer {
+    pub name: String,
+    pub instance_id: String,
+    /// Name of the folder of its workspace.
+    pub workspace: String,
+    /// URLs it can be connected to with, to give to `--peer`, IPv4 ones first.
+    pub urls: Vec<String>,
+    /// Whether it waits for an instance to pair with, showing a code.
+    pub pairing: bool,
+}
+
+/// Advertises the instance with mDNS for as long as the returned daemon lives. An instance
+/// listening on every address is advertised with the addresses of the machine.
+pub fn advertise(
+    name: &str,
+    instance_id: &str,
+    workspace: &str,
+    listen_address: &str,
+    pairing: bool,
+) -> Result<ServiceDaemon, String> {
+    let address: SocketAddr = listen_address
+        .parse()
+        .map_err(|error| format!("invalid address '{}' -> {}", listen_address, error))?;
+    let daemon = open_daemon()?;
+    let properties = [
+        ("name", name),
+        ("id", instance_id),
+        ("workspace", workspace),
+        ("pairing", if pairing { "1" } else { "0" }),
+    ];
+    let host_name = format!("{}.local.", instance_id);
+    let service = if address.ip().is_unspecified() {
+        ServiceInfo::new(
+            SERVICE_TYPE,
+            instance_id,
+            &host_name,
+            "",
+            address.port(),
+            &properties[..],
+        )
+        .map(|service| service.enable_addr_auto())
+    } else {
+        ServiceInfo::new(
+            SERVICE_TYPE,
+            instance_id,
+            &host_name,
+            address.ip(),
+            address.port(),
+            &properties[..],
+        )
+    };
+    match service.and_then(|service| daemon.register(service)) {
+        Ok(()) => Ok(daemon),
+        Err(error) => Err(format!("cannot advertise instance -> {}", error)),
+    }
+}
+
+/// Instances answering on the network within `duration`, by instance id.
+pub fn discover(duration: Duration) -> Result<Vec<DiscoveredPeer>, String> {
+    let daemon = open_daemon()?;
+    let receiver = daemon
+        .browse(SERVICE_TYPE)
+        .map_err(|error| format!("cannot browse the network -> {}", error))?;
+    let deadline = Instant::now() + duration;
+    let mut peers = BTreeMap::new();
+    while let Some(timeout) = deadline.checked_duration_since(Instant::now()) {
+        let service = match receiver.recv_timeout(timeout) {
+            Ok(ServiceEvent::ServiceResolved(service)) => service,
+            Ok(_) => continue,
+            Err(_) => break,
+        };
+        let property = |key| {
+            service
+                .get_property_val_str(key)
+                .unwrap_or_default()
+                .to_string()
+        };
+        let mut urls: Vec<String> = service
+            .get_addresses()
+            .iter()
+            // link-local addresses need an interface, which URLs don't have
+            .filter(|ip| !matches!(ip, IpAddr::V6(ip) if ip.segments()[0] & 0xffc0 == 0xfe80))
+            .map(|ip| format!("ws://{}", SocketAddr::new(*ip, service.get_port())))
+            .collect();
+        urls.sort();
+        peers.insert(
+            property("id"),
+            DiscoveredPeer {
+                name: property("name"),
+                instance_id: property("id"),
+                workspace: property("workspace"),
+                urls,
+                pairing: property("pairing") == "1",
+            },
+        );
+    }
+    let _ = daemon.shutdown();
+    Ok(peers.into_values().collect())
+}
+
+/// Daemon answering on every interface, the loopback one too so that instances of the same
+/// machine find each other.
+fn open_daemon() -> Result<ServiceDaemon, String> {
+    let daemon =
+        ServiceDaemon::new().map_err(|error| format!("cannot start mDNS daemon -> {}", error))?;
+    daemon
+        .enable_interface(IfKind::LoopbackV4)
+        .map_err(|error| format!("cannot start mDNS daemon -> {}", error))?;
+    Ok(daemon)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[ignore = "needs multicast on the loopback interface"]
+    fn advertised_instances_are_discovered() {
+        let _daemon = advertise(
+            "alice",
+            "5a1c3e0f-8d2b-4f6a-9c71-2e4b6d8f0a13",
+            "notes",
+            "127.0.0.1:48613",
+            true,
+        )
+        .unwrap();
+        let peers = discover(DEFAULT_DISCOVERY_TIME).unwrap();
+        let peer = peers
+            .iter()
+            .find(|peer| peer.instance_id == "5a1c3e0f-8d2b-4f6a-9c71-2e4b6d8f0a13")
+            .expect("the advertised instance wasn't discovered");
+        assert_eq!(peer.name, "alice");
+        assert_eq!(peer.workspace, "notes");
+        assert_eq!(peer.urls, vec!["ws://127.0.0.1:48613"]);
+        assert!(peer.pairing);
+    }
+}
@@ -1,16 +1,44 @@
-use model::{Model, Note};
+use model::Model;
 
 use notify::{Error, Event, Watcher};
-use std::fs::{read};
+use std::collections::HashMap;
+use std::fs::{metadata, read, read_dir};
+use std::mem;
 use std::path::{Path, PathBuf};
-use std::sync::mpsc::channel;
 use std::sync::mpsc::Receiver;
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use std::{str, thread};
-use string_join::display::Join;
+use tracing::{debug, debug_span, error, info, trace, warn};
 
+use crate::ignore_rules::IgnoreRules;
 use crate::model;
+use crate::note_store::{self, StoredFile};
+use crate::scanner::Scanner;
 
-pub fn watch_workspace(workspace_path: PathBuf, model: Model) -> Result<Receiver<Model>, String> {
+/// Time without events after which the pending events are applied to the model.
+pub const DEFAULT_DEBOUNCE: Duration = Duration::from_millis(100);
+
+/// Pending events are applied after this many debounce windows even if events keep coming,
+/// so that a file written continuously is still synced.
+const MAX_DEBOUNCE_WINDOWS: u32 = 10;
+
+/// Watches the workspace and sends the model each time a change on disk updates it. The whole
+/// workspace is read once watching started, for the changes made before, and again when events
+/// were lost and every `rescan_interval` if there is one.
+///
+/// Events are applied once no event came for `debounce`, so that the writes of an editor
+/// saving a file produce a single update, with the file as it is after the last write.
+///
+/// The model is shared with the code writing to the workspace: writes made while holding the
+/// lock are already in the model when their events come, so they don't produce updates.
+pub fn watch_workspace(
+    workspace_path: PathBuf,
+    model: Arc<Mutex<Model>>,
+    debounce: Duration,
+    rescan_interval: Option<Duration>,
+) -> Result<Receiver<Model>, String> {
     // channel used to receive notifications from notify
     let (notify_sender, notify_receiver) = channel();
 
@@ -22,67 +50,294 @@ pub fn watch_workspace(workspace_path: PathBuf, model: Model) -> Result<Receiver
         Err(error) => return Err(format!("cannot create watcher -> {}", error)),
     };
 
+    let ignore_rules = IgnoreRules::load(&workspace_path)?;
+
     // start watching workspace in separate thread
     thread::spawn(move || {
-        match watcher.watch(&workspace_path, notify::RecursiveMode::NonRecursive) {
+        match watcher.watch(&workspace_path, notify::RecursiveMode::Recursive) {
             Ok(_) => {
-                let mut current_model = model;
-                match process_events(notify_receiver, &mut |event| {
-                    println!("notify event: {:?}", event);
-                    match event_handler(event, &current_model, &workspace_path) {
-                        Ok(Some(updated_model)) => {
-                            current_model = updated_model;
-                            match watcher_sender.send(current_model.clone()) {
-                                Ok(_) => {}
-                                Err(error) => eprintln!("cannot send model: {}", error),
-                            };
-                        },
-                        Ok(None) => {}
-                        Err(error) => eprintln!("cannot update model: {}", error),
-                    };
-                }) {
-                    Ok(()) => {},
-                    Err(error) => eprintln!("watcher stopped: {}", error)
+                match apply_events(
+                    &workspace_path,
+                    &model,
+                    ignore_rules,
+                    notify_receiver,
+                    debounce,
+                    rescan_interval,
+                    &watcher_sender,
+                ) {
+                    Ok(()) => {}
+                    Err(error) => error!(%error, "watcher stopped"),
                 };
             }
-            Err(error) => eprintln!("cannot watch '{:?}' -> {}", &workspace_path, error),
+            Err(error) => error!(path = ?workspace_path, %error, "cannot watch workspace"),
         };
     });
 
     Ok(watcher_receiver)
 }
 
-fn process_events(receiver: Receiver<Result<Event, Error>>, callback: &mut impl FnMut(Event) -> ()) -> Result<(), String> {
+/// Applies the events of the workspace to the model until the event source stops, and sends
+/// the model after each batch of events that changed it. Events can come from notify or from
+/// any other source, like a channel filled by hand.
+///
+/// The whole workspace is read first, then again when the source reports an error or lost
+/// events, and every `rescan_interval` if there is one.
+pub fn apply_events(
+    workspace_path: &Path,
+    model: &Mutex<Model>,
+    mut ignore_rules: IgnoreRules,
+    events: Receiver<Result<Event, Error>>,
+    debounce: Duration,
+    rescan_interval: Option<Duration>,
+    sender: &Sender<Model>,
+) -> Result<(), String> {
+    let mut scanner = Scanner::new();
+    rescan(workspace_path, model, &ignore_rules, &mut scanner, sender);
+    process_events(
+        events,
+        debounce,
+        rescan_interval,
+        &mut |events, needs_rescan| {
+            debug!(count = events.len(), needs_rescan, "apply events");
+            trace!(?events, "notify events");
+            if events.iter().any(|event| {
+                event
+                    .paths
+                    .iter()
+                    .any(|path| ignore_rules.is_ignore_file(path))
+            }) {
+                match IgnoreRules::load(workspace_path) {
+                    Ok(rules) => ignore_rules = rules,
+                    Err(error) => warn!(%error, "cannot reload ignore rules"),
+                };
+            }
+            if needs_rescan {
+                // the workspace as it is on disk includes the changes of the events
+                rescan(workspace_path, model, &ignore_rules, &mut scanner, sender);
+                return;
+            }
+            let mut current_model = model.lock().unwrap();
+            let mut updated_model = current_model.clone();
+            for mut event in events {
+                event
+                    .paths
+                    .retain(|path| !ignore_rules.is_ignored(path, path.is_dir()));
+                if event.paths.is_empty() {
+                    continue;
+                }
+                match event_handler(event, &updated_model, workspace_path, &ignore_rules) {
+                    Ok(Some(model)) => updated_model = model,
+                    Ok(None) => {}
+                    Err(error) => warn!(%error, "cannot update model"),
+                };
+            }
+            if updated_model != *current_model {
+                *current_model = updated_model;
+                match sender.send(current_model.clone()) {
+                    Ok(_) => {}
+                    Err(error) => error!(%error, "cannot send model"),
+                };
+            }
+        },
+    )
+}
+
+/// Reads the whole workspace and sends the model if it differs from the current one.
+fn rescan(
+    workspace_path: &Path,
+    model: &Mutex<Model>,
+    ignore_rules: &IgnoreRules,
+    scanner: &mut Scanner,
+    sender: &Sender<Model>,
+) {
+    info!("scan workspace");
+    let mut current_model = model.lock().unwrap();
+    let scanned_model = match scanner.scan(&current_model, workspace_path, ignore_rules) {
+        Ok(scanned_model) => scanned_model,
+        Err(error) => {
+            error!(%error, "cannot scan workspace");
+            return;
+        }
+    };
+    if note_store::update_model(&mut current_model, scanned_model) {
+        match sender.send(current_model.clone()) {
+            Ok(_) => {}
+            Err(error) => error!(%error, "cannot send model"),
+        };
+    }
+}
+
+/// Calls the callback with the pending events each time no event came for `debounce`, and
+/// whether the workspace must be read again because events were lost or `rescan_interval`
+/// elapsed.
+fn process_events(
+    receiver: Receiver<Result<Event, Error>>,
+    debounce: Duration,
+    rescan_interval: Option<Duration>,
+    callback: &mut impl FnMut(Vec<Event>, bool),
+) -> Result<(), String> {
+    let mut pending = PendingEvents::new();
+    let mut next_rescan = rescan_interval.map(|interval| Instant::now() + interval);
     loop {
-        match receiver.recv() {
-            Ok(event_or_error) => match event_or_error {
-                Ok(event) => callback(event),
-                Err(error) => eprintln!("{}", error),
+        let time_left = match (pending.time_left(debounce), next_rescan) {
+            (Some(time_left), Some(next_rescan)) => {
+                Some(time_left.min(next_rescan.saturating_duration_since(Instant::now())))
+            }
+            (Some(time_left), None) => Some(time_left),
+            (None, Some(next_rescan)) => {
+                Some(next_rescan.saturating_duration_since(Instant::now()))
+            }
+            (None, None) => None,
+        };
+        let event_or_error = match time_left {
+            None => match receiver.recv() {
+                Ok(event_or_error) => event_or_error,
+                Err(error) => return Err(format!("rx stopped: {}", error)),
             },
+            Some(time_left) => match receiver.recv_timeout(time_left) {
+                Ok(event_or_error) => event_or_error,
+                Err(RecvTimeoutError::Timeout) => {
+                    if let (Some(interval), Some(rescan_time)) = (rescan_interval, next_rescan) {
+                        if Instant::now() >= rescan_time {
+                            pending.request_rescan();
+                            next_rescan = Some(Instant::now() + interval);
+                        }
+                    }
+                    apply_pending(&mut pending, callback);
+                    continue;
+                }
+                Err(error) => {
+                    apply_pending(&mut pending, callback);
+                    return Err(format!("rx stopped: {}", error));
+                }
+            },
+        };
+        match event_or_error {
+            // the kernel queue overflowed, notify can't tell which paths changed
+            Ok(event) if event.need_rescan() => pending.request_rescan(),
+            Ok(event) => pending.push(event),
             Err(error) => {
-                return Err(format!("rx stopped: {}", error));
+                warn!(%error, "watch error, the workspace is scanned again");
+                pending.request_rescan();
+            }
+        }
+    }
+}
+
+fn apply_pending(pending: &mut PendingEvents, callback: &mut impl FnMut(Vec<Event>, bool)) {
+    let (events, needs_rescan) = pending.take();
+    if !events.is_empty() || needs_rescan {
+        callback(events, needs_rescan);
+    }
+}
+
+/// Events received since the last batch was applied. Consecutive events of the same kind on a
+/// path, like the writes of an editor saving a file, are coalesced into one.
+struct PendingEvents {
+    events: Vec<Event>,
+    /// Removals held back from the last batch.
+    held_removals: Vec<Event>,
+    /// Index in `events` of the last event of each path.
+    last_events: HashMap<PathBuf, usize>,
+    first_received: Option<Instant>,
+    last_received: Option<Instant>,
+    needs_rescan: bool,
+}
+
+impl PendingEvents {
+    fn new() -> PendingEvents {
+        PendingEvents {
+            events: Vec::new(),
+            held_removals: Vec::new(),
+            last_events: HashMap::new(),
+            first_received: None,
+            last_received: None,
+            needs_rescan: false,
+        }
+    }
+
+    fn push(&mut self, event: Event) {
+        let now = Instant::now();
+        self.first_received.get_or_insert(now);
+        self.last_received = Some(now);
+        if let [path] = event.paths.as_slice() {
+            if let Some(index) = self.last_events.get(path) {
+                if self.events[*index].kind == event.kind {
+                    return;
+                }
+            }
+        }
+        for path in &event.paths {
+            self.last_events.insert(path.clone(), self.events.len());
+        }
+        self.events.push(event);
+    }
+
+    /// Events were lost, the workspace is read again with the next batch.
+    fn request_rescan(&mut self) {
+        let now = Instant::now();
+        self.first_received.get_or_insert(now);
+        self.last_received = Some(now);
+        self.needs_rescan = true;
+    }
+
+    /// Time until the pending events must be applied, `None` when there are none.
+    fn time_left(&self, debounce: Duration) -> Option<Duration> {
+        let deadline = (self.last_received? + debounce)
+            .min(self.first_received? + debounce * MAX_DEBOUNCE_WINDOWS);
+        Some(deadline.saturating_duration_since(Instant::now()))
+    }
+
+    /// Takes the events to apply. Removals are held back for one more window first: editors
+    /// saving by deleting and recreating a file would otherwise remove its note, and the
+    /// removal is skipped if the file exists again by then.
+    fn take(&mut self) -> (Vec<Event>, bool) {
+        self.last_events.clear();
+        self.first_received = None;
+        self.last_received = None;
+        let mut events = mem::take(&mut self.held_removals);
+        for event in mem::take(&mut self.events) {
+            if event.kind.is_remove() {
+                self.held_removals.push(event);
+            } else {
+                events.push(event);
             }
         }
+        if !self.held_removals.is_empty() {
+            let now = Instant::now();
+            self.first_received = Some(now);
+            self.last_received = Some(now);
+        }
+        (events, mem::take(&mut self.needs_rescan))
     }
 }
 
-fn event_handler(event: Event, model: &Model, workspace_path: &PathBuf) -> Result<Option<Model>, String> {
+fn event_handler(
+    event: Event,
+    model: &Model,
+    workspace_path: &Path,
+    ignore_rules: &IgnoreRules,
+) -> Result<Option<Model>, String> {
     match event.kind {
         notify::EventKind::Access(_) => Ok(None),
-        notify::EventKind::Any => todo!(),
+        notify::EventKind::Any | notify::EventKind::Other => {
+            // nothing tells what changed, the paths are read again
+            let mut updated_model = model.clone();
+            for path in event.paths {
+                sync_path(&mut updated_model, workspace_path, &path, ignore_rules)?;
+            }
+            Ok(Some(updated_model))
+        }
         notify::EventKind::Create(_) => {
+            // files are read as they are once the writes settled, a file deleted and recreated
+            // by an editor saving it updates the same note
             let mut updated_model = model.clone();
             for path in event.paths {
-                updated_model
-                    .notes
-                    .entry(
-                        path.strip_prefix(workspace_path)
-                            .unwrap()
-                            .to_str()
-                            .unwrap()
-                            .to_string(),
-                    )
-                    .or_insert(Note::new());
+                if !path.exists() {
+                    // renamed or removed since, a later event will update the model
+                    continue;
+                }
+                sync_path(&mut updated_model, workspace_path, &path, ignore_rules)?;
             }
             Ok(Some(updated_model))
         }
@@ -91,58 +346,416 @@ fn event_handler(event: Event, model: &Model, workspace_path: &PathBuf) -> Resul
             notify::event::ModifyKind::Data(_) => {
                 let mut updated_model = model.clone();
                 for path in event.paths {
-                    match read_note(&path) {
-                        Ok(note) => {
-                            *updated_model
-                                .notes
-                                .entry(
-                                    path.strip_prefix(workspace_path)
-                                        .unwrap()
-                                        .to_str()
-                                        .unwrap()
-                                        .to_string(),
-                                )
-                                .or_insert(Note::new()) = note
-                        }
-                        Err(error) => {
-                            eprintln!("could not read note '{:?}': {}", path, error)
-                        }
+                    if !path.exists() {
+                        // renamed or removed since, a later event will update the model
+                        continue;
+                    }
+                    if let Err(error) = read_entry(&mut updated_model, workspace_path, &path) {
+                        warn!(?path, %error, "could not read note")
                     }
                 }
                 Ok(Some(updated_model))
             }
             notify::event::ModifyKind::Metadata(_) => Ok(None),
-            notify::event::ModifyKind::Name(_) => todo!(),
+            notify::event::ModifyKind::Name(_) => {
+                // renamed into or out of the workspace, or over another file like the temporary
+                // files of atomic writes: the paths that still exist are read, the others removed
+                let mut updated_model = model.clone();
+                for path in event.paths {
+                    sync_path(&mut updated_model, workspace_path, &path, ignore_rules)?;
+                }
+                Ok(Some(updated_model))
+            }
             notify::event::ModifyKind::Other => Ok(None),
         },
-        notify::EventKind::Other => todo!(),
-        notify::EventKind::Remove(_) => todo!(),
+        notify::EventKind::Remove(_) => {
+            let mut updated_model = model.clone();
+            for path in event.paths {
+                if path.exists() {
+                    // recreated since, the creation event will update the model
+                    continue;
+                }
+                // the path doesn't exist anymore, the model tells whether it was a folder
+                updated_model.remove(&note_key(workspace_path, &path)?);
+            }
+            Ok(Some(updated_model))
+        }
     }
 }
 
-fn read_note(path: &Path) -> Result<Note, String> {
-    match read(&path) {
-        Ok(buf) => match str::from_utf8(&buf) {
-            Ok(text) => {
-                let lines: Vec<&str> = text.split("\n").collect();
-                if lines.len() > 1 {
-                    let iterator = lines.iter();
-                    let without_title = iterator.skip(1);
-                    let body_lines = without_title.skip_while(|line| line.is_empty());
-                    let body = "\n".join(body_lines);
-                    Ok(Note {
-                        title: lines[0].to_string(),
-                        body: body,
-                    })
-                } else {
-                    Ok(Note {
-                        title: "".to_string(),
-                        body: text.to_string(),
-                    })
-                }
+/// Updates the model with a path as it is now on disk: read if it exists, removed otherwise.
+fn sync_path(
+    model: &mut Model,
+    workspace_path: &Path,
+    path: &Path,
+    ignore_rules: &IgnoreRules,
+) -> Result<(), String> {
+    let key = note_key(workspace_path, path)?;
+    if path.is_dir() {
+        // the files created in a new folder before it was watched have no events
+        if let Err(error) = read_folder(model, workspace_path, path, ignore_rules) {
+            warn!(?path, %error, "could not read folder")
+        }
+    } else if path.exists() {
+        if let Err(error) = read_entry(model, workspace_path, path) {
+            warn!(?path, %error, "could not read")
+        }
+    } else {
+        let _span = debug_span!("note", %key).entered();
+        debug!("removed");
+        model.remove(&key);
+    }
+    Ok(())
+}
+
+/// Reads a folder of the workspace and everything in it into the model, except the ignored
+/// paths. Symbolic links to folders are not followed.
+pub fn read_folder(
+    model: &mut Model,
+    workspace_path: &Path,
+    path: &Path,
+    ignore_rules: &IgnoreRules,
+) -> Result<(), String> {
+    if path != workspace_path {
+        model.folders.insert(note_key(workspace_path, path)?);
+    }
+    let entries = match read_dir(path) {
+        Ok(entries) => entries,
+        Err(error) => return Err(format!("cannot read folder '{:?}' -> {}", path, error)),
+    };
+    for entry in entries {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(error) => {
+                warn!(?path, %error, "cannot read entry of folder");
+                continue;
             }
-            Err(error) => Err(format!("could not read '{:?}' -> {}", path, error)),
-        },
+        };
+        let entry_path = entry.path();
+        let is_dir = entry
+            .file_type()
+            .map(|file_type| file_type.is_dir())
+            .unwrap_or(false);
+        if ignore_rules.is_ignored(&entry_path, is_dir) {
+            continue;
+        }
+        let result = if is_dir {
+            read_folder(model, workspace_path, &entry_path, ignore_rules)
+        } else {
+            read_entry(model, workspace_path, &entry_path)
+        };
+        if let Err(error) = result {
+            warn!(path = ?entry_path, %error, "could not read");
+        }
+    }
+    Ok(())
+}
+
+/// Key of a note or folder in the model: its path relative to the workspace, with `/`
+/// separators whatever the platform.
+pub fn note_key(workspace_path: &Path, path: &Path) -> Result<String, String> {
+    let relative_path = path
+        .strip_prefix(workspace_path)
+        .map_err(|_| format!("'{:?}' is not in the workspace", path))?;
+    let components: Option<Vec<&str>> = relative_path
+        .components()
+        .map(|component| component.as_os_str().to_str())
+        .collect();
+    match components {
+        Some(components) => Ok(components.join("/")),
+        None => Err(format!("'{:?}' is not valid unicode", path)),
+    }
+}
+
+/// Reads a file of the workspace into the model, as a note or as an attachment.
+pub fn read_entry(model: &mut Model, workspace_path: &Path, path: &Path) -> Result<(), String> {
+    match read(path) {
+        Ok(data) => insert_entry(model, workspace_path, path, data),
         Err(error) => Err(format!("could not read '{:?}' -> {}", path, error)),
     }
 }
+
+/// Inserts the contents of a file of the workspace into the model, as a note or as an
+/// attachment.
+pub fn insert_entry(
+    model: &mut Model,
+    workspace_path: &Path,
+    path: &Path,
+    data: Vec<u8>,
+) -> Result<(), String> {
+    let key = note_key(workspace_path, path)?;
+    let _span = debug_span!("note", %key).entered();
+    debug!(size = data.len(), "read");
+    let (created, modified) = match metadata(path) {
+        Ok(metadata) => (metadata.created().ok(), metadata.modified().ok()),
+        Err(error) => {
+            warn!(%error, "could not read metadata");
+            (None, None)
+        }
+    };
+    let file = StoredFile {
+        data,
+        created,
+        modified,
+    };
+    note_store::insert_file(model, &key, &file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify::event::{
+        AccessKind, AccessMode, CreateKind, DataChange, MetadataKind, ModifyKind, RemoveKind,
+        RenameMode,
+    };
+    use notify::EventKind;
+    use std::fs::{remove_file, rename, write};
+    use std::thread::{sleep, JoinHandle};
+
+    const DEBOUNCE: Duration = Duration::from_millis(100);
+
+    /// Applies the events sent by hand to the model of a workspace, returns the sender of the
+    /// events and the receiver of the models once the workspace was read a first time.
+    fn watch(
+        workspace_path: &Path,
+    ) -> (
+        Sender<Result<Event, Error>>,
+        Receiver<Model>,
+        JoinHandle<()>,
+    ) {
+        let (event_sender, event_receiver) = channel();
+        let (model_sender, model_receiver) = channel();
+        let workspace_path = workspace_path.to_path_buf();
+        let thread = thread::spawn(move || {
+            let ignore_rules = IgnoreRules::load(&workspace_path).unwrap();
+            let model = Mutex::new(Model::default());
+            // stops once the sender of the events is dropped
+            let _ = apply_events(
+                &workspace_path,
+                &model,
+                ignore_rules,
+                event_receiver,
+                DEBOUNCE,
+                None,
+                &model_sender,
+            );
+        });
+        model_receiver.recv().unwrap();
+        (event_sender, model_receiver, thread)
+    }
+
+    fn event(kind: EventKind, path: &Path) -> Result<Event, Error> {
+        Ok(Event::new(kind).add_path(path.to_path_buf()))
+    }
+
+    fn modified(path: &Path) -> Result<Event, Error> {
+        event(
+            EventKind::Modify(ModifyKind::Data(DataChange::Content)),
+            path,
+        )
+    }
+
+    /// Models sent until the events stop.
+    fn stop(
+        events: Sender<Result<Event, Error>>,
+        models: Receiver<Model>,
+        thread: JoinHandle<()>,
+    ) -> Vec<Model> {
+        drop(events);
+        thread.join().unwrap();
+        models.try_iter().collect()
+    }
+
+    #[test]
+    fn burst_of_writes_gives_one_model() {
+        let workspace = tempfile::tempdir().unwrap();
+        let path = workspace.path().join("note.txt");
+        write(&path, "Title\n\nfirst").unwrap();
+        let (events, models, thread) = watch(workspace.path());
+        for index in 0..5 {
+            write(&path, format!("Title\n\nwrite {}", index)).unwrap();
+            events.send(modified(&path)).unwrap();
+            sleep(DEBOUNCE / 5);
+        }
+        sleep(DEBOUNCE * 3);
+        let models = stop(events, models, thread);
+        assert_eq!(models.len(), 1);
+        assert_eq!(models[0].notes["note.txt"].body, "write 4");
+    }
+
+    #[test]
+    fn continuous_writes_are_applied_after_the_maximum_windows() {
+        let workspace = tempfile::tempdir().unwrap();
+        let path = workspace.path().join("note.txt");
+        write(&path, "Title\n\nfirst").unwrap();
+        let (events, models, thread) = watch(workspace.path());
+        let start = Instant::now();
+        let mut applied_while_writing = false;
+        let mut index = 0;
+        // writes keep coming more often than the debounce for twice the maximum delay
+        while start.elapsed() < DEBOUNCE * MAX_DEBOUNCE_WINDOWS * 2 {
+            write(&path, format!("Title\n\nwrite {}", index)).unwrap();
+            events.send(modified(&path)).unwrap();
+            applied_while_writing |= models.try_recv().is_ok();
+            index += 1;
+            sleep(DEBOUNCE / 5);
+        }
+        stop(events, models, thread);
+        assert!(applied_while_writing);
+    }
+
+    #[test]
+    fn removal_is_held_back_one_window() {
+        let workspace = tempfile::tempdir().unwrap();
+        let path = workspace.path().join("note.txt");
+        write(&path, "Title\n\nBody").unwrap();
+        let (events, models, thread) = watch(workspace.path());
+        remove_file(&path).unwrap();
+        let start = Instant::now();
+        events
+            .send(event(EventKind::Remove(RemoveKind::File), &path))
+            .unwrap();
+        let model = models.recv_timeout(DEBOUNCE * 10).unwrap();
+        assert!(start.elapsed() >= DEBOUNCE * 2);
+        assert!(model.notes.is_empty());
+        stop(events, models, thread);
+    }
+
+    #[test]
+    fn held_back_removal_is_skipped_when_the_file_is_recreated() {
+        let workspace = tempfile::tempdir().unwrap();
+        let path = workspace.path().join("note.txt");
+        write(&path, "Title\n\nBody").unwrap();
+        let (events, models, thread) = watch(workspace.path());
+        remove_file(&path).unwrap();
+        events
+            .send(event(EventKind::Remove(RemoveKind::File), &path))
+            .unwrap();
+        // the removal is held back once its window ended
+        sleep(DEBOUNCE * 3 / 2);
+        write(&path, "Title\n\nSaved").unwrap();
+        events
+            .send(event(EventKind::Create(CreateKind::File), &path))
+            .unwrap();
+        sleep(DEBOUNCE * 4);
+        let models = stop(events, models, thread);
+        assert_eq!(models.len(), 1);
+        assert_eq!(models[0].notes["note.txt"].body, "Saved");
+    }
+
+    /// Step of a recorded save: what the editor did to the workspace, or an event inotify
+    /// reported for it, with the file names it is about.
+    enum Step {
+        Write(&'static str, &'static str),
+        Rename(&'static str, &'static str),
+        Remove(&'static str),
+        Notify(EventKind, &'static [&'static str]),
+    }
+
+    const CREATE: EventKind = EventKind::Create(CreateKind::File);
+    const MODIFY: EventKind = EventKind::Modify(ModifyKind::Data(DataChange::Any));
+    const ATTRIB: EventKind = EventKind::Modify(ModifyKind::Metadata(MetadataKind::Any));
+    const CLOSE_WRITE: EventKind = EventKind::Access(AccessKind::Close(AccessMode::Write));
+    const MOVED_FROM: EventKind = EventKind::Modify(ModifyKind::Name(RenameMode::From));
+    const MOVED_TO: EventKind = EventKind::Modify(ModifyKind::Name(RenameMode::To));
+    const MOVED: EventKind = EventKind::Modify(ModifyKind::Name(RenameMode::Both));
+    const DELETE: EventKind = EventKind::Remove(RemoveKind::File);
+
+    /// Replays a save of `note.txt` and checks that it is a single update of the note.
+    fn assert_single_update(trace: &[Step]) {
+        let workspace = tempfile::tempdir().unwrap();
+        let path = |name: &str| workspace.path().join(name);
+        write(path("note.txt"), "Title\n\nBefore").unwrap();
+        let (events, models, thread) = watch(workspace.path());
+        for step in trace {
+            match step {
+                Step::Write(name, text) => write(path(name), text).unwrap(),
+                Step::Rename(from, to) => rename(path(from), path(to)).unwrap(),
+                Step::Remove(name) => remove_file(path(name)).unwrap(),
+                Step::Notify(kind, names) => {
+                    let event = names
+                        .iter()
+                        .fold(Event::new(*kind), |event, name| event.add_path(path(name)));
+                    events.send(Ok(event)).unwrap();
+                }
+            }
+        }
+        sleep(DEBOUNCE * 4);
+        let models = stop(events, models, thread);
+        assert_eq!(models.len(), 1);
+        assert_eq!(models[0].notes.keys().collect::<Vec<_>>(), vec!["note.txt"]);
+        assert_eq!(models[0].notes["note.txt"].body, "After");
+        assert!(models[0].attachments.is_empty());
+    }
+
+    #[test]
+    fn vim_save() {
+        // renames the note to a backup, writes a new file and removes the backup
+        assert_single_update(&[
+            Step::Write("4913", ""),
+            Step::Notify(CREATE, &["4913"]),
+            Step::Notify(ATTRIB, &["4913"]),
+            Step::Notify(CLOSE_WRITE, &["4913"]),
+            Step::Remove("4913"),
+            Step::Notify(DELETE, &["4913"]),
+            Step::Rename("note.txt", "note.txt~"),
+            Step::Notify(MOVED_FROM, &["note.txt"]),
+            Step::Notify(MOVED_TO, &["note.txt~"]),
+            Step::Notify(MOVED, &["note.txt", "note.txt~"]),
+            Step::Write("note.txt", "Title\n\nAfter"),
+            Step::Notify(CREATE, &["note.txt"]),
+            Step::Notify(MODIFY, &["note.txt"]),
+            Step::Notify(ATTRIB, &["note.txt"]),
+            Step::Notify(CLOSE_WRITE, &["note.txt"]),
+            Step::Remove("note.txt~"),
+            Step::Notify(DELETE, &["note.txt~"]),
+        ]);
+    }
+
+    #[test]
+    fn emacs_save() {
+        // takes a lock, renames the note to a backup it keeps and writes a new file
+        assert_single_update(&[
+            Step::Write(".#note.txt", "user@host.1234"),
+            Step::Notify(CREATE, &[".#note.txt"]),
+            Step::Rename("note.txt", "note.txt~"),
+            Step::Notify(MOVED_FROM, &["note.txt"]),
+            Step::Notify(MOVED_TO, &["note.txt~"]),
+            Step::Notify(MOVED, &["note.txt", "note.txt~"]),
+            Step::Write("note.txt", "Title\n\nAfter"),
+            Step::Notify(CREATE, &["note.txt"]),
+            Step::Notify(MODIFY, &["note.txt"]),
+            Step::Notify(CLOSE_WRITE, &["note.txt"]),
+            Step::Remove(".#note.txt"),
+            Step::Notify(DELETE, &[".#note.txt"]),
+        ]);
+    }
+
+    #[test]
+    fn vs_code_save() {
+        // deletes the note and writes it again
+        assert_single_update(&[
+            Step::Remove("note.txt"),
+            Step::Notify(DELETE, &["note.txt"]),
+            Step::Write("note.txt", "Title\n\nAfter"),
+            Step::Notify(CREATE, &["note.txt"]),
+            Step::Notify(MODIFY, &["note.txt"]),
+            Step::Notify(CLOSE_WRITE, &["note.txt"]),
+        ]);
+    }
+
+    #[test]
+    fn gedit_save() {
+        // writes a temporary file and renames it over the note
+        assert_single_update(&[
+            Step::Write(".goutputstream-4TQZ41", "Title\n\nAfter"),
+            Step::Notify(CREATE, &[".goutputstream-4TQZ41"]),
+            Step::Notify(MODIFY, &[".goutputstream-4TQZ41"]),
+            Step::Notify(CLOSE_WRITE, &[".goutputstream-4TQZ41"]),
+            Step::Rename(".goutputstream-4TQZ41", "note.txt"),
+            Step::Notify(MOVED_FROM, &[".goutputstream-4TQZ41"]),
+            Step::Notify(MOVED_TO, &["note.txt"]),
+            Step::Notify(MOVED, &[".goutputstream-4TQZ41", "note.txt"]),
+        ]);
+    }
+}
@@ -0,0 +1,269 @@
+use git2::{IndexAddOption, Repository, Signature, Sort, Tree};
+use std::path::{Path, PathBuf};
+use std::str;
+use tracing::warn;
+
+use crate::attachments;
+use crate::ignore_rules::IgnoreRules;
+use crate::model::Revision;
+use crate::note_file;
+
+/// Git repository at the root of a workspace, where the synced changes are committed so that
+/// their history can be browsed with the usual tools.
+pub struct GitRepository {
+    repository: Repository,
+    workspace_path: PathBuf,
+}
+
+impl GitRepository {
+    /// Opens the repository of a workspace, or creates it. An existing repository is used as
+    /// is, with its history.
+    pub fn open(workspace_path: &Path) -> Result<GitRepository, String> {
+        let repository = if workspace_path.join(".git").exists() {
+            Repository::open(workspace_path)
+        } else {
+            Repository::init(workspace_path)
+        };
+        match repository {
+            Ok(repository) => Ok(GitRepository {
+                repository,
+                workspace_path: workspace_path.to_path_buf(),
+            }),
+            Err(error) => Err(format!(
+                "cannot open repository '{:?}' -> {}",
+                workspace_path, error
+            )),
+        }
+    }
+
+    /// Commits the files of the workspace that changed since the last commit, if any, with
+    /// their keys as message.
+    pub fn commit(&self, author: &str) -> Result<(), String> {
+        let ignore_rules = IgnoreRules::load(&self.workspace_path)?;
+        let mut index = self
+            .repository
+            .index()
+            .map_err(|error| format!("cannot read index -> {}", error))?;
+        let mut skip_ignored = |path: &Path, _: &[u8]| -> i32 {
+            if ignore_rules.is_ignored(&self.workspace_path.join(path), false) {
+                1
+            } else {
+                0
+            }
+        };
+        index
+            .add_all(["*"], IndexAddOption::DEFAULT, Some(&mut skip_ignored))
+            .and_then(|()| index.update_all(["*"], None))
+            .and_then(|()| index.write())
+            .map_err(|error| format!("cannot update index -> {}", error))?;
+        let tree = index
+            .write_tree()
+            .and_then(|tree_id| self.repository.find_tree(tree_id))
+            .map_err(|error| format!("cannot write tree -> {}", error))?;
+        let parent = match self.repository.head() {
+            Ok(head) => Some(
+                head.peel_to_commit()
+                    .map_err(|error| format!("cannot read last commit -> {}", error))?,
+            ),
+            // no commit yet
+            Err(_) => None,
+        };
+        let parent_tree = match &parent {
+            Some(parent) => Some(
+                parent
+                    .tree()
+                    .map_err(|error| format!("cannot read last commit -> {}", error))?,
+            ),
+            None => None,
+        };
+        let keys = self.changed_keys(parent_tree.as_ref(), &tree)?;
+        if keys.is_empty() {
+            return Ok(());
+        }
+        let signature = Signature::now(author, &format!("{}@notes-sync", author))
+            .map_err(|error| format!("invalid author '{}' -> {}", author, error))?;
+        let parents: Vec<&git2::Commit> = parent.iter().collect();
+        match self.repository.commit(
+            Some("HEAD"),
+            &signature,
+            &signature,
+            &keys.join(", "),
+            &tree,
+            &parents,
+        ) {
+            Ok(_) => Ok(()),
+            Err(error) => Err(format!("cannot commit -> {}", error)),
+        }
+    }
+
+    /// Versions of the notes in the commits of the current branch, oldest first.
+    pub fn revisions(&self) -> Result<Vec<Revision>, String> {
+        let mut walk = self
+            .repository
+            .revwalk()
+            .map_err(|error| format!("cannot read commits -> {}", error))?;
+        walk.push_head()
+            .and_then(|()| walk.set_sorting(Sort::TOPOLOGICAL | Sort::REVERSE))
+            .map_err(|error| format!("cannot read commits -> {}", error))?;
+        let mut revisions = Vec::new();
+        for commit_id in walk {
+            let commit = commit_id
+                .and_then(|commit_id| self.repository.find_commit(commit_id))
+                .map_err(|error| format!("cannot read commit -> {}", error))?;
+            let tree = commit
+                .tree()
+                .map_err(|error| format!("cannot read commit {} -> {}", commit.id(), error))?;
+            let parent_tree =
+                match commit.parent(0) {
+                    Ok(parent) => Some(parent.tree().map_err(|error| {
+                        format!("cannot read commit {} -> {}", parent.id(), error)
+                    })?),
+                    Err(_) => None,
+                };
+            let diff = self
+                .repository
+                .diff_tree_to_tree(parent_tree.as_ref(), Some(&tree), None)
+                .map_err(|error| format!("cannot read commit {} -> {}", commit.id(), error))?;
+            for delta in diff.deltas() {
+                let (file, deleted) = match delta.status() {
+                    git2::Delta::Deleted => (delta.old_file(), true),
+                    _ => (delta.new_file(), false),
+                };
+                let key = match file.path().and_then(|path| path.to_str()) {
+                    Some(key) => key.to_string(),
+                    None => continue,
+                };
+                let blob = match self.repository.find_blob(file.id()) {
+                    Ok(blob) => blob,
+                    Err(error) => return Err(format!("cannot read '{}' -> {}", key, error)),
+                };
+                // only the notes have revisions
+                let text = match str::from_utf8(blob.content()) {
+                    Ok(text) if !attachments::is_attachment(Path::new(&key), blob.content()) => {
+                        text
+                    }
+                    _ => continue,
+                };
+                let note = if deleted {
+                    None
+                } else {
+                    match note_file::parse(text) {
+                        Ok(note) => Some(note),
+                        Err(error) => {
+                            warn!(%key, commit = %commit.id(), %error, "could not parse note");
+                            continue;
+                        }
+                    }
+                };
+                revisions.push(Revision {
+                    key,
+                    author: commit.author().name().unwrap_or("unknown").to_string(),
+                    time: u64::try_from(commit.time().seconds()).unwrap_or(0),
+                    note,
+                });
+            }
+        }
+        Ok(revisions)
+    }
+
+    fn changed_keys(
+        &self,
+        old_tree: Option<&Tree>,
+        new_tree: &Tree,
+    ) -> Result<Vec<String>, String> {
+        let diff = self
+            .repository
+            .diff_tree_to_tree(old_tree, Some(new_tree), None)
+            .map_err(|error| format!("cannot compare trees -> {}", error))?;
+        Ok(diff
+            .deltas()
+            .filter_map(|delta| delta.new_file().path().or(delta.old_file().path()))
+            .filter_map(|path| path.to_str())
+            .map(|key| key.to_string())
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{remove_file, write};
+
+    fn last_commit(git_repository: &GitRepository) -> git2::Commit<'_> {
+        git_repository
+            .repository
+            .head()
+            .unwrap()
+            .peel_to_commit()
+            .unwrap()
+    }
+
+    #[test]
+    fn commits_have_the_changed_keys_as_message_and_the_instance_as_author() {
+        let workspace = tempfile::tempdir().unwrap();
+        let path = workspace.path();
+        write(path.join(".notesignore"), "private.txt\n").unwrap();
+        write(path.join("private.txt"), "Private\n\nbody").unwrap();
+        write(path.join(".note_1.txt.swp"), "swap").unwrap();
+        write(path.join("note_1.txt"), "Note 1\n\nbody").unwrap();
+        let git_repository = GitRepository::open(path).unwrap();
+
+        git_repository.commit("alice").unwrap();
+        let commit = last_commit(&git_repository);
+        assert_eq!(commit.author().name(), Some("alice"));
+        assert_eq!(commit.message(), Some("note_1.txt"));
+        let tree = commit.tree().unwrap();
+        let keys: Vec<String> = tree
+            .iter()
+            .filter_map(|entry| entry.name().map(|name| name.to_string()))
+            .collect();
+        assert_eq!(keys, ["note_1.txt"]);
+
+        // nothing changed, nothing to commit
+        let commit_id = commit.id();
+        git_repository.commit("alice").unwrap();
+        assert_eq!(last_commit(&git_repository).id(), commit_id);
+
+        write(path.join("note_1.txt"), "Note 1\n\nchanged").unwrap();
+        write(path.join("note_2.txt"), "Note 2\n\nbody").unwrap();
+        git_repository.commit("bob").unwrap();
+        let commit = last_commit(&git_repository);
+        assert_eq!(commit.author().name(), Some("bob"));
+        assert_eq!(commit.message(), Some("note_1.txt, note_2.txt"));
+        assert_eq!(commit.parent_id(0).unwrap(), commit_id);
+    }
+
+    #[test]
+    fn revisions_are_the_notes_changed_by_each_commit() {
+        let workspace = tempfile::tempdir().unwrap();
+        let path = workspace.path();
+        let git_repository = GitRepository::open(path).unwrap();
+        write(path.join("note_1.txt"), "Note 1\n\nfirst").unwrap();
+        write(path.join("image.png"), [0x89, b'P', b'N', b'G']).unwrap();
+        git_repository.commit("alice").unwrap();
+        write(path.join("note_1.txt"), "Note 1\n\nsecond").unwrap();
+        git_repository.commit("bob").unwrap();
+        remove_file(path.join("note_1.txt")).unwrap();
+        git_repository.commit("alice").unwrap();
+
+        let revisions = git_repository.revisions().unwrap();
+        let summary: Vec<(&str, &str, Option<&str>)> = revisions
+            .iter()
+            .map(|revision| {
+                (
+                    revision.key.as_str(),
+                    revision.author.as_str(),
+                    revision.note.as_ref().map(|note| note.body.as_str()),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("note_1.txt", "alice", Some("first")),
+                ("note_1.txt", "bob", Some("second")),
+                ("note_1.txt", "alice", None),
+            ]
+        );
+    }
+}
@@ -0,0 +1,155 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tracing::warn;
+
+use crate::atomic_file::{self, Durability};
+use crate::model::{Model, Revision};
+use crate::note_store::seconds_since_epoch;
+
+/// Every version of the notes of a workspace, one JSON revision per line of a file, oldest
+/// first.
+pub struct History {
+    path: PathBuf,
+    /// Last revision of each note.
+    latest: HashMap<String, Revision>,
+}
+
+impl History {
+    /// Opens the history of a workspace, kept next to it in `<workspace>.history`, so that it
+    /// is never synced or cleared with the workspace.
+    pub fn open(workspace_path: &Path) -> Result<History, String> {
+        let mut path = workspace_path.as_os_str().to_owned();
+        path.push(".history");
+        let mut history = History {
+            path: PathBuf::from(path),
+            latest: HashMap::new(),
+        };
+        for revision in history.read()? {
+            history.latest.insert(revision.key.clone(), revision);
+        }
+        Ok(history)
+    }
+
+    /// Records a revision for each note of the model that changed since its last revision, and
+    /// for each note that was deleted.
+    pub fn record(&mut self, model: &Model, author: &str) -> Result<(), String> {
+        let time = seconds_since_epoch(SystemTime::now());
+        let mut revisions = Vec::new();
+        for (key, note) in model.notes.iter() {
+            match self.latest.get(key) {
+                Some(revision) if revision.has_note(Some(note)) => {}
+                _ => revisions.push(Revision {
+                    key: key.clone(),
+                    author: author.to_string(),
+                    time,
+                    note: Some(note.clone()),
+                }),
+            }
+        }
+        for revision in self.latest.values() {
+            if revision.note.is_some() && !model.notes.contains_key(&revision.key) {
+                revisions.push(Revision {
+                    key: revision.key.clone(),
+                    author: author.to_string(),
+                    time,
+                    note: None,
+                });
+            }
+        }
+        if revisions.is_empty() {
+            return Ok(());
+        }
+        let mut file = match OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+        {
+            Ok(file) => file,
+            Err(error) => return Err(format!("cannot open '{:?}' -> {}", self.path, error)),
+        };
+        for revision in revisions {
+            let line = serde_json::to_string(&revision)
+                .map_err(|error| format!("cannot serialize revision -> {}", error))?;
+            if let Err(error) = writeln!(file, "{}", line) {
+                return Err(format!("cannot write '{:?}' -> {}", self.path, error));
+            }
+            self.latest.insert(revision.key.clone(), revision);
+        }
+        Ok(())
+    }
+
+    /// Adds revisions made before the history was recorded, like the commits of a repository
+    /// the workspace was kept in. The revisions of a note that aren't older than its first
+    /// revision here are already recorded and are skipped. Returns how many were added.
+    pub fn import(&mut self, revisions: Vec<Revision>) -> Result<usize, String> {
+        let recorded_revisions = self.read()?;
+        let mut first_times = HashMap::new();
+        for revision in recorded_revisions.iter() {
+            first_times
+                .entry(revision.key.clone())
+                .or_insert(revision.time);
+        }
+        let mut all_revisions: Vec<Revision> = revisions
+            .into_iter()
+            .filter(|revision| {
+                first_times
+                    .get(&revision.key)
+                    .map(|time| revision.time < *time)
+                    .unwrap_or(true)
+            })
+            .collect();
+        let count = all_revisions.len();
+        if count == 0 {
+            return Ok(0);
+        }
+        all_revisions.extend(recorded_revisions);
+        // stable, so revisions of the same second keep their order
+        all_revisions.sort_by_key(|revision| revision.time);
+        let mut data = String::new();
+        for revision in all_revisions.iter() {
+            let line = serde_json::to_string(revision)
+                .map_err(|error| format!("cannot serialize revision -> {}", error))?;
+            data.push_str(&line);
+            data.push('\n');
+        }
+        atomic_file::write(&self.path, data.as_bytes(), None, Durability::File)?;
+        self.latest.clear();
+        for revision in all_revisions {
+            self.latest.insert(revision.key.clone(), revision);
+        }
+        Ok(count)
+    }
+
+    /// Revisions of a note, oldest first.
+    pub fn revisions(&self, key: &str) -> Result<Vec<Revision>, String> {
+        Ok(self
+            .read()?
+            .into_iter()
+            .filter(|revision| revision.key == key)
+            .collect())
+    }
+
+    fn read(&self) -> Result<Vec<Revision>, String> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(error) => return Err(format!("cannot open '{:?}' -> {}", self.path, error)),
+        };
+        let mut revisions = Vec::new();
+        for line in BufReader::new(file).lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(error) => return Err(format!("cannot read '{:?}' -> {}", self.path, error)),
+            };
+            // a line cut by a crash is skipped, the next change is recorded again
+            match serde_json::from_str(&line) {
+                Ok(revision) => revisions.push(revision),
+                Err(error) => warn!(path = ?self.path, %error, "could not read revision"),
+            }
+        }
+        Ok(revisions)
+    }
+}
@@ -0,0 +1,121 @@
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use std::fs::read_to_string;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+use crate::atomic_file;
+
+/// Name of the file, at the root of the workspace, listing the paths that are not synced. It
+/// isn't synced itself: the rules apply to the instance where the file is, each instance needs
+/// its own copy.
+pub const IGNORE_FILE_NAME: &str = ".notesignore";
+
+/// Rules applied before the ones of the ignore file, which can override them with `!` lines.
+const DEFAULT_RULES: [&str; 10] = [
+    IGNORE_FILE_NAME,
+    // repository of the workspace in git mode
+    ".git/",
+    // vim swap files and the file it writes to check that a directory is writable
+    ".*.sw[a-p]",
+    "4913",
+    // backups of vim, emacs and others
+    "*~",
+    // emacs auto-save and lock files
+    "#*#",
+    ".#*",
+    // macOS and Windows folder metadata
+    ".DS_Store",
+    "Thumbs.db",
+    "*.tmp",
+];
+
+/// Paths of the workspace that are not synced, with the semantics of a `.gitignore` file.
+pub struct IgnoreRules {
+    workspace_path: PathBuf,
+    gitignore: Gitignore,
+}
+
+impl IgnoreRules {
+    /// Loads the default rules and the ones of the ignore file of the workspace, if it exists.
+    /// Invalid lines of the ignore file are skipped, the other rules still apply.
+    pub fn load(workspace_path: &Path) -> Result<IgnoreRules, String> {
+        let mut builder = GitignoreBuilder::new(workspace_path);
+        for rule in DEFAULT_RULES {
+            builder
+                .add_line(None, rule)
+                .map_err(|error| format!("invalid default ignore rule '{}' -> {}", rule, error))?;
+        }
+        let ignore_file_path = workspace_path.join(IGNORE_FILE_NAME);
+        match read_to_string(&ignore_file_path) {
+            Ok(text) => {
+                for (index, line) in text.lines().enumerate() {
+                    if let Err(error) = builder.add_line(Some(ignore_file_path.clone()), line) {
+                        warn!(line = index + 1, %error, "skip invalid ignore rule");
+                    }
+                }
+            }
+            Err(error) if error.kind() == ErrorKind::NotFound => {}
+            Err(error) => return Err(format!("cannot read '{:?}' -> {}", ignore_file_path, error)),
+        }
+        match builder.build() {
+            Ok(gitignore) => Ok(IgnoreRules {
+                workspace_path: workspace_path.to_path_buf(),
+                gitignore,
+            }),
+            Err(error) => Err(format!("cannot build ignore rules -> {}", error)),
+        }
+    }
+
+    /// Whether a path of the workspace, or one of its parent folders, is ignored. Paths outside
+    /// of the workspace are never ignored, temporary files of atomic writes always are.
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        if atomic_file::is_temp_file(path) {
+            return true;
+        }
+        match path.strip_prefix(&self.workspace_path) {
+            Ok(relative_path) if relative_path.as_os_str().is_empty() => false,
+            Ok(relative_path) => self
+                .gitignore
+                .matched_path_or_any_parents(relative_path, is_dir)
+                .is_ignore(),
+            Err(_) => false,
+        }
+    }
+
+    /// Whether a path is the ignore file of the workspace, whose changes require reloading the
+    /// rules.
+    pub fn is_ignore_file(&self, path: &Path) -> bool {
+        path == self.workspace_path.join(IGNORE_FILE_NAME)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::write;
+
+    #[test]
+    fn load_skips_invalid_lines() {
+        let workspace = tempfile::tempdir().unwrap();
+        write(
+            workspace.path().join(IGNORE_FILE_NAME),
+            "drafts/\nbroken{a,b\r\n*.log\n",
+        )
+        .unwrap();
+        let rules = IgnoreRules::load(workspace.path()).unwrap();
+        assert!(rules.is_ignored(&workspace.path().join("drafts"), true));
+        assert!(rules.is_ignored(&workspace.path().join("build.log"), false));
+        assert!(rules.is_ignored(&workspace.path().join("backup~"), false));
+        assert!(!rules.is_ignored(&workspace.path().join("notes.txt"), false));
+        assert!(rules.is_ignored(&workspace.path().join(IGNORE_FILE_NAME), false));
+    }
+
+    #[test]
+    fn load_without_ignore_file() {
+        let workspace = tempfile::tempdir().unwrap();
+        let rules = IgnoreRules::load(workspace.path()).unwrap();
+        assert!(rules.is_ignored(&workspace.path().join(".git"), true));
+        assert!(!rules.is_ignored(&workspace.path().join("notes.txt"), false));
+    }
+}
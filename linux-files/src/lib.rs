@@ -0,0 +1,25 @@
+pub mod atomic_file;
+pub mod attachments;
+pub mod chunks;
+pub mod control;
+pub mod directory_store;
+pub mod discovery;
+pub mod fs_watcher;
+pub mod git_repository;
+pub mod history;
+pub mod ignore_rules;
+pub mod logging;
+pub mod memory_store;
+pub mod model;
+pub mod networking;
+pub mod note_file;
+pub mod note_store;
+pub mod pairing;
+pub mod permissions;
+pub mod scanner;
+pub mod sqlite_store;
+pub mod status;
+pub mod subscription;
+pub mod sync_state;
+pub mod transfer;
+pub mod trash;
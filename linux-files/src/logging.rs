@@ -0,0 +1,54 @@
+use std::fs::OpenOptions;
+use std::io::{self, IsTerminal};
+use std::path::Path;
+use std::sync::Mutex;
+
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, EnvFilter, Layer};
+
+/// Level logged when neither `--log-level` nor `RUST_LOG` is given.
+pub const DEFAULT_LOG_LEVEL: &str = "info";
+
+/// Logs to stderr, and as JSON lines appended to `log_file` if there is one. `filter` is a
+/// level, like `debug`, or directives like `warn,linux_files::networking=trace`. Note contents
+/// are only logged at the trace level.
+pub fn init(filter: &str, log_file: Option<&Path>) -> Result<(), String> {
+    let stderr_layer = fmt::layer()
+        .with_writer(io::stderr)
+        .with_ansi(io::stderr().is_terminal())
+        .with_target(false)
+        .with_filter(parse_filter(filter)?);
+    let file_layer = match log_file {
+        Some(path) => {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|error| format!("cannot open '{:?}' -> {}", path, error))?;
+            Some(
+                fmt::layer()
+                    .json()
+                    .with_writer(Mutex::new(file))
+                    .with_filter(parse_filter(filter)?),
+            )
+        }
+        None => None,
+    };
+    tracing_subscriber::registry()
+        .with(stderr_layer)
+        .with(file_layer)
+        .try_init()
+        .map_err(|error| format!("cannot start logging -> {}", error))
+}
+
+/// A level alone only applies to this crate, the others logging their warnings.
+fn parse_filter(filter: &str) -> Result<EnvFilter, String> {
+    let directives = match filter {
+        "off" | "error" => filter.to_string(),
+        "warn" | "info" | "debug" | "trace" => format!("warn,linux_files={}", filter),
+        _ => filter.to_string(),
+    };
+    EnvFilter::try_new(directives)
+        .map_err(|error| format!("invalid log level '{}' -> {}", filter, error))
+}
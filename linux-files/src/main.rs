@@ -1,211 +1,1408 @@
-pub mod fs_watcher;
-pub mod model;
-pub mod networking;
+mod commands;
 
-use model::{Model, Note};
-use networking::Connection;
+use linux_files::atomic_file::Durability;
+use linux_files::control::{Call, Command, NoteEntry, Resolution};
+use linux_files::directory_store::DirectoryStore;
+use linux_files::git_repository::GitRepository;
+use linux_files::history::History;
+use linux_files::logging::{self, DEFAULT_LOG_LEVEL};
+use linux_files::model::{Model, Note};
+use linux_files::networking::{ConnectionId, Event, Hello, Message, Network};
+use linux_files::note_store::{self, load_model, NoteStore};
+use linux_files::pairing::{self, PeerKeys, Session};
+use linux_files::permissions::{PeerPermissions, Permissions};
+use linux_files::sqlite_store::SqliteStore;
+use linux_files::status::Status;
+use linux_files::subscription::{Scope, Subscription};
+use linux_files::sync_state::{self, Order, SyncState, Version};
+use linux_files::transfer::Transfer;
+use linux_files::trash::{self, DEFAULT_RETENTION, PURGE_INTERVAL};
+use linux_files::{attachments, control, discovery, note_file};
+use serde_json::Value;
 
-use fs_watcher::watch_workspace;
+use linux_files::fs_watcher::DEFAULT_DEBOUNCE;
 
-use std::fs::{create_dir, File, OpenOptions};
-use std::io::Write;
+use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
-use std::{fs::remove_dir_all, process::exit};
+use std::process::exit;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use tracing::{debug, debug_span, error, info, info_span, trace, warn};
 
-pub enum InstanceKind {
-    ServerKind,
-    ClientKind,
-}
+/// Longest wait for an event of the network, after which the calls of the control socket and
+/// the changes of the workspace are handled.
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
 
 fn main() {
-    let workspace_path_string = std::env::args()
-        .nth(1)
+    let arguments: Vec<String> = std::env::args().collect();
+
+    if let Err(error) = init_logging(&arguments) {
+        eprintln!("{}", error);
+        exit(1)
+    }
+
+    let workspace_path_string = arguments
+        .get(1)
+        .cloned()
         .expect("Argument 1 needs to be a path");
 
-    let instance_kind_string = std::env::args()
-        .nth(2)
-        .expect("Argument 2 needs to be either 'server' or 'client'");
+    // discover doesn't need a workspace
+    if workspace_path_string == "discover" {
+        match commands::run_discover_command(&arguments[2..]) {
+            Ok(()) => exit(0),
+            Err(error) => {
+                eprintln!("cannot run 'discover' -> {}", error);
+                exit(1)
+            }
+        }
+    }
+
+    let instance_kind_string = arguments
+        .get(2)
+        .cloned()
+        .expect("Argument 2 needs to be either 'server', 'client' or a command");
+
+    // status asks the instance running on the workspace
+    if instance_kind_string == "status" {
+        match commands::run_status_command(Path::new(&workspace_path_string), &arguments[3..]) {
+            Ok(()) => exit(0),
+            Err(error) => {
+                eprintln!("cannot run 'status' -> {}", error);
+                exit(1)
+            }
+        }
+    }
+
+    if commands::DATABASE_COMMANDS.contains(&instance_kind_string.as_str()) {
+        match commands::run_database_command(
+            Path::new(&workspace_path_string),
+            &instance_kind_string,
+            &arguments[3..],
+        ) {
+            Ok(()) => exit(0),
+            Err(error) => {
+                eprintln!("cannot run '{}' -> {}", instance_kind_string, error);
+                exit(1)
+            }
+        }
+    }
+
+    let options = match Options::parse(&arguments, &instance_kind_string) {
+        Ok(options) => options,
+        Err(error) => {
+            eprintln!("{}", error);
+            exit(1)
+        }
+    };
 
     let mut workspace_path = PathBuf::new();
     workspace_path.push(workspace_path_string);
 
-    let instance_kind = match instance_kind_string.as_str() {
-        "server" => InstanceKind::ServerKind,
-        "client" => InstanceKind::ClientKind,
-        _ => {
-            eprintln!("invalid instance kind '{}'", instance_kind_string);
+    let mut store = open_store(&workspace_path, &options);
+
+    let mut history = match History::open(&workspace_path) {
+        Ok(history) => history,
+        Err(error) => {
+            error!(%error, "cannot open history");
             exit(1)
         }
     };
 
-    // create connection
-    let connection = match instance_kind {
-        InstanceKind::ServerKind => {
-            match Connection::new("127.0.0.1:55000", "ws://127.0.0.1:55001") {
-                Ok(connection) => connection,
-                Err(error) => {
-                    eprintln!("cannot open connection -> {}", error);
-                    exit(1)
-                }
+    if commands::WORKSPACE_COMMANDS.contains(&instance_kind_string.as_str()) {
+        match commands::run_workspace_command(
+            store.as_mut(),
+            &mut history,
+            &workspace_path,
+            &instance_kind_string,
+            &arguments[3..],
+        ) {
+            Ok(()) => exit(0),
+            Err(error) => {
+                eprintln!("cannot run '{}' -> {}", instance_kind_string, error);
+                exit(1)
             }
         }
-        InstanceKind::ClientKind => {
-            match Connection::new("127.0.0.1:55001", "ws://127.0.0.1:55000") {
-                Ok(connection) => connection,
-                Err(error) => {
-                    eprintln!("cannot open connection -> {}", error);
-                    exit(1)
-                }
+    }
+
+    let git_repository = match (options.git_mode, options.store_kind.as_str()) {
+        (false, _) => None,
+        (true, "directory") => match GitRepository::open(&workspace_path) {
+            Ok(git_repository) => Some(git_repository),
+            Err(error) => {
+                error!(%error, "cannot open git repository");
+                exit(1)
             }
+        },
+        (true, _) => {
+            eprintln!("--git needs a directory store");
+            exit(1)
         }
     };
 
-    // server sends model to client
-    // TODO: eventually remove this code
-    let mut model = match instance_kind {
-        InstanceKind::ServerKind => {
-            let model = Model::new();
-            println!("send model to client");
-            match connection.send(&model) {
-                Ok(_) => {}
-                Err(error) => {
-                    eprintln!("cannot write model to stream -> {}", error);
-                    exit(1)
-                }
-            };
+    let (listen_address, peer_addresses) =
+        match network_addresses(&arguments, &instance_kind_string) {
+            Ok(addresses) => addresses,
+            Err(error) => {
+                eprintln!("{}", error);
+                exit(1)
+            }
+        };
 
-            model
-        }
-        InstanceKind::ClientKind => {
-            println!("receive model from server");
-            // let mut de = serde_json::Deserializer::from_reader(client.as_reader());
-            // match Model::deserialize(&mut de) {\
-            match connection.as_receiver().recv() {
-                Ok(model) => model,
-                Err(error) => {
-                    eprintln!("cannot read model from stream -> {}", error);
-                    exit(1)
-                }
+    if instance_kind_string == "pair" {
+        match commands::run_pair_command(
+            &workspace_path,
+            &options.name,
+            &listen_address,
+            &peer_addresses,
+            &arguments[3..],
+        ) {
+            Ok(()) => exit(0),
+            Err(error) => {
+                error!(%error, "cannot run 'pair'");
+                exit(1)
             }
         }
+    }
+
+    let mut sync_state = match SyncState::open(&workspace_path) {
+        Ok(sync_state) => sync_state,
+        Err(error) => {
+            error!(%error, "cannot open sync state");
+            exit(1)
+        }
     };
 
-    println!("initialize workspace");
-    match destroy_workspace(&workspace_path) {
-        Ok(_) => {}
+    let workspace_name = workspace_path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let status = Arc::new(Mutex::new(Status::new(
+        &options.name,
+        &sync_state.instance_id,
+        &workspace_name,
+    )));
+    let calls = match control::serve(&workspace_path, status.clone()) {
+        Ok(calls) => calls,
         Err(error) => {
-            eprintln!("cannot destroy workspace -> {}", error);
+            error!(%error, "cannot open control socket");
             exit(1)
         }
     };
-    match init_workspace(&workspace_path, &model) {
-        Ok(_) => {}
+
+    let mut transfer = Transfer::new();
+
+    // the server starts a new or empty workspace with example notes
+    if instance_kind_string == "server" {
+        match store.list() {
+            Ok(entries) if entries.is_empty() => {
+                info!("initialize workspace");
+                write_workspace(store.as_mut(), &Model::new(), &transfer, &status);
+            }
+            Ok(_) => {}
+            Err(error) => {
+                error!(%error, "cannot read workspace");
+                exit(1)
+            }
+        }
+    }
+
+    let mut model = match load_model(store.as_ref()) {
+        Ok(model) => model,
+        Err(error) => {
+            error!(%error, "cannot read workspace");
+            exit(1)
+        }
+    };
+    trash::update_tombstones(&mut model, store.as_ref());
+    trash::purge(store.as_mut(), &mut model, options.retention);
+    keep_attachments(&mut transfer, store.as_ref(), &model, &status);
+    // the changes made while the instance wasn't running are sent like any other
+    match sync_state.has_changed(&model) {
+        Ok(true) => record_change(&mut sync_state, &model, &options.name, &status),
+        Ok(false) => {}
+        Err(error) => status.lock().unwrap().add_error(format!(
+            "could not compare workspace to sync state: {}",
+            error
+        )),
+    }
+    record_history(&mut history, &model, &options.name, &status);
+    commit_changes(&git_repository, &options.name, &status);
+
+    let network = match Network::new(Some(&listen_address), &peer_addresses) {
+        Ok(network) => network,
         Err(error) => {
-            eprintln!("cannot init workspace -> {}", error);
+            error!(%error, "cannot open connection");
             exit(1)
         }
     };
 
-    println!("watch workspace...");
-    let watch_receiver = match watch_workspace(workspace_path.clone(), model.clone()) {
+    // the daemon answers for the instance until it is dropped
+    let _advertisement = if options.advertise {
+        match discovery::advertise(
+            &options.name,
+            &sync_state.instance_id,
+            &workspace_name,
+            &listen_address,
+            false,
+        ) {
+            Ok(daemon) => Some(daemon),
+            Err(error) => {
+                status
+                    .lock()
+                    .unwrap()
+                    .add_error(format!("could not advertise instance: {}", error));
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    info!("watch workspace");
+    let synced_model = model.clone();
+    let model = Arc::new(Mutex::new(model));
+    let watch_receiver = match store.watch(model.clone()) {
         Ok(receiver) => receiver,
         Err(error) => {
-            eprintln!("cannot watch workspace -> {}", error);
+            error!(%error, "cannot watch workspace");
             exit(1)
         }
     };
 
-    loop {
-        match watch_receiver.try_recv() {
-            Ok(updated_model) => {
-                model = updated_model;
-                println!("{}", model);
-                match connection.send(&model) {
-                    Ok(_) => {}
+    let mut instance = Instance {
+        name: options.name,
+        workspace_path,
+        subscription: options.subscription,
+        permissions: options.permissions,
+        paired_only: options.paired_only,
+        retention: options.retention,
+        store,
+        history,
+        git_repository,
+        sync_state,
+        status,
+        transfer,
+        network,
+        model,
+        synced_model,
+        peers: HashMap::new(),
+        outbound_connections: HashSet::new(),
+        nonces: HashMap::new(),
+        pending_peers: HashMap::new(),
+        paused: false,
+        last_purge: Instant::now(),
+    };
+    instance.run(&calls, &watch_receiver);
+}
+
+/// Sets up logging from `--log-level` and `--log-file`.
+fn init_logging(arguments: &[String]) -> Result<(), String> {
+    let log_level = match option_value(arguments, "--log-level") {
+        Some(Some(log_level)) => log_level.clone(),
+        Some(None) => return Err(String::from("--log-level needs a level")),
+        None => std::env::var("RUST_LOG").unwrap_or_else(|_| String::from(DEFAULT_LOG_LEVEL)),
+    };
+    let log_file = match option_value(arguments, "--log-file") {
+        Some(Some(log_file)) => Some(PathBuf::from(log_file)),
+        Some(None) => return Err(String::from("--log-file needs a file")),
+        None => None,
+    };
+    logging::init(&log_level, log_file.as_deref())
+}
+
+/// Options of an instance, from its arguments.
+struct Options {
+    debounce: Duration,
+    rescan_interval: Option<Duration>,
+    durability: Durability,
+    store_kind: String,
+    name: String,
+    retention: Duration,
+    git_mode: bool,
+    advertise: bool,
+    paired_only: bool,
+    permissions: Option<Permissions>,
+    subscription: Subscription,
+}
+
+impl Options {
+    fn parse(arguments: &[String], instance_kind: &str) -> Result<Options, String> {
+        let debounce = match option_value(arguments, "--debounce").map(|value| value?.parse().ok())
+        {
+            Some(Some(milliseconds)) => Duration::from_millis(milliseconds),
+            Some(None) => return Err(String::from("--debounce needs a number of milliseconds")),
+            None => DEFAULT_DEBOUNCE,
+        };
+        let rescan_interval =
+            match option_value(arguments, "--rescan").map(|value| value?.parse().ok()) {
+                Some(Some(seconds)) => Some(Duration::from_secs(seconds)),
+                Some(None) => return Err(String::from("--rescan needs a number of seconds")),
+                None => None,
+            };
+        let durability = match option_value(arguments, "--fsync") {
+            Some(Some(value)) => Durability::parse(value)?,
+            Some(None) => return Err(String::from("--fsync needs a mode")),
+            None => Durability::File,
+        };
+        let store_kind = match option_value(arguments, "--store") {
+            Some(Some(store_kind)) => store_kind.clone(),
+            Some(None) => return Err(String::from("--store needs a kind of store")),
+            None => String::from("directory"),
+        };
+        let name = match option_value(arguments, "--name") {
+            Some(Some(name)) => name.clone(),
+            Some(None) => return Err(String::from("--name needs a name")),
+            None => instance_kind.to_string(),
+        };
+        let retention =
+            match option_value(arguments, "--trash-days").map(|value| value?.parse::<u64>().ok()) {
+                Some(Some(days)) => Duration::from_secs(days * 24 * 60 * 60),
+                Some(None) => return Err(String::from("--trash-days needs a number of days")),
+                None => DEFAULT_RETENTION,
+            };
+        let permissions = match option_value(arguments, "--permissions") {
+            Some(Some(value)) => Some(
+                Permissions::load(Path::new(value))
+                    .map_err(|error| format!("cannot load permissions -> {}", error))?,
+            ),
+            Some(None) => return Err(String::from("--permissions needs a file")),
+            None => None,
+        };
+        Ok(Options {
+            debounce,
+            rescan_interval,
+            durability,
+            store_kind,
+            name,
+            retention,
+            git_mode: arguments.iter().any(|argument| argument == "--git"),
+            advertise: !arguments
+                .iter()
+                .any(|argument| argument == "--no-advertise"),
+            paired_only: arguments.iter().any(|argument| argument == "--paired-only"),
+            permissions,
+            subscription: Subscription {
+                include: option_values(arguments, "--include"),
+                exclude: option_values(arguments, "--exclude"),
+            },
+        })
+    }
+}
+
+/// Value of an option given once, like `--name alice`: `None` without the option, `Some(None)`
+/// when it has no value.
+fn option_value<'a>(arguments: &'a [String], option: &str) -> Option<Option<&'a String>> {
+    let index = arguments.iter().position(|argument| argument == option)?;
+    Some(arguments.get(index + 1))
+}
+
+fn open_store(workspace_path: &Path, options: &Options) -> Box<dyn NoteStore> {
+    match options.store_kind.as_str() {
+        "directory" => match DirectoryStore::open(
+            workspace_path,
+            options.durability,
+            options.debounce,
+            options.rescan_interval,
+        ) {
+            Ok(store) => Box::new(store),
+            Err(error) => {
+                error!(%error, "cannot open workspace");
+                exit(1)
+            }
+        },
+        "sqlite" => match SqliteStore::open(workspace_path) {
+            Ok(store) => Box::new(store),
+            Err(error) => {
+                error!(%error, "cannot open workspace");
+                exit(1)
+            }
+        },
+        _ => {
+            eprintln!(
+                "invalid store '{}', expected 'directory' or 'sqlite'",
+                options.store_kind
+            );
+            exit(1)
+        }
+    }
+}
+
+/// Address to listen on and URLs of the peers to connect to. `server` and `client` are presets
+/// for two instances on this machine, the other instances only listen on the network when given
+/// an address with `--listen`.
+fn network_addresses(
+    arguments: &[String],
+    instance_kind: &str,
+) -> Result<(String, Vec<String>), String> {
+    let (default_listen_address, mut peer_addresses) = match instance_kind {
+        "sync" | "pair" => ("127.0.0.1:55000", Vec::new()),
+        "server" => ("127.0.0.1:55000", Vec::new()),
+        "client" => (
+            "127.0.0.1:55001",
+            vec![String::from("ws://127.0.0.1:55000")],
+        ),
+        _ => return Err(format!("invalid instance kind '{}'", instance_kind)),
+    };
+    let listen_address = match option_value(arguments, "--listen") {
+        Some(Some(listen_address)) => listen_address.clone(),
+        Some(None) => return Err(String::from("--listen needs an address")),
+        None => String::from(default_listen_address),
+    };
+    peer_addresses.extend(option_values(arguments, "--peer"));
+    Ok((listen_address, peer_addresses))
+}
+
+/// An instance syncing its workspace with its peers.
+struct Instance {
+    name: String,
+    workspace_path: PathBuf,
+    subscription: Subscription,
+    permissions: Option<Permissions>,
+    paired_only: bool,
+    retention: Duration,
+    store: Box<dyn NoteStore>,
+    history: History,
+    git_repository: Option<GitRepository>,
+    sync_state: SyncState,
+    status: Arc<Mutex<Status>>,
+    transfer: Transfer,
+    network: Network,
+    /// Model of the workspace, shared with the watcher.
+    model: Arc<Mutex<Model>>,
+    /// Last model sent to or received from the peers.
+    synced_model: Model,
+    peers: HashMap<ConnectionId, Peer>,
+    outbound_connections: HashSet<ConnectionId>,
+    /// Nonce of the hello sent on each connection.
+    nonces: HashMap<ConnectionId, String>,
+    /// Paired peers, with their session, until they send their proof.
+    pending_peers: HashMap<ConnectionId, Peer>,
+    /// While paused, nothing is sent or received.
+    paused: bool,
+    last_purge: Instant,
+}
+
+impl Instance {
+    /// Answers the calls of the control socket, sends the changes of the workspace and handles
+    /// the events of the network, until the process is stopped.
+    fn run(&mut self, calls: &Receiver<Call>, watch_receiver: &Receiver<Model>) {
+        loop {
+            // a rescan changes the model like the watcher does
+            let mut rescanned = false;
+            for call in calls.try_iter() {
+                rescanned |= self.answer_call(call);
+            }
+            // the model is shared with the watcher, its updates are all in it already
+            if watch_receiver.try_iter().count() > 0 || rescanned {
+                self.send_workspace_changes();
+            }
+            if let Some(event) = self.next_event() {
+                self.handle_event(event);
+            }
+            if self.last_purge.elapsed() > PURGE_INTERVAL {
+                self.purge_trash();
+            }
+        }
+    }
+
+    /// Answers a call of the control socket. Returns whether it was a rescan that changed the
+    /// model.
+    fn answer_call(&mut self, call: Call) -> bool {
+        let mut rescanned = false;
+        let result = match &call.command {
+            Command::ListNotes => Ok(list_notes(&self.model.lock().unwrap())),
+            Command::GetNote { key } => {
+                get_note(self.store.as_ref(), &self.model.lock().unwrap(), key)
+            }
+            Command::PutNote { key, text } => put_note(self.store.as_mut(), key, text),
+            Command::Pause => {
+                self.paused = true;
+                self.status.lock().unwrap().paused = true;
+                info!("sync paused");
+                Ok(Value::Null)
+            }
+            Command::Resume => {
+                if self.paused {
+                    self.paused = false;
+                    self.status.lock().unwrap().paused = false;
+                    info!("sync resumed");
+                    // the peers get the changes made while paused
+                    let model = self.model.lock().unwrap().clone();
+                    self.send_to_peers(&model);
+                }
+                Ok(Value::Null)
+            }
+            Command::Rescan => {
+                let mut model = self.model.lock().unwrap();
+                load_model(self.store.as_ref()).map(|stored_model| {
+                    rescanned = note_store::update_model(&mut model, stored_model);
+                    Value::Null
+                })
+            }
+            Command::ResolveConflict { key, keep } => resolve_conflict(
+                self.store.as_mut(),
+                &self.status,
+                &mut self.sync_state,
+                key,
+                *keep,
+            ),
+            // answered by the control socket
+            Command::Status | Command::ListPeers => Ok(Value::Null),
+        };
+        call.answer(result);
+        rescanned
+    }
+
+    /// Records the changes of the workspace and sends them to the peers. The files deleted from
+    /// the workspace are only in the previous model anymore, they are moved to the trash.
+    fn send_workspace_changes(&mut self) {
+        let updated_model = {
+            let mut model = self.model.lock().unwrap();
+            trash::trash_deleted(
+                self.store.as_mut(),
+                &self.synced_model,
+                &mut model,
+                &self.transfer,
+            );
+            trash::update_tombstones(&mut model, self.store.as_ref());
+            model.clone()
+        };
+        keep_attachments(
+            &mut self.transfer,
+            self.store.as_ref(),
+            &updated_model,
+            &self.status,
+        );
+        log_changes(&self.name, &self.synced_model, &updated_model);
+        self.synced_model = updated_model.clone();
+        record_history(&mut self.history, &updated_model, &self.name, &self.status);
+        commit_changes(&self.git_repository, &self.name, &self.status);
+        record_change(
+            &mut self.sync_state,
+            &updated_model,
+            &self.name,
+            &self.status,
+        );
+        if !self.paused {
+            self.send_to_peers(&updated_model);
+        }
+    }
+
+    /// Empties the trash of the notes deleted for longer than the retention, the peers get the
+    /// model without them.
+    fn purge_trash(&mut self) {
+        self.last_purge = Instant::now();
+        let model = {
+            let mut model = self.model.lock().unwrap();
+            if !trash::purge(self.store.as_mut(), &mut model, self.retention) {
+                return;
+            }
+            model.clone()
+        };
+        keep_attachments(
+            &mut self.transfer,
+            self.store.as_ref(),
+            &model,
+            &self.status,
+        );
+        self.synced_model = model.clone();
+        record_change(&mut self.sync_state, &model, &self.name, &self.status);
+        if !self.paused {
+            self.send_to_peers(&model);
+        }
+    }
+
+    fn send_to_peers(&mut self, model: &Model) {
+        for (id, peer) in self.peers.iter() {
+            send_model(
+                &self.network,
+                *id,
+                &mut self.transfer,
+                self.store.as_ref(),
+                peer,
+                &self.sync_state.version,
+                model,
+                &self.status,
+            );
+        }
+    }
+
+    /// Next event of the network, waiting at most `POLL_INTERVAL` for it. While paused, the
+    /// events wait in the channel. The messages of a synced peer are opened, the ones that can't
+    /// be are dropped.
+    fn next_event(&self) -> Option<Event> {
+        if self.paused {
+            thread::sleep(POLL_INTERVAL);
+            return None;
+        }
+        let event = match self.network.as_receiver().recv_timeout(POLL_INTERVAL) {
+            Ok(event) => event,
+            Err(RecvTimeoutError::Timeout) => return None,
+            // no connection can be made anymore, the calls and changes are still handled
+            Err(RecvTimeoutError::Disconnected) => {
+                thread::sleep(POLL_INTERVAL);
+                return None;
+            }
+        };
+        match event {
+            Event::Message(id, message) if self.peers.contains_key(&id) => {
+                let peer = &self.peers[&id];
+                match peer.open(message) {
+                    Ok(message) => Some(Event::Message(id, message)),
                     Err(error) => {
-                        eprintln!("cannot write model to stream -> {}", error);
-                        exit(1)
+                        self.status.lock().unwrap().add_error(format!(
+                            "refused a message of {}: {}",
+                            peer.hello.name, error
+                        ));
+                        None
+                    }
+                }
+            }
+            event => Some(event),
+        }
+    }
+
+    fn handle_event(&mut self, event: Event) {
+        let id = match &event {
+            Event::Connected(id, _) | Event::Message(id, _) | Event::Disconnected(id) => *id,
+        };
+        let _span = info_span!("connection", id).entered();
+        // peer to sync with once its hello is received, and its proof if it is paired
+        let accepted_peer = match event {
+            Event::Connected(id, outbound) => {
+                self.send_hello(id, outbound);
+                None
+            }
+            Event::Disconnected(id) => {
+                self.disconnect(id);
+                None
+            }
+            Event::Message(id, Message::Hello(hello)) => self.receive_hello(id, hello),
+            Event::Message(id, Message::Proof(proof)) => self.receive_proof(id, &proof),
+            Event::Message(id, Message::Rejected(keys)) => {
+                if let Some(peer) = self.peers.get(&id) {
+                    self.status.lock().unwrap().add_error(format!(
+                        "{} rejected the changes to {}, they are undone",
+                        peer.hello.name,
+                        keys.join(", ")
+                    ));
+                }
+                None
+            }
+            Event::Message(id, message) if self.peers.contains_key(&id) => {
+                match self.transfer.receive(id, message) {
+                    Ok(Some((received_model, version))) => {
+                        self.receive_model(id, received_model, version)
                     }
+                    Ok(None) => {}
+                    Err(error) => self
+                        .status
+                        .lock()
+                        .unwrap()
+                        .add_error(format!("cannot receive model -> {}", error)),
+                }
+                None
+            }
+            // the messages of a connection that is not synced are ignored
+            Event::Message(_, _) => None,
+        };
+        if let Some((id, peer)) = accepted_peer {
+            self.accept_peer(id, peer);
+        }
+    }
+
+    fn send_hello(&mut self, id: ConnectionId, outbound: bool) {
+        if outbound {
+            self.outbound_connections.insert(id);
+        }
+        let nonce = pairing::new_nonce();
+        let hello = Hello {
+            name: self.name.clone(),
+            subscription: self.subscription.clone(),
+            instance_id: self.sync_state.instance_id.clone(),
+            nonce: nonce.clone(),
+        };
+        self.nonces.insert(id, nonce);
+        if let Err(error) = self.network.send(id, &Message::Hello(hello)) {
+            self.status
+                .lock()
+                .unwrap()
+                .add_error(format!("could not write hello to stream: {}", error));
+        }
+    }
+
+    fn disconnect(&mut self, id: ConnectionId) {
+        self.outbound_connections.remove(&id);
+        self.nonces.remove(&id);
+        self.pending_peers.remove(&id);
+        self.transfer.forget_peer(id);
+        if let Some(peer) = self.peers.remove(&id) {
+            info!(peer = %peer.hello.name, "disconnected");
+            self.status
+                .lock()
+                .unwrap()
+                .remove_peer(&peer.hello.instance_id);
+        }
+    }
+
+    /// Returns the peer of a hello to sync with, unless it is refused or paired: a paired peer
+    /// waits for its proof.
+    fn receive_hello(&mut self, id: ConnectionId, hello: Hello) -> Option<(ConnectionId, Peer)> {
+        // of two connections between the same instances, both keep the one opened by the
+        // instance with the greatest id
+        let has_duplicate = self.peers.iter().any(|(other_id, peer)| {
+            *other_id != id
+                && !hello.instance_id.is_empty()
+                && peer.hello.instance_id == hello.instance_id
+        });
+        let is_kept = if has_duplicate {
+            self.outbound_connections.contains(&id)
+                == (self.sync_state.instance_id > hello.instance_id)
+        } else {
+            hello.instance_id != self.sync_state.instance_id
+        };
+        // the keys are read again so that the peers paired while running are known
+        let key = match PeerKeys::open(&self.workspace_path) {
+            Ok(peer_keys) => peer_keys.get(&hello.instance_id),
+            Err(error) => {
+                self.status
+                    .lock()
+                    .unwrap()
+                    .add_error(format!("could not read keys: {}", error));
+                None
+            }
+        };
+        match Scope::new(&[&self.subscription, &hello.subscription]) {
+            Ok(_) if is_kept && key.is_none() && self.paired_only => self
+                .status
+                .lock()
+                .unwrap()
+                .add_error(format!("refused {}, it isn't paired", hello.name)),
+            Ok(scope) if is_kept => {
+                // the instance id of a paired peer is checked with its proof
+                let paired_instance_id = key.as_ref().map(|_| hello.instance_id.as_str());
+                let peer = Peer {
+                    // without permissions the peer can change everything
+                    permissions: self
+                        .permissions
+                        .as_ref()
+                        .map(|permissions| permissions.for_peer(paired_instance_id)),
+                    session: key.map(|key| {
+                        Session::new(
+                            &key,
+                            self.nonces
+                                .get(&id)
+                                .map(|nonce| nonce.as_str())
+                                .unwrap_or(""),
+                            &hello.nonce,
+                            &self.sync_state.instance_id,
+                            &hello.instance_id,
+                        )
+                    }),
+                    scope,
+                    hello,
                 };
+                match &peer.session {
+                    // a paired peer proves it has the key before it is synced with
+                    Some(session) => {
+                        let proof = Message::Proof(session.proof());
+                        if let Err(error) = self.network.send(id, &proof) {
+                            self.status
+                                .lock()
+                                .unwrap()
+                                .add_error(format!("could not write proof to stream: {}", error));
+                        }
+                        self.pending_peers.insert(id, peer);
+                    }
+                    None => return Some((id, peer)),
+                }
+            }
+            Ok(_) => {}
+            Err(error) => self.status.lock().unwrap().add_error(format!(
+                "invalid subscription of {} -> {}",
+                hello.name, error
+            )),
+        }
+        None
+    }
+
+    /// Returns the pending peer of a connection to sync with once its proof is checked.
+    fn receive_proof(&mut self, id: ConnectionId, proof: &str) -> Option<(ConnectionId, Peer)> {
+        let peer = self.pending_peers.remove(&id)?;
+        let is_proven = match &peer.session {
+            Some(session) => session.verify_proof(proof),
+            None => false,
+        };
+        if is_proven {
+            return Some((id, peer));
+        }
+        self.status.lock().unwrap().add_error(format!(
+            "refused {}, it doesn't have its key",
+            peer.hello.name
+        ));
+        None
+    }
+
+    /// Starts syncing with a peer, which gets the model with the most recent changes.
+    fn accept_peer(&mut self, id: ConnectionId, peer: Peer) {
+        self.peers
+            .retain(|_, other_peer| other_peer.hello.instance_id != peer.hello.instance_id);
+        info!(
+            peer = %peer.hello.name,
+            instance_id = %peer.hello.instance_id,
+            "connected"
+        );
+        self.status
+            .lock()
+            .unwrap()
+            .add_peer(&peer.hello.name, &peer.hello.instance_id);
+        let model = self.model.lock().unwrap().clone();
+        send_model(
+            &self.network,
+            id,
+            &mut self.transfer,
+            self.store.as_ref(),
+            &peer,
+            &self.sync_state.version,
+            &model,
+            &self.status,
+        );
+        self.peers.insert(id, peer);
+    }
+
+    /// Applies a model received from a peer to the workspace, merging it with the local one when
+    /// both changed, and relays it to the other peers.
+    fn receive_model(&mut self, id: ConnectionId, received_model: Model, version: Version) {
+        let peer = &self.peers[&id];
+        self.status.lock().unwrap().synced(&peer.hello.instance_id);
+        let mut model = self.model.lock().unwrap();
+        let order = self.sync_state.version.compare(&version);
+        // a model is received again from every peer it is relayed to
+        if order != Order::Before && order != Order::Concurrent {
+            return;
+        }
+        let received_model = peer.scope.merge(&model, received_model);
+        let author = match version.author.as_str() {
+            "" => &peer.hello.name,
+            author => author,
+        };
+        let (mut updated_model, updated_version) = if order == Order::Concurrent {
+            info!(peer = %peer.hello.name, "merge concurrent changes");
+            let merge = sync_state::merge_concurrent(
+                &model,
+                &self.sync_state.version,
+                &received_model,
+                &version,
+            );
+            for received_wins in [true, false] {
+                let conflicting_keys: Vec<String> = merge
+                    .conflicts
+                    .iter()
+                    .filter(|(_, other_kept)| *other_kept == received_wins)
+                    .map(|(key, _)| key.clone())
+                    .collect();
+                if conflicting_keys.is_empty() {
+                    continue;
+                }
+                let (kept, discarded_model) = if received_wins {
+                    (author, &*model)
+                } else {
+                    (self.name.as_str(), &received_model)
+                };
+                record_conflict(
+                    &mut self.sync_state,
+                    &self.status,
+                    conflicting_keys,
+                    discarded_model,
+                    &peer.hello.name,
+                    kept,
+                );
+            }
+            (merge.model, merge.version)
+        } else {
+            (received_model, version.clone())
+        };
+        let merged_model = updated_model.clone();
+        trash::remove_stale_notes(&model, &mut updated_model);
+        let rejected_keys = match &peer.permissions {
+            Some(permissions) => permissions.reject_changes(&model, &mut updated_model),
+            None => Vec::new(),
+        };
+        if updated_model != *model {
+            log_changes(author, &model, &updated_model);
+            let written_keys = update_workspace(
+                self.store.as_mut(),
+                &model,
+                &updated_model,
+                &self.transfer,
+                &self.status,
+            );
+            *model = updated_model.clone();
+            // the written notes get the timestamps of their file when read back, the model
+            // needs them too so that the watcher doesn't see a change
+            for key in written_keys {
+                if let Err(error) = note_store::read_entry(&mut model, self.store.as_ref(), &key) {
+                    self.status
+                        .lock()
+                        .unwrap()
+                        .add_error(format!("could not read back note '{}': {}", key, error));
+                }
+            }
+            keep_attachments(
+                &mut self.transfer,
+                self.store.as_ref(),
+                &model,
+                &self.status,
+            );
+            record_history(&mut self.history, &model, author, &self.status);
+            commit_changes(&self.git_repository, author, &self.status);
+        }
+        self.synced_model = model.clone();
+        if let Err(error) = self.sync_state.record_received(&model, updated_version) {
+            self.status
+                .lock()
+                .unwrap()
+                .add_error(format!("could not save sync state: {}", error));
+        }
+        // stale notes and rejected changes make a model the peer doesn't have, a merge too but
+        // the peer merges to the same one
+        let is_new_change = updated_model != merged_model;
+        if is_new_change {
+            record_change(&mut self.sync_state, &model, &self.name, &self.status);
+        }
+        if !rejected_keys.is_empty() {
+            self.status.lock().unwrap().add_error(format!(
+                "rejected the changes of {} to {}",
+                peer.hello.name,
+                rejected_keys.join(", ")
+            ));
+            if let Err(error) = peer.send(&self.network, id, &Message::Rejected(rejected_keys)) {
+                self.status
+                    .lock()
+                    .unwrap()
+                    .add_error(format!("could not write rejection to stream: {}", error));
             }
-            Err(_) => {}
         }
-        match connection.as_receiver().try_recv() {
-            Ok(updated_model) => {
-                model = updated_model;
-                println!("{}", model);
-                write_workspace(&workspace_path, &model);
+        // the changes are relayed to the other peers, the peer gets the model back when it
+        // changed
+        for (other_id, other_peer) in self.peers.iter() {
+            if *other_id != id || is_new_change || order == Order::Concurrent {
+                send_model(
+                    &self.network,
+                    *other_id,
+                    &mut self.transfer,
+                    self.store.as_ref(),
+                    other_peer,
+                    &self.sync_state.version,
+                    &model,
+                    &self.status,
+                );
             }
-            Err(_) => {}
         }
     }
 }
 
-fn destroy_workspace(workspace_path: &Path) -> Result<(), String> {
-    if !workspace_path.exists() {
-        Ok(())
-    } else {
-        match remove_dir_all(workspace_path) {
-            Ok(_) => Ok(()),
-            Err(error) => Err(format!(
-                "cannot delete workspace '{:?}': {}",
-                workspace_path, error
-            )),
+/// A connected instance, once its hello is received.
+struct Peer {
+    hello: Hello,
+    /// Notes synced with the peer.
+    scope: Scope,
+    permissions: Option<PeerPermissions>,
+    /// Authenticates the messages of a paired peer.
+    session: Option<Session>,
+}
+
+impl Peer {
+    /// Sends a message to the peer, sealed when it is paired.
+    fn send(&self, network: &Network, id: ConnectionId, message: &Message) -> Result<(), String> {
+        match &self.session {
+            Some(session) => network.send(id, &session.seal(message)?),
+            None => network.send(id, message),
+        }
+    }
+
+    /// Message received from the peer as it was sent: a paired peer only sends sealed ones.
+    fn open(&self, message: Message) -> Result<Message, String> {
+        match (&self.session, message) {
+            (Some(session), Message::Sealed(sealed_message)) => session.open(sealed_message),
+            (Some(session), Message::SealedChunk(sealed_chunk)) => session.open_chunk(sealed_chunk),
+            (Some(_), message) => Err(format!("unsealed {} message", message.kind())),
+            (None, Message::Sealed(_) | Message::SealedChunk(_)) => {
+                Err(String::from("sealed message without a session"))
+            }
+            (None, message) => Ok(message),
         }
     }
 }
 
-fn init_workspace(workspace_path: &Path, model: &Model) -> Result<(), String> {
-    let path = Path::new(workspace_path);
-    match create_dir(path) {
-        Ok(_) => {}
-        Err(error) => {
-            return Err(format!(
-                "could not create directory '{:?}': {}",
-                workspace_path, error
-            ))
+/// Counts a change made by this instance, pending until it is sent to a peer.
+fn record_change(sync_state: &mut SyncState, model: &Model, name: &str, status: &Mutex<Status>) {
+    let mut status = status.lock().unwrap();
+    status.pending_changes += 1;
+    if let Err(error) = sync_state.record_change(model, name) {
+        status.add_error(format!("could not save sync state: {}", error));
+    }
+}
+
+fn record_history(history: &mut History, model: &Model, author: &str, status: &Mutex<Status>) {
+    if let Err(error) = history.record(model, author) {
+        status
+            .lock()
+            .unwrap()
+            .add_error(format!("could not record history: {}", error));
+    }
+}
+
+/// Commits the changes of the workspace in git mode.
+fn commit_changes(git_repository: &Option<GitRepository>, author: &str, status: &Mutex<Status>) {
+    if let Some(git_repository) = git_repository {
+        if let Err(error) = git_repository.commit(author) {
+            status
+                .lock()
+                .unwrap()
+                .add_error(format!("could not commit changes: {}", error));
         }
-    };
-    write_workspace(workspace_path, model);
-    Ok(())
+    }
 }
 
-fn write_workspace(workspace_path: &Path, model: &Model) -> () {
-    model
+/// Notes of the model, for `list_notes`.
+fn list_notes(model: &Model) -> Value {
+    let notes: Vec<NoteEntry> = model
         .notes
         .iter()
-        .for_each(|(path, note)| write_note(workspace_path.join(path).as_path(), note));
+        .map(|(key, note)| NoteEntry {
+            key: key.clone(),
+            title: note.title.clone(),
+            modified: note.modified,
+        })
+        .collect();
+    serde_json::to_value(notes).unwrap_or_default()
 }
 
-fn write_note(path: &Path, note: &Note) -> () {
-    if path.exists() {
-        match OpenOptions::new().write(true).open(path) {
-            Ok(mut file) => match file.set_len(0) {
-                Ok(()) => match write!(file, "{}\n\n{}", note.title, note.body) {
-                    Ok(_) => (),
-                    Err(error) => {
-                        eprintln!("could not write file '{:?}': {}", path, error);
-                    }
-                },
-                Err(error) => {
-                    eprintln!("could not clear file '{:?}': {}", path, error);
-                }
-            },
-            Err(error) => {
-                eprintln!("cannot update existing note -> {}", error)
+/// Contents of the file of a note, for `get_note`.
+fn get_note(store: &dyn NoteStore, model: &Model, key: &str) -> Result<Value, String> {
+    if !model.notes.contains_key(key) {
+        return Err(format!("no note '{}'", key));
+    }
+    let file = store.read(key)?;
+    match String::from_utf8(file.data) {
+        Ok(text) => Ok(Value::String(text)),
+        Err(error) => Err(format!("cannot read note '{}' -> {}", key, error)),
+    }
+}
+
+/// Writes the file of a note, for `put_note`. The watcher notices it like any other change.
+fn put_note(store: &mut dyn NoteStore, key: &str, text: &str) -> Result<Value, String> {
+    if !note_store::is_valid_key(key) || trash::original_key(key).is_some() {
+        return Err(format!("invalid key '{}'", key));
+    }
+    if attachments::is_attachment(Path::new(key), text.as_bytes()) {
+        return Err(format!("'{}' is not a note", key));
+    }
+    note_file::parse(text).map_err(|error| format!("invalid note '{}' -> {}", key, error))?;
+    store.write(key, text.as_bytes(), None)?;
+    Ok(Value::Null)
+}
+
+/// Keeps the discarded versions of the notes of a conflict, so that it can be resolved, and
+/// reports it.
+fn record_conflict(
+    sync_state: &mut SyncState,
+    status: &Mutex<Status>,
+    keys: Vec<String>,
+    discarded_model: &Model,
+    peer: &str,
+    kept: &str,
+) {
+    for key in keys.iter() {
+        if let Some(note) = discarded_model.notes.get(key) {
+            if let Err(error) = sync_state.keep_discarded(key, note.clone()) {
+                status
+                    .lock()
+                    .unwrap()
+                    .add_error(format!("could not save sync state: {}", error));
             }
         }
-    } else {
-        match File::create(path.clone()) {
-            Ok(mut file) => match write!(file, "{}\n\n{}", note.title, note.body) {
-                Ok(_) => (),
-                Err(error) => {
-                    println!("could not write file '{:?}': {}", path, error)
-                }
-            },
+    }
+    warn!(
+        kept,
+        keys = %keys.join(", "),
+        "conflict, only the versions of one instance are kept"
+    );
+    status.lock().unwrap().add_conflict(keys, peer, kept);
+}
+
+/// Ends the conflict on a note, for `resolve_conflict`. The discarded version is written as a
+/// new change, which the peers then get. The discarded versions are kept in the sync state,
+/// their conflicts can be resolved after a restart.
+fn resolve_conflict(
+    store: &mut dyn NoteStore,
+    status: &Mutex<Status>,
+    sync_state: &mut SyncState,
+    key: &str,
+    keep: Resolution,
+) -> Result<Value, String> {
+    let discarded_note = sync_state.discarded_note(key);
+    if !status.lock().unwrap().has_conflict(key) && discarded_note.is_none() {
+        return Err(format!("no conflict on '{}'", key));
+    }
+    if keep == Resolution::Discarded {
+        let note = match discarded_note {
+            Some(note) => note,
+            None => return Err(format!("the discarded version of '{}' isn't kept", key)),
+        };
+        store.write(key, note_file::serialize(note)?.as_bytes(), None)?;
+    }
+    sync_state.forget_discarded(key)?;
+    status.lock().unwrap().resolve(key);
+    Ok(Value::Null)
+}
+
+/// Logs the keys changed from one model to the next, and the contents of the changed notes at
+/// the trace level only.
+fn log_changes(author: &str, model: &Model, updated_model: &Model) {
+    let changed_keys = sync_state::changed_keys(model, updated_model);
+    if changed_keys.is_empty() {
+        return;
+    }
+    info!(author, keys = %changed_keys.join(", "), "changed");
+    for key in changed_keys.iter() {
+        if let Some(note) = updated_model.notes.get(key) {
+            trace!(%key, %note, "note contents");
+        }
+    }
+}
+
+/// Values of an option that can be given several times, like `--include a --include b`.
+fn option_values(arguments: &[String], option: &str) -> Vec<String> {
+    arguments
+        .windows(2)
+        .filter(|pair| pair[0] == option)
+        .map(|pair| pair[1].clone())
+        .collect()
+}
+
+/// Sends a peer the part of a model in its scope, with the chunks of its contents that the peer
+/// doesn't have yet.
+#[allow(clippy::too_many_arguments)]
+fn send_model(
+    network: &Network,
+    id: ConnectionId,
+    transfer: &mut Transfer,
+    store: &dyn NoteStore,
+    peer: &Peer,
+    version: &Version,
+    model: &Model,
+    status: &Mutex<Status>,
+) {
+    let mut model = peer.scope.filter(model);
+    let blobs = read_attachments(store, &mut model, transfer, status);
+    let result = transfer
+        .send(id, &model, version, &blobs)
+        .and_then(|messages| {
+            messages
+                .iter()
+                .try_for_each(|message| peer.send(network, id, message))
+        });
+    let mut status = status.lock().unwrap();
+    match result {
+        Ok(()) => {
+            status.synced(&peer.hello.instance_id);
+            status.pending_changes = 0;
+        }
+        Err(error) => {
+            status.add_error(format!(
+                "could not send model to {}: {}",
+                peer.hello.name, error
+            ));
+        }
+    }
+}
+
+/// Keeps the contents of the attachments of the local model in the transfer, reading the new
+/// ones from the store, so that they can be moved to the trash once deleted from it.
+fn keep_attachments(
+    transfer: &mut Transfer,
+    store: &dyn NoteStore,
+    model: &Model,
+    status: &Mutex<Status>,
+) {
+    let mut model = model.clone();
+    let blobs = read_attachments(store, &mut model, transfer, status);
+    transfer.keep_attachments(&model, &blobs);
+}
+
+/// Reads the contents of the attachments of the model that were never sent. An attachment that
+/// changed since the model was updated is sent with its current contents, one that can't be
+/// read anymore is not sent.
+fn read_attachments(
+    store: &dyn NoteStore,
+    model: &mut Model,
+    transfer: &Transfer,
+    status: &Mutex<Status>,
+) -> HashMap<String, Vec<u8>> {
+    let mut blobs = HashMap::new();
+    model.attachments.retain(|key, attachment| {
+        if !transfer.needs_blob(&attachment.hash) {
+            return true;
+        }
+        match store.read(key) {
+            Ok(file) => {
+                *attachment = attachments::attachment(Path::new(key), &file.data);
+                blobs.insert(attachment.hash.clone(), file.data);
+                true
+            }
             Err(error) => {
-                println!("could not create file '{:?}': {}", path, error)
+                status
+                    .lock()
+                    .unwrap()
+                    .add_error(format!("could not read attachment '{}': {}", key, error));
+                false
             }
         }
+    });
+    blobs
+}
+
+fn write_workspace(
+    store: &mut dyn NoteStore,
+    model: &Model,
+    transfer: &Transfer,
+    status: &Mutex<Status>,
+) {
+    model
+        .folders
+        .iter()
+        .for_each(|key| create_folder(store, key, status));
+    model
+        .notes
+        .iter()
+        .for_each(|(key, note)| write_note(store, key, note, status));
+    model.attachments.iter().for_each(|(key, attachment)| {
+        write_attachment(store, key, &attachment.hash, transfer, status)
+    });
+}
+
+/// Applies the differences between two models to the workspace: removes the notes,
+/// attachments and folders that disappeared, creates the new folders and writes the notes and
+/// attachments that changed. Returns the keys of the written notes.
+fn update_workspace(
+    store: &mut dyn NoteStore,
+    model: &Model,
+    updated_model: &Model,
+    transfer: &Transfer,
+    status: &Mutex<Status>,
+) -> Vec<String> {
+    model
+        .notes
+        .keys()
+        .chain(model.attachments.keys())
+        .filter(|key| {
+            !updated_model.notes.contains_key(*key) && !updated_model.attachments.contains_key(*key)
+        })
+        .for_each(|key| delete_entry(store, key, status));
+    model
+        .folders
+        .iter()
+        .filter(|key| !updated_model.folders.contains(*key))
+        .for_each(|key| delete_entry(store, key, status));
+    updated_model
+        .folders
+        .iter()
+        .filter(|key| !model.folders.contains(*key))
+        .for_each(|key| create_folder(store, key, status));
+    updated_model
+        .attachments
+        .iter()
+        .filter(|(key, attachment)| model.attachments.get(*key) != Some(attachment))
+        .for_each(|(key, attachment)| {
+            write_attachment(store, key, &attachment.hash, transfer, status)
+        });
+    updated_model
+        .notes
+        .iter()
+        .filter(|(key, note)| model.notes.get(*key) != Some(note))
+        .map(|(key, note)| {
+            write_note(store, key, note, status);
+            key.to_string()
+        })
+        .collect()
+}
+
+fn create_folder(store: &mut dyn NoteStore, key: &str, status: &Mutex<Status>) {
+    let _span = debug_span!("note", key).entered();
+    debug!("create folder");
+    if let Err(error) = store.create_folder(key) {
+        status
+            .lock()
+            .unwrap()
+            .add_error(format!("could not create folder '{}': {}", key, error));
+    }
+}
+
+fn delete_entry(store: &mut dyn NoteStore, key: &str, status: &Mutex<Status>) {
+    let _span = debug_span!("note", key).entered();
+    debug!("remove");
+    if let Err(error) = store.delete(key) {
+        status
+            .lock()
+            .unwrap()
+            .add_error(format!("could not remove '{}': {}", key, error));
+    }
+}
+
+fn write_attachment(
+    store: &mut dyn NoteStore,
+    key: &str,
+    hash: &str,
+    transfer: &Transfer,
+    status: &Mutex<Status>,
+) {
+    let _span = debug_span!("note", key).entered();
+    debug!(hash, "write attachment");
+    let data = match transfer.blob(hash) {
+        Ok(data) => data,
+        Err(error) => {
+            status.lock().unwrap().add_error(format!(
+                "cannot get contents of attachment '{}' -> {}",
+                key, error
+            ));
+            return;
+        }
+    };
+    if let Err(error) = store.write(key, &data, None) {
+        status
+            .lock()
+            .unwrap()
+            .add_error(format!("could not write attachment '{}': {}", key, error));
+    }
+}
+
+fn write_note(store: &mut dyn NoteStore, key: &str, note: &Note, status: &Mutex<Status>) {
+    let _span = debug_span!("note", key).entered();
+    debug!("write");
+    let text = match note_file::serialize(note) {
+        Ok(text) => text,
+        Err(error) => {
+            status
+                .lock()
+                .unwrap()
+                .add_error(format!("cannot serialize note '{}' -> {}", key, error));
+            return;
+        }
+    };
+    trace!(%text, "note contents");
+    // keep the modification time of the note so that it survives the round-trip to disk
+    let modified = match note.modified {
+        0 => None,
+        modified => Some(UNIX_EPOCH + Duration::from_secs(modified)),
+    };
+    if let Err(error) = store.write(key, text.as_bytes(), modified) {
+        status
+            .lock()
+            .unwrap()
+            .add_error(format!("could not write note '{}': {}", key, error));
     }
 }
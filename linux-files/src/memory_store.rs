@@ -0,0 +1,195 @@
+use std::collections::BTreeMap;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::SystemTime;
+use tracing::error;
+
+use crate::model::Model;
+use crate::note_store::{load_model, update_model, NoteStore, StoreEntry, StoredFile};
+
+enum MemoryEntry {
+    Folder,
+    File(StoredFile),
+}
+
+/// Store keeping everything in memory, for tests and tools that don't need files on disk.
+///
+/// Clones share their contents: a change made through a clone is a change made outside of the
+/// sync engine for the others, and is sent by their `watch`.
+#[derive(Clone, Default)]
+pub struct MemoryStore {
+    entries: Arc<Mutex<BTreeMap<String, MemoryEntry>>>,
+    /// Notified after each change.
+    watchers: Arc<Mutex<Vec<Sender<()>>>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+
+    fn notify_watchers(&self) {
+        self.watchers
+            .lock()
+            .unwrap()
+            .retain(|watcher| watcher.send(()).is_ok());
+    }
+
+    /// Creates the missing parent folders of a key.
+    fn create_parents(entries: &mut BTreeMap<String, MemoryEntry>, key: &str) {
+        let mut parent = key;
+        while let Some((folder, _)) = parent.rsplit_once('/') {
+            entries
+                .entry(folder.to_string())
+                .or_insert(MemoryEntry::Folder);
+            parent = folder;
+        }
+    }
+}
+
+/// Whether a key is the one of a folder or of something in it.
+fn is_in(key: &str, folder: &str) -> bool {
+    key == folder
+        || key
+            .strip_prefix(folder)
+            .map(|rest| rest.starts_with('/'))
+            .unwrap_or(false)
+}
+
+impl NoteStore for MemoryStore {
+    fn list(&self) -> Result<Vec<StoreEntry>, String> {
+        Ok(self
+            .entries
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(key, entry)| StoreEntry {
+                key: key.clone(),
+                is_folder: matches!(entry, MemoryEntry::Folder),
+            })
+            .collect())
+    }
+
+    fn read(&self, key: &str) -> Result<StoredFile, String> {
+        match self.entries.lock().unwrap().get(key) {
+            Some(MemoryEntry::File(file)) => Ok(file.clone()),
+            Some(MemoryEntry::Folder) => Err(format!("'{}' is a folder", key)),
+            None => Err(format!("'{}' doesn't exist", key)),
+        }
+    }
+
+    fn write(
+        &mut self,
+        key: &str,
+        data: &[u8],
+        modified: Option<SystemTime>,
+    ) -> Result<(), String> {
+        {
+            let mut entries = self.entries.lock().unwrap();
+            let now = SystemTime::now();
+            let created = match entries.get(key) {
+                Some(MemoryEntry::File(file)) => file.created,
+                Some(MemoryEntry::Folder) => return Err(format!("'{}' is a folder", key)),
+                None => Some(now),
+            };
+            MemoryStore::create_parents(&mut entries, key);
+            entries.insert(
+                key.to_string(),
+                MemoryEntry::File(StoredFile {
+                    data: data.to_vec(),
+                    created,
+                    modified: Some(modified.unwrap_or(now)),
+                }),
+            );
+        }
+        self.notify_watchers();
+        Ok(())
+    }
+
+    fn create_folder(&mut self, key: &str) -> Result<(), String> {
+        {
+            let mut entries = self.entries.lock().unwrap();
+            match entries.get(key) {
+                Some(MemoryEntry::Folder) => return Ok(()),
+                Some(MemoryEntry::File(_)) => return Err(format!("'{}' is a file", key)),
+                None => {}
+            }
+            MemoryStore::create_parents(&mut entries, key);
+            entries.insert(key.to_string(), MemoryEntry::Folder);
+        }
+        self.notify_watchers();
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &str) -> Result<(), String> {
+        self.entries
+            .lock()
+            .unwrap()
+            .retain(|entry_key, _| !is_in(entry_key, key));
+        self.notify_watchers();
+        Ok(())
+    }
+
+    fn rename(&mut self, from: &str, to: &str) -> Result<(), String> {
+        {
+            let mut entries = self.entries.lock().unwrap();
+            let keys: Vec<String> = entries
+                .keys()
+                .filter(|key| is_in(key, from))
+                .cloned()
+                .collect();
+            if keys.is_empty() {
+                return Err(format!("'{}' doesn't exist", from));
+            }
+            MemoryStore::create_parents(&mut entries, to);
+            for key in keys {
+                if let Some(entry) = entries.remove(&key) {
+                    entries.insert(format!("{}{}", to, &key[from.len()..]), entry);
+                }
+            }
+        }
+        self.notify_watchers();
+        Ok(())
+    }
+
+    fn watch(&mut self, model: Arc<Mutex<Model>>) -> Result<Receiver<Model>, String> {
+        let (change_sender, change_receiver) = channel();
+        let (model_sender, model_receiver) = channel();
+        self.watchers.lock().unwrap().push(change_sender);
+        let store = self.clone();
+        // changes are applied on another thread since they can be made while the model is
+        // locked
+        thread::spawn(move || {
+            for () in change_receiver {
+                // read under the lock, so that the model has the writes made before
+                let mut current_model = model.lock().unwrap();
+                let stored_model = match load_model(&store) {
+                    Ok(stored_model) => stored_model,
+                    Err(error) => {
+                        error!(%error, "cannot read store");
+                        continue;
+                    }
+                };
+                if update_model(&mut current_model, stored_model)
+                    && model_sender.send(current_model.clone()).is_err()
+                {
+                    return;
+                }
+            }
+        });
+        Ok(model_receiver)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::note_store::contract;
+
+    #[test]
+    fn contract() {
+        let mut store = MemoryStore::new();
+        contract::check(&mut store.clone(), &mut store);
+    }
+}
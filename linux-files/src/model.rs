@@ -1,15 +1,27 @@
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
 use std::str;
-use std::{fmt};
 use string_join::Join;
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, PartialEq, Default)]
 pub struct Model {
+    /// Notes by path relative to the workspace, with `/` separators.
     pub notes: HashMap<String, Note>,
+    /// Folders of the workspace, including empty ones, with the same kind of paths.
+    pub folders: BTreeSet<String>,
+    /// Binary files of the workspace, like images referenced from notes, with the same kind
+    /// of paths. Their contents are transferred separately.
+    pub attachments: HashMap<String, Attachment>,
+    /// Notes and attachments moved to the trash, by key, with the time they were deleted in
+    /// seconds since the Unix epoch. A copy received from a peer that was modified before is
+    /// stale and doesn't bring the note back.
+    #[serde(default)]
+    pub tombstones: BTreeMap<String, u64>,
 }
 
 impl Model {
+    /// Model with example notes, for a new workspace or a demo. `default` is empty.
     pub fn new() -> Model {
         Model {
             notes: HashMap::from([
@@ -18,6 +30,9 @@ impl Model {
                     Note {
                         title: "Example note 1".to_string(),
                         body: "Some text".to_string(),
+                        tags: BTreeSet::from(["example".to_string()]),
+                        pinned: true,
+                        ..Note::new()
                     },
                 ),
                 (
@@ -25,25 +40,211 @@ impl Model {
                     Note {
                         title: "Example note 2".to_string(),
                         body: "Some text\nwith multiple lines".to_string(),
+                        tags: BTreeSet::from(["example".to_string(), "multiline".to_string()]),
+                        ..Note::new()
+                    },
+                ),
+                (
+                    "projects/note_3.txt".to_string(),
+                    Note {
+                        title: "Example note 3".to_string(),
+                        body: "A note in a folder".to_string(),
+                        ..Note::new()
                     },
                 ),
             ]),
+            folders: BTreeSet::from(["projects".to_string()]),
+            attachments: HashMap::new(),
+            tombstones: BTreeMap::new(),
+        }
+    }
+
+    /// Removes a note or attachment, or a folder with all its contents.
+    pub fn remove(&mut self, key: &str) {
+        self.notes.remove(key);
+        self.attachments.remove(key);
+        if self.folders.remove(key) {
+            let prefix = format!("{}/", key);
+            self.notes.retain(|path, _| !path.starts_with(&prefix));
+            self.folders.retain(|path| !path.starts_with(&prefix));
+            self.attachments
+                .retain(|path, _| !path.starts_with(&prefix));
+        }
+    }
+
+    /// All folders, including the ones only known through the path of their contents.
+    pub fn all_folders(&self) -> BTreeSet<String> {
+        let mut folders = self.folders.clone();
+        for key in self
+            .notes
+            .keys()
+            .chain(self.folders.iter())
+            .chain(self.attachments.keys())
+        {
+            let mut folder = parent_folder(key);
+            while !folder.is_empty() {
+                folders.insert(folder.to_string());
+                folder = parent_folder(folder);
+            }
         }
+        folders
+    }
+
+    /// All tags used by at least one note, in alphabetical order.
+    pub fn tags(&self) -> BTreeSet<String> {
+        self.notes
+            .values()
+            .flat_map(|note| note.tags.iter().cloned())
+            .collect()
     }
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
 pub struct Note {
     pub title: String,
     pub body: String,
+    /// Creation time, in seconds since the Unix epoch (0 if unknown).
+    pub created: u64,
+    /// Last modification time, in seconds since the Unix epoch (0 if unknown).
+    pub modified: u64,
+    pub tags: BTreeSet<String>,
+    pub pinned: bool,
+    /// YAML front matter block the note was read with, delimiters included, kept verbatim so
+    /// that fields used by other tools survive the synchronization.
+    pub front_matter: Option<String>,
+    pub layout: Layout,
 }
 
 impl Note {
     pub fn new() -> Note {
-        return Note {
+        Note {
             title: "".to_string(),
             body: "".to_string(),
-        };
+            created: 0,
+            modified: 0,
+            tags: BTreeSet::new(),
+            pinned: false,
+            front_matter: None,
+            layout: Layout::new(),
+        }
+    }
+
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.contains(tag)
+    }
+
+    /// Whether two notes have the same title, body, tags and pinned flag. Timestamps and front
+    /// matter are left aside since they change when a note is written to another workspace.
+    pub fn has_same_contents(&self, other: &Note) -> bool {
+        self.title == other.title
+            && self.body == other.body
+            && self.tags == other.tags
+            && self.pinned == other.pinned
+    }
+}
+
+impl Default for Note {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Version of a note kept in its history.
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct Revision {
+    pub key: String,
+    /// Name of the instance where the change was made.
+    pub author: String,
+    /// When the change was recorded, in seconds since the Unix epoch.
+    pub time: u64,
+    /// The note after the change, `None` when it was deleted.
+    pub note: Option<Note>,
+}
+
+impl Revision {
+    /// Whether the note has the same contents as in this revision.
+    pub fn has_note(&self, note: Option<&Note>) -> bool {
+        match (&self.note, note) {
+            (Some(revision_note), Some(note)) => revision_note.has_same_contents(note),
+            (None, None) => true,
+            _ => false,
+        }
+    }
+
+    /// Title and body of the note, empty when it was deleted.
+    pub fn text(&self) -> String {
+        self.note
+            .as_ref()
+            .map(|note| note.to_string())
+            .unwrap_or_default()
+    }
+}
+
+/// Line of the difference between two texts.
+#[derive(Clone, PartialEq, Debug)]
+pub enum DiffLine {
+    Same(String),
+    Added(String),
+    Removed(String),
+}
+
+/// Line by line difference between two texts.
+pub fn diff_lines(old: &str, new: &str) -> Vec<DiffLine> {
+    similar::TextDiff::from_lines(old, new)
+        .iter_all_changes()
+        .map(|change| {
+            let line = change.value().trim_end_matches('\n').to_string();
+            match change.tag() {
+                similar::ChangeTag::Equal => DiffLine::Same(line),
+                similar::ChangeTag::Insert => DiffLine::Added(line),
+                similar::ChangeTag::Delete => DiffLine::Removed(line),
+            }
+        })
+        .collect()
+}
+
+/// Binary file, addressed by the hash of its contents.
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct Attachment {
+    /// Hexadecimal SHA-256 of the contents.
+    pub hash: String,
+    pub size: u64,
+    pub media_type: String,
+}
+
+/// Folder containing a note or folder, the empty string for the root of the workspace.
+pub fn parent_folder(key: &str) -> &str {
+    key.rsplit_once('/').map_or("", |(parent, _)| parent)
+}
+
+/// How a note is laid out in its file, besides its title and body, so that the file can be
+/// written back exactly as it was read.
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct Layout {
+    /// The file starts with a UTF-8 byte order mark.
+    pub byte_order_mark: bool,
+    /// The first line of the file (after the front matter) is the title.
+    pub title_line: bool,
+    /// Line ending of the title line, also used for the lines we generate.
+    pub line_ending: String,
+    /// Blank lines between the title and the body.
+    pub separator: String,
+}
+
+impl Layout {
+    pub fn new() -> Layout {
+        Layout {
+            byte_order_mark: false,
+            title_line: true,
+            line_ending: "\n".to_string(),
+            separator: "\n".to_string(),
+        }
+    }
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -1,74 +1,268 @@
+use std::collections::HashMap;
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use std::{str, thread};
 
-use serde::Deserialize;
-use websocket::sync::Server;
-use websocket::ClientBuilder;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, info_span, trace, warn};
+use websocket::sync::{Client, Server, Writer};
+use websocket::{ClientBuilder, OwnedMessage, WebSocketError};
 
-use crate::model::Model;
+use crate::chunks::Chunk;
+use crate::pairing::{PairingMessage, SealedChunk, SealedMessage};
+use crate::subscription::Subscription;
+use crate::transfer::Manifest;
 
-pub struct Connection {
-    sender: Sender<Model>,
-    receiver: Receiver<Model>,
+/// Messages exchanged between instances.
+#[derive(Serialize, Deserialize, Clone)]
+pub enum Message {
+    /// First message sent to a peer.
+    Hello(Hello),
+    /// Chunk of the contents of notes or attachments, sent before the manifest referencing it.
+    /// Chunks are sent in binary frames, the other messages in JSON.
+    #[serde(skip)]
+    Chunk(Chunk),
+    Manifest(Box<Manifest>),
+    /// Keys of the changes of the last model received that were undone, the peer not being
+    /// allowed to make them.
+    Rejected(Vec<String>),
+    /// Proof that the instance has the key it shares with a paired peer, for the nonces of both
+    /// hellos.
+    Proof(String),
+    Pairing(PairingMessage),
+    /// Message sent to a paired peer once both proofs are exchanged, which only accepts those.
+    Sealed(SealedMessage),
+    #[serde(skip)]
+    SealedChunk(SealedChunk),
 }
 
-impl Connection {
-    // TODO: take ToSocketAddrs instead of str
-    pub fn new(bind_address: &str, connect_address: &str) -> Result<Connection, String> {
-        // bind address
-        let (server_sender, server_receiver) = channel();
-        let mut server = Server::bind(bind_address).map_err(|err| err.to_string())?;
-        thread::spawn(move || {
-            // wait for a client
-            let mut client = server.accept().unwrap().accept().unwrap();
-            // and listen for messages coming from the server channel
-            for value in server_receiver {
-                // send the messages to the client
-                serde_json::to_writer(client.writer_mut(), &value).unwrap();
+/// First byte of the binary frame of a chunk, followed by its data.
+const CHUNK_FRAME: u8 = 0;
+/// First byte of the binary frame of a sealed chunk, followed by its MAC and its data.
+const SEALED_CHUNK_FRAME: u8 = 1;
+/// Size of the MAC of a sealed chunk.
+const MAC_SIZE: usize = 32;
+
+impl Message {
+    /// Name of the kind of message, for the logs, which don't show the contents of notes.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Message::Hello(_) => "hello",
+            Message::Chunk(_) => "chunk",
+            Message::Manifest(_) => "manifest",
+            Message::Rejected(_) => "rejected",
+            Message::Proof(_) => "proof",
+            Message::Pairing(_) => "pairing",
+            Message::Sealed(_) => "sealed",
+            Message::SealedChunk(_) => "sealed chunk",
+        }
+    }
+
+    /// Frame the message is sent in.
+    pub fn to_frame(&self) -> Result<OwnedMessage, String> {
+        let (kind, mac, data) = match self {
+            Message::Chunk(chunk) => (CHUNK_FRAME, &[][..], &chunk.data),
+            Message::SealedChunk(sealed_chunk) => (
+                SEALED_CHUNK_FRAME,
+                &sealed_chunk.mac[..],
+                &sealed_chunk.data,
+            ),
+            _ => {
+                return serde_json::to_string(self)
+                    .map(OwnedMessage::Text)
+                    .map_err(|error| error.to_string())
             }
-        });
-
-        let (client_sender, client_receiver) = channel();
-        let connect_address_owned = connect_address.to_owned();
-        thread::spawn(move || {
-            // repeatedly try to connect the server
-            let mut client = loop {
-                match ClientBuilder::new(&connect_address_owned)
-                    .unwrap()
-                    .connect_insecure()
-                {
-                    Ok(client) => break client,
-                    Err(_) => thread::sleep(Duration::from_secs(1)),
-                }
-            };
-            // and listen for messages coming from the client
-
-            loop {
-                let mut de = serde_json::Deserializer::from_reader(client.reader_mut());
-                match Model::deserialize(&mut de) {
-                    // send the messages through the client channel
-                    Ok(model) => {
-                        client_sender.send(model).unwrap();
-                    }
-                    Err(error) => eprintln!("cannot read model from stream -> {}", error),
-                }
+        };
+        let mut frame = Vec::with_capacity(1 + mac.len() + data.len());
+        frame.push(kind);
+        frame.extend_from_slice(mac);
+        frame.extend_from_slice(data);
+        Ok(OwnedMessage::Binary(frame))
+    }
+
+    /// Message of a binary frame.
+    fn from_binary_frame(mut frame: Vec<u8>) -> Result<Message, String> {
+        match frame.first() {
+            Some(&CHUNK_FRAME) => Ok(Message::Chunk(Chunk::new(frame.split_off(1)))),
+            Some(&SEALED_CHUNK_FRAME) if frame.len() > MAC_SIZE => {
+                let data = frame.split_off(1 + MAC_SIZE);
+                Ok(Message::SealedChunk(SealedChunk {
+                    data,
+                    mac: frame.split_off(1),
+                }))
             }
-        });
+            _ => Err(String::from("invalid binary frame")),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Hello {
+    /// Name of the instance, the author of the changes it sends.
+    pub name: String,
+    /// Notes the instance wants, the peer only sends those.
+    #[serde(default)]
+    pub subscription: Subscription,
+    /// Unique id of the instance, a peer reached by several connections is only synced with
+    /// through one of them.
+    #[serde(default)]
+    pub instance_id: String,
+    /// Random value the key of the session with a paired peer is derived from.
+    #[serde(default)]
+    pub nonce: String,
+}
+
+/// Identifies a connection of the network.
+pub type ConnectionId = usize;
+
+pub enum Event {
+    /// A connection was opened, by this instance when `outbound` is true.
+    Connected(ConnectionId, bool),
+    Message(ConnectionId, Message),
+    Disconnected(ConnectionId),
+}
 
-        Ok(Connection {
-            sender: server_sender,
-            receiver: client_receiver,
-        })
+/// Connections to any number of peers: the ones connecting to the listen address, if any, and
+/// the peers this instance connects to, which are connected again when the connection is lost.
+pub struct Network {
+    writers: Arc<Mutex<HashMap<ConnectionId, Writer<TcpStream>>>>,
+    receiver: Receiver<Event>,
+}
+
+impl Network {
+    // TODO: take ToSocketAddrs instead of str
+    pub fn new(listen_address: Option<&str>, peer_addresses: &[String]) -> Result<Network, String> {
+        let writers = Arc::new(Mutex::new(HashMap::new()));
+        let next_id = Arc::new(AtomicUsize::new(0));
+        let (sender, receiver) = channel();
+
+        if let Some(listen_address) = listen_address {
+            let mut server = Server::bind(listen_address)
+                .map_err(|error| format!("cannot listen on '{}' -> {}", listen_address, error))?;
+            let server_writers = writers.clone();
+            let server_next_id = next_id.clone();
+            let server_sender = sender.clone();
+            thread::spawn(move || loop {
+                // wait for a peer
+                let client = match server.accept() {
+                    Ok(upgrade) => match upgrade.accept() {
+                        Ok(client) => client,
+                        Err((_, error)) => {
+                            warn!(%error, "could not accept connection");
+                            continue;
+                        }
+                    },
+                    Err(error) => {
+                        warn!(error = %error.error, "could not accept connection");
+                        continue;
+                    }
+                };
+                let id = server_next_id.fetch_add(1, Ordering::SeqCst);
+                let writers = server_writers.clone();
+                let sender = server_sender.clone();
+                thread::spawn(move || run_connection(id, client, false, &writers, &sender));
+            });
+        }
+
+        for address in peer_addresses {
+            let address = address.clone();
+            let writers = writers.clone();
+            let next_id = next_id.clone();
+            let sender = sender.clone();
+            thread::spawn(move || loop {
+                // repeatedly try to connect the peer
+                let client = loop {
+                    match ClientBuilder::new(&address).map(|mut builder| builder.connect_insecure())
+                    {
+                        Ok(Ok(client)) => break client,
+                        Ok(Err(error)) => {
+                            trace!(%address, %error, "could not connect");
+                            thread::sleep(Duration::from_secs(1))
+                        }
+                        Err(error) => {
+                            error!(%address, %error, "invalid peer address");
+                            return;
+                        }
+                    }
+                };
+                let id = next_id.fetch_add(1, Ordering::SeqCst);
+                run_connection(id, client, true, &writers, &sender);
+                thread::sleep(Duration::from_secs(1));
+            });
+        }
+
+        Ok(Network { writers, receiver })
     }
 
-    pub fn send(self: &Self, value: &Model) -> Result<(), String> {
-        self.sender
-            .send(value.clone())
-            .map_err(|err| err.to_string())
+    pub fn send(&self, id: ConnectionId, value: &Message) -> Result<(), String> {
+        trace!(connection = id, kind = value.kind(), "send message");
+        let mut writers = self.writers.lock().unwrap();
+        match writers.get_mut(&id) {
+            Some(writer) => writer
+                .send_message(&value.to_frame()?)
+                .map_err(|err| err.to_string()),
+            None => Err(format!("connection {} is closed", id)),
+        }
     }
 
-    pub fn as_receiver(self: &Self) -> &Receiver<Model> {
+    pub fn as_receiver(&self) -> &Receiver<Event> {
         &self.receiver
     }
 }
+
+/// Reads the messages of a connection until it is closed.
+fn run_connection(
+    id: ConnectionId,
+    client: Client<TcpStream>,
+    outbound: bool,
+    writers: &Mutex<HashMap<ConnectionId, Writer<TcpStream>>>,
+    sender: &Sender<Event>,
+) {
+    let address = client
+        .peer_addr()
+        .map(|address| address.to_string())
+        .unwrap_or_default();
+    let _span = info_span!("connection", id, %address, outbound).entered();
+    let (mut reader, writer) = match client.split() {
+        Ok(halves) => halves,
+        Err(error) => {
+            warn!(%error, "could not open connection");
+            return;
+        }
+    };
+    debug!("connected");
+    writers.lock().unwrap().insert(id, writer);
+    if sender.send(Event::Connected(id, outbound)).is_err() {
+        return;
+    }
+    loop {
+        let message = match reader.recv_message() {
+            Ok(OwnedMessage::Text(text)) => {
+                serde_json::from_str(&text).map_err(|error| error.to_string())
+            }
+            Ok(OwnedMessage::Binary(frame)) => Message::from_binary_frame(frame),
+            Ok(OwnedMessage::Close(_)) | Err(WebSocketError::NoDataAvailable) => break,
+            Ok(OwnedMessage::Ping(_)) | Ok(OwnedMessage::Pong(_)) => continue,
+            Err(error) => Err(error.to_string()),
+        };
+        match message {
+            // send the messages through the channel
+            Ok(message) => {
+                trace!(kind = message.kind(), "received message");
+                if sender.send(Event::Message(id, message)).is_err() {
+                    return;
+                }
+            }
+            Err(error) => {
+                warn!(%error, "cannot read message from stream");
+                break;
+            }
+        }
+    }
+    writers.lock().unwrap().remove(&id);
+    debug!("disconnected");
+    let _ = sender.send(Event::Disconnected(id));
+}
@@ -0,0 +1,500 @@
+use serde_yaml::{Mapping, Value};
+use std::collections::BTreeSet;
+
+use crate::model::Note;
+
+const BYTE_ORDER_MARK: &str = "\u{feff}";
+const FRONT_MATTER_DELIMITER: &str = "---";
+
+const CREATED_KEY: &str = "created";
+const MODIFIED_KEY: &str = "modified";
+const TAGS_KEY: &str = "tags";
+const PINNED_KEY: &str = "pinned";
+
+/// Parses the contents of a note file: an optional byte order mark, an optional front matter
+/// block delimited by `---` lines, then the title on the first line, blank lines, and the
+/// body.
+///
+/// Everything that isn't part of the title or the body is recorded in the note's layout, so
+/// that `serialize(&parse(text)?)` gives back `text` exactly. The front matter is kept as is
+/// in the note so that keys unknown to us survive the round-trip, only `created`,
+/// `modified`, `tags` and `pinned` are read from it.
+pub fn parse(text: &str) -> Result<Note, String> {
+    let mut note = Note::new();
+    let text = match text.strip_prefix(BYTE_ORDER_MARK) {
+        Some(text) => {
+            note.layout.byte_order_mark = true;
+            text
+        }
+        None => text,
+    };
+    let (front_matter, text) = split_front_matter(text);
+    if let Some(block) = front_matter {
+        let (_, yaml, _) = split_delimiters(block);
+        read_fields(yaml, &mut note);
+        note.front_matter = Some(block.to_string());
+    }
+    match text.split_once('\n') {
+        Some((title_line, rest)) => {
+            let (title, line_ending) = match title_line.strip_suffix('\r') {
+                Some(title) => (title, "\r\n"),
+                None => (title_line, "\n"),
+            };
+            let body_start = rest.len() - rest.trim_start_matches(line_ending).len();
+            note.title = title.to_string();
+            note.layout.line_ending = line_ending.to_string();
+            note.layout.separator = rest[..body_start].to_string();
+            note.body = rest[body_start..].to_string();
+        }
+        None => {
+            // a single line is the body of a note without title
+            note.layout.title_line = false;
+            note.body = text.to_string();
+        }
+    }
+    Ok(note)
+}
+
+/// Serializes a note to the contents of a note file, in the layout the note was read with.
+///
+/// The front matter the note was read with is written back byte-for-byte, except for the
+/// entries of the fields that changed. Notes read without front matter only get one when
+/// they have tags or are pinned, the creation time alone is left to the file.
+pub fn serialize(note: &Note) -> Result<String, String> {
+    let line_ending = note.layout.line_ending.as_str();
+    let mut text = String::new();
+    if note.layout.byte_order_mark {
+        text.push_str(BYTE_ORDER_MARK);
+    }
+    let front_matter = match &note.front_matter {
+        Some(block) => Some(update_front_matter(block, note)?),
+        None if !note.tags.is_empty() || note.pinned => Some(create_front_matter(note)?),
+        None => None,
+    };
+    let title_line = note.layout.title_line || !note.title.is_empty();
+    if let Some(front_matter) = front_matter {
+        text.push_str(&front_matter);
+        if (title_line || !note.body.is_empty()) && !front_matter.ends_with('\n') {
+            // the front matter was closed at the end of the file
+            text.push_str(line_ending);
+        }
+    }
+    if title_line {
+        text.push_str(&note.title);
+        text.push_str(line_ending);
+        if note.layout.title_line {
+            text.push_str(&note.layout.separator);
+        } else {
+            text.push_str(line_ending);
+        }
+    }
+    text.push_str(&note.body);
+    Ok(text)
+}
+
+/// Splits the front matter block, delimiters included, from the rest of the note. A text
+/// that doesn't start with a delimiter line, or where the front matter is never closed, has
+/// no front matter.
+fn split_front_matter(text: &str) -> (Option<&str>, &str) {
+    let mut lines = line_offsets(text);
+    match lines.next() {
+        Some((_, line)) if is_delimiter(line) && line.ends_with('\n') => {}
+        _ => return (None, text),
+    };
+    for (offset, line) in lines {
+        if is_delimiter(line) {
+            let (block, rest) = text.split_at(offset + line.len());
+            return (Some(block), rest);
+        }
+    }
+    (None, text)
+}
+
+fn is_delimiter(line: &str) -> bool {
+    line.trim_end_matches(['\r', '\n']) == FRONT_MATTER_DELIMITER
+}
+
+/// Splits a front matter block into its opening delimiter line, its YAML and its closing
+/// delimiter line.
+fn split_delimiters(block: &str) -> (&str, &str, &str) {
+    let yaml_start = block.find('\n').map_or(block.len(), |index| index + 1);
+    let closing_start = block
+        .rfind(FRONT_MATTER_DELIMITER)
+        .filter(|index| *index >= yaml_start)
+        .unwrap_or(block.len());
+    (
+        &block[..yaml_start],
+        &block[yaml_start..closing_start],
+        &block[closing_start..],
+    )
+}
+
+/// Reads the known fields of a front matter into a note, fields with an unexpected type are
+/// ignored.
+fn read_fields(yaml: &str, note: &mut Note) {
+    let mapping = match serde_yaml::from_str::<Mapping>(yaml) {
+        Ok(mapping) => mapping,
+        Err(_) => return,
+    };
+    if let Some(created) = mapping.get(CREATED_KEY).and_then(Value::as_u64) {
+        note.created = created;
+    }
+    if let Some(modified) = mapping.get(MODIFIED_KEY).and_then(Value::as_u64) {
+        note.modified = modified;
+    }
+    if let Some(tags) = mapping.get(TAGS_KEY).and_then(tags_from_value) {
+        note.tags = tags;
+    }
+    if let Some(pinned) = mapping.get(PINNED_KEY).and_then(Value::as_bool) {
+        note.pinned = pinned;
+    }
+}
+
+/// Tags are either a list of strings or, like Obsidian allows it, a single string of tags
+/// separated by commas or spaces.
+fn tags_from_value(value: &Value) -> Option<BTreeSet<String>> {
+    match value {
+        Value::Sequence(values) => values
+            .iter()
+            .map(|value| value.as_str().map(|tag| tag.to_string()))
+            .collect(),
+        Value::String(tags) => Some(
+            tags.split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|tag| !tag.is_empty())
+                .map(|tag| tag.to_string())
+                .collect(),
+        ),
+        Value::Null => Some(BTreeSet::new()),
+        _ => None,
+    }
+}
+
+/// Value of a known field in a note, `None` for the default value.
+fn field_value(key: &str, note: &Note) -> Option<Value> {
+    match key {
+        CREATED_KEY => Some(note.created)
+            .filter(|created| *created != 0)
+            .map(Value::from),
+        MODIFIED_KEY => Some(note.modified)
+            .filter(|modified| *modified != 0)
+            .map(Value::from),
+        TAGS_KEY => Some(&note.tags)
+            .filter(|tags| !tags.is_empty())
+            .map(|tags| {
+                Value::Sequence(tags.iter().map(|tag| Value::from(tag.as_str())).collect())
+            }),
+        PINNED_KEY => Some(note.pinned).filter(|pinned| *pinned).map(Value::from),
+        _ => None,
+    }
+}
+
+/// Renders a single front matter entry with the given line ending.
+fn render_field(key: &str, value: Value, line_ending: &str) -> Result<String, String> {
+    let mut mapping = Mapping::new();
+    mapping.insert(Value::from(key), value);
+    serde_yaml::to_string(&mapping)
+        .map(|yaml| yaml.replace('\n', line_ending))
+        .map_err(|error| format!("cannot serialize front matter field '{}' -> {}", key, error))
+}
+
+fn create_front_matter(note: &Note) -> Result<String, String> {
+    let line_ending = note.layout.line_ending.as_str();
+    let mut yaml = String::new();
+    // the modification time of the file keeps `modified`
+    for key in [CREATED_KEY, TAGS_KEY, PINNED_KEY] {
+        if let Some(value) = field_value(key, note) {
+            yaml.push_str(&render_field(key, value, line_ending)?);
+        }
+    }
+    Ok(format!(
+        "{}{}{}{}{}",
+        FRONT_MATTER_DELIMITER, line_ending, yaml, FRONT_MATTER_DELIMITER, line_ending
+    ))
+}
+
+/// Rewrites the entries of the known fields whose value differs from the note, removes the
+/// ones that went back to their default value and appends the creation time, tags and pinned
+/// state if they are missing. Everything else is kept byte-for-byte.
+///
+/// A missing `created` doesn't make the block change on its own, the creation time of a note
+/// without one comes from its file. It is added along other changes since a file replaced by
+/// the sync engine gets a new birth time. `modified` is never added, the modification time of
+/// the file keeps it.
+fn update_front_matter(block: &str, note: &Note) -> Result<String, String> {
+    let (opening, yaml, closing) = split_delimiters(block);
+    let mut original = Note::new();
+    read_fields(yaml, &mut original);
+    if [CREATED_KEY, MODIFIED_KEY, TAGS_KEY, PINNED_KEY]
+        .iter()
+        .filter(|key| **key != CREATED_KEY || original.created != 0)
+        .all(|key| field_value(key, &original) == field_value(key, note))
+    {
+        return Ok(block.to_string());
+    }
+
+    let line_ending = note.layout.line_ending.as_str();
+    let mut updated = opening.to_string();
+    let mut seen_keys = BTreeSet::new();
+    for entry in split_entries(yaml) {
+        let key = match entry_key(entry) {
+            Some(key) if is_known_key(key) => key,
+            _ => {
+                updated.push_str(entry);
+                continue;
+            }
+        };
+        seen_keys.insert(key);
+        let mut parsed = Note::new();
+        read_fields(entry, &mut parsed);
+        let valid = serde_yaml::from_str::<Mapping>(entry).is_ok();
+        let wanted = field_value(key, note);
+        if valid && field_value(key, &parsed) == wanted {
+            updated.push_str(entry);
+        } else if !valid && wanted.is_none() {
+            // we can't tell what this entry holds, leave it to the user
+            updated.push_str(entry);
+        } else {
+            let (_, trailing_comments) = split_trailing_comments(entry);
+            if let Some(value) = wanted {
+                updated.push_str(&render_field(key, value, line_ending)?);
+            }
+            updated.push_str(trailing_comments);
+        }
+    }
+    if !updated.ends_with('\n') {
+        updated.push_str(line_ending);
+    }
+    for key in [CREATED_KEY, TAGS_KEY, PINNED_KEY] {
+        if !seen_keys.contains(key) {
+            if let Some(value) = field_value(key, note) {
+                updated.push_str(&render_field(key, value, line_ending)?);
+            }
+        }
+    }
+    updated.push_str(closing);
+    Ok(updated)
+}
+
+fn is_known_key(key: &str) -> bool {
+    [CREATED_KEY, MODIFIED_KEY, TAGS_KEY, PINNED_KEY].contains(&key)
+}
+
+/// Splits a front matter into its top-level entries, each entry keeping its continuation
+/// lines (indented values, list items, comments and blank lines). Lines before the first
+/// entry form an entry without key.
+fn split_entries(yaml: &str) -> Vec<&str> {
+    let mut entries = Vec::new();
+    let mut start = 0;
+    for (offset, line) in line_offsets(yaml) {
+        if offset != 0 && starts_entry(line) {
+            entries.push(&yaml[start..offset]);
+            start = offset;
+        }
+    }
+    if start < yaml.len() {
+        entries.push(&yaml[start..]);
+    }
+    entries
+}
+
+fn line_offsets(text: &str) -> impl Iterator<Item = (usize, &str)> {
+    text.split_inclusive('\n').scan(0, |offset, line| {
+        let line_offset = *offset;
+        *offset += line.len();
+        Some((line_offset, line))
+    })
+}
+
+fn starts_entry(line: &str) -> bool {
+    !line.starts_with(|c: char| c.is_whitespace() || c == '-' || c == '#') && line.contains(':')
+}
+
+/// Splits the comments and blank lines at the end of an entry, they are about what follows
+/// rather than about the entry itself.
+fn split_trailing_comments(entry: &str) -> (&str, &str) {
+    let mut split = entry.len();
+    for (offset, line) in line_offsets(entry).collect::<Vec<_>>().into_iter().rev() {
+        let trimmed = line.trim();
+        if offset == 0 || !(trimmed.is_empty() || trimmed.starts_with('#')) {
+            break;
+        }
+        split = offset;
+    }
+    entry.split_at(split)
+}
+
+fn entry_key(entry: &str) -> Option<&str> {
+    let first_line = entry.lines().next()?;
+    if !starts_entry(first_line) {
+        return None;
+    }
+    first_line.split(':').next().map(|key| key.trim())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    const FRONT_MATTER: &str = "---\n\
+        # written by hand\n\
+        title:   \"Kept as is\"   # aligned\n\
+        aliases: [one, 'two']\n\
+        tags:\n\
+        \x20 - work\n\
+        hugo:\n\
+        \x20 draft: true\n\
+        \x20 weight: 10\n\
+        \n\
+        date: 2024-01-31T10:00:00+01:00\n\
+        ---\n";
+
+    fn updated(text: &str, change: impl FnOnce(&mut Note)) -> String {
+        let mut note = parse(text).unwrap();
+        change(&mut note);
+        serialize(&note).unwrap()
+    }
+
+    #[test]
+    fn update_keeps_unknown_keys() {
+        let text = format!("{}Title\n\nBody\n", FRONT_MATTER);
+        // the changed field is rendered again, the others are untouched
+        let expected = text.replace("tags:\n  - work\n", "tags:\n- home\n- work\n");
+        assert_eq!(
+            updated(&text, |note| {
+                note.tags.insert(String::from("home"));
+            }),
+            expected
+        );
+    }
+
+    #[test]
+    fn update_appends_missing_fields_after_unknown_keys() {
+        let text = format!("{}Title\n\nBody\n", FRONT_MATTER);
+        let expected = text.replace(
+            "+01:00\n---\n",
+            "+01:00\ncreated: 1700000000\npinned: true\n---\n",
+        );
+        assert_eq!(
+            updated(&text, |note| {
+                note.created = 1_700_000_000;
+                note.pinned = true;
+            }),
+            expected
+        );
+    }
+
+    #[test]
+    fn creation_time_alone_does_not_add_front_matter() {
+        let set_created = |note: &mut Note| note.created = 1_700_000_000;
+        assert_eq!(updated("Title\n\nBody", set_created), "Title\n\nBody");
+        let text = "---\nsource: web\n---\nTitle\n";
+        assert_eq!(updated(text, set_created), text);
+        assert_eq!(
+            updated("Title\n", |note| {
+                note.created = 1_700_000_000;
+                note.pinned = true;
+            }),
+            "---\ncreated: 1700000000\npinned: true\n---\nTitle\n"
+        );
+    }
+
+    #[test]
+    fn update_removes_only_cleared_fields() {
+        let text = format!("{}Title\n\nBody\n", FRONT_MATTER);
+        let expected = text.replace("tags:\n  - work\n", "");
+        assert_eq!(updated(&text, |note| note.tags.clear()), expected);
+    }
+
+    #[test]
+    fn update_keeps_trailing_comments_of_a_rewritten_field() {
+        let text = "---\npinned: false\n# about the next key\nsource: web\n---\nTitle\n";
+        assert_eq!(
+            updated(text, |note| note.pinned = true),
+            "---\npinned: true\n# about the next key\nsource: web\n---\nTitle\n"
+        );
+    }
+
+    #[test]
+    fn update_keeps_line_endings_of_unknown_keys() {
+        let text = "---\r\nsource: web\r\nlinks:\r\n  - a\r\n---\r\nTitle\r\n\r\nBody";
+        assert_eq!(
+            updated(text, |note| note.pinned = true),
+            "---\r\nsource: web\r\nlinks:\r\n  - a\r\npinned: true\r\n---\r\nTitle\r\n\r\nBody"
+        );
+    }
+
+    #[test]
+    fn update_leaves_unreadable_entries_to_the_user() {
+        let text = "---\ntags: [unclosed\nsource: web\n---\nTitle\n";
+        assert_eq!(
+            updated(text, |note| note.pinned = true),
+            "---\ntags: [unclosed\nsource: web\npinned: true\n---\nTitle\n"
+        );
+    }
+
+    /// Pieces note files are made of, so that generated files often have a front matter, a
+    /// title and odd line endings.
+    fn note_file_text() -> impl Strategy<Value = String> {
+        let piece = prop_oneof![
+            Just(BYTE_ORDER_MARK),
+            Just("---"),
+            Just("\n"),
+            Just("\r\n"),
+            Just("\r"),
+            Just(" "),
+            Just("#"),
+            Just("- "),
+            Just("created: 1700000000"),
+            Just("tags: [a, b]"),
+            Just("tags: a, b"),
+            Just("pinned: true"),
+            Just("source: web"),
+            Just("Title"),
+            Just("é"),
+        ];
+        proptest::collection::vec(piece, 0..24).prop_map(|pieces| pieces.concat())
+    }
+
+    fn assert_round_trip(text: &str) {
+        assert_eq!(serialize(&parse(text).unwrap()).unwrap(), text);
+    }
+
+    #[test]
+    fn round_trip_edge_cases() {
+        for text in [
+            "",
+            "\n",
+            "\r",
+            "\r\n",
+            BYTE_ORDER_MARK,
+            "Title",
+            "Title\n",
+            "Title\r\n\r\n\r\nBody\r\n",
+            "Title\rBody",
+            "Title\n\rBody\n\n",
+            "---\n---\n",
+            "---\n---",
+            "---\ntags: [a]\n---",
+            "---\ntags: [a]\n---\n",
+            "---\ntags: [a]\n---\nTitle\n\nBody",
+            "---\r\ntags: [a]\r\n---\r\nTitle\n\nBody\r\n",
+            "---\ntags: [a]\n",
+            "\u{feff}---\npinned: true\n---\n\n\n",
+        ] {
+            assert_round_trip(text);
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn round_trip_generated_files(text in note_file_text()) {
+            assert_round_trip(&text);
+        }
+
+        #[test]
+        fn round_trip_any_text(text in any::<String>()) {
+            assert_round_trip(&text);
+        }
+    }
+}
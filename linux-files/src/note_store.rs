@@ -0,0 +1,280 @@
+use std::path::Path;
+use std::str;
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+use crate::model::{Model, Note};
+use crate::{attachments, note_file};
+
+/// File or folder of a store.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StoreEntry {
+    pub key: String,
+    pub is_folder: bool,
+}
+
+/// Contents of a file of a store, with its timestamps when the store has them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StoredFile {
+    pub data: Vec<u8>,
+    pub created: Option<SystemTime>,
+    pub modified: Option<SystemTime>,
+}
+
+/// Where the files of a workspace are kept. Keys are paths relative to the workspace with `/`
+/// separators, like the keys of the model.
+pub trait NoteStore: Send {
+    /// Every file and folder of the store, except the ignored ones.
+    fn list(&self) -> Result<Vec<StoreEntry>, String>;
+
+    fn read(&self, key: &str) -> Result<StoredFile, String>;
+
+    /// Replaces the contents of a file, or creates it with its parent folders. Its
+    /// modification time is set to `modified` when there is one.
+    fn write(&mut self, key: &str, data: &[u8], modified: Option<SystemTime>)
+        -> Result<(), String>;
+
+    /// Creates a folder with its parent folders, if it doesn't exist yet.
+    fn create_folder(&mut self, key: &str) -> Result<(), String>;
+
+    /// Deletes a file, or a folder with everything in it. Missing keys are ignored.
+    fn delete(&mut self, key: &str) -> Result<(), String>;
+
+    /// Moves a file, or a folder with everything in it.
+    fn rename(&mut self, from: &str, to: &str) -> Result<(), String>;
+
+    /// Sends the model each time a change made outside of the sync engine updates it.
+    ///
+    /// The model is shared with the code writing to the store: writes made while holding the
+    /// lock are already in the model when they are noticed, so they don't produce updates.
+    fn watch(&mut self, model: Arc<Mutex<Model>>) -> Result<Receiver<Model>, String>;
+}
+
+/// Reads every file of a store into a model.
+pub fn load_model(store: &dyn NoteStore) -> Result<Model, String> {
+    let mut model = Model::default();
+    for entry in store.list()? {
+        if entry.is_folder {
+            model.folders.insert(entry.key);
+        } else if let Err(error) = read_entry(&mut model, store, &entry.key) {
+            warn!(key = %entry.key, %error, "could not read");
+        }
+    }
+    Ok(model)
+}
+
+/// Replaces `model` with `stored_model`, the model read again from its store, and returns
+/// whether it changed. Tombstones are not stored, they are kept from `model`.
+pub fn update_model(model: &mut Model, mut stored_model: Model) -> bool {
+    stored_model.tombstones = model.tombstones.clone();
+    if stored_model == *model {
+        return false;
+    }
+    *model = stored_model;
+    true
+}
+
+/// Copies every file and folder of a store into another, with their modification times.
+pub fn copy(from: &dyn NoteStore, to: &mut dyn NoteStore) -> Result<(), String> {
+    for entry in from.list()? {
+        if entry.is_folder {
+            to.create_folder(&entry.key)?;
+        } else {
+            let file = from.read(&entry.key)?;
+            to.write(&entry.key, &file.data, file.modified)?;
+        }
+    }
+    Ok(())
+}
+
+/// Reads a file of a store into the model, as a note or as an attachment.
+pub fn read_entry(model: &mut Model, store: &dyn NoteStore, key: &str) -> Result<(), String> {
+    let file = store.read(key)?;
+    insert_file(model, key, &file)
+}
+
+/// Inserts a file into the model, as a note or as an attachment.
+pub fn insert_file(model: &mut Model, key: &str, file: &StoredFile) -> Result<(), String> {
+    let path = Path::new(key);
+    if attachments::is_attachment(path, &file.data) {
+        model.notes.remove(key);
+        model
+            .attachments
+            .insert(key.to_string(), attachments::attachment(path, &file.data));
+    } else {
+        let note = parse_note(key, file)?;
+        model.attachments.remove(key);
+        model.notes.insert(key.to_string(), note);
+    }
+    Ok(())
+}
+
+fn parse_note(key: &str, file: &StoredFile) -> Result<Note, String> {
+    match str::from_utf8(&file.data) {
+        Ok(text) => {
+            let mut note = note_file::parse(text)
+                .map_err(|error| format!("could not parse '{}' -> {}", key, error))?;
+            fill_timestamps(&mut note, file);
+            Ok(note)
+        }
+        Err(error) => Err(format!("could not read '{}' -> {}", key, error)),
+    }
+}
+
+/// Completes the timestamps of a note with the ones of its file. Timestamps from the front
+/// matter are kept as is, tools like Hugo expect them to be managed by the user.
+fn fill_timestamps(note: &mut Note, file: &StoredFile) {
+    if note.modified == 0 {
+        if let Some(modified) = file.modified {
+            note.modified = seconds_since_epoch(modified);
+        }
+    }
+    if note.created == 0 {
+        note.created = file
+            .created
+            .map(seconds_since_epoch)
+            .unwrap_or(note.modified);
+    }
+}
+
+pub fn seconds_since_epoch(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+/// Whether a key is a relative path that stays in the store.
+pub fn is_valid_key(key: &str) -> bool {
+    !key.is_empty()
+        && key
+            .split('/')
+            .all(|part| !part.is_empty() && part != "." && part != "..")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn plain_notes_are_written_back_as_they_were_read() {
+        let text = "Title\n\nBody\n";
+        let file = StoredFile {
+            data: text.as_bytes().to_vec(),
+            created: Some(UNIX_EPOCH + Duration::from_secs(1_700_000_000)),
+            modified: Some(UNIX_EPOCH + Duration::from_secs(1_700_000_100)),
+        };
+        let mut model = Model::default();
+        insert_file(&mut model, "note.txt", &file).unwrap();
+        let note = &model.notes["note.txt"];
+        assert_eq!(note.created, 1_700_000_000);
+        assert_eq!(note_file::serialize(note).unwrap(), text);
+    }
+}
+
+/// What every store must do, checked on each of them.
+#[cfg(test)]
+pub mod contract {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    /// Checks a store, starting empty. `outside` is another handle on the same contents, the
+    /// changes made through it are made outside of the sync engine.
+    pub fn check(store: &mut dyn NoteStore, outside: &mut dyn NoteStore) {
+        check_files(store);
+        check_folders(store);
+        check_watch(store, outside);
+    }
+
+    fn keys(store: &dyn NoteStore) -> Vec<(String, bool)> {
+        let mut keys: Vec<(String, bool)> = store
+            .list()
+            .unwrap()
+            .into_iter()
+            .map(|entry| (entry.key, entry.is_folder))
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    fn entries(entries: &[(&str, bool)]) -> Vec<(String, bool)> {
+        entries
+            .iter()
+            .map(|(key, is_folder)| (key.to_string(), *is_folder))
+            .collect()
+    }
+
+    fn check_files(store: &mut dyn NoteStore) {
+        assert!(store.list().unwrap().is_empty());
+        assert!(store.read("missing.txt").is_err());
+
+        let modified = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        store
+            .write("a/b/note.txt", b"first", Some(modified))
+            .unwrap();
+        assert_eq!(
+            keys(store),
+            entries(&[("a", true), ("a/b", true), ("a/b/note.txt", false)])
+        );
+        let file = store.read("a/b/note.txt").unwrap();
+        assert_eq!(file.data, b"first");
+        assert_eq!(file.modified, Some(modified));
+
+        store.write("a/b/note.txt", b"second", None).unwrap();
+        assert_eq!(store.read("a/b/note.txt").unwrap().data, b"second");
+        assert!(store.write("a/b", b"folder", None).is_err());
+
+        store.rename("a/b/note.txt", "a/renamed.txt").unwrap();
+        assert_eq!(store.read("a/renamed.txt").unwrap().data, b"second");
+        assert!(store.read("a/b/note.txt").is_err());
+        assert!(store.rename("a/b/note.txt", "a/again.txt").is_err());
+
+        store.delete("a/renamed.txt").unwrap();
+        store.delete("a/renamed.txt").unwrap();
+        assert_eq!(keys(store), entries(&[("a", true), ("a/b", true)]));
+        store.delete("a").unwrap();
+        assert!(store.list().unwrap().is_empty());
+    }
+
+    fn check_folders(store: &mut dyn NoteStore) {
+        store.create_folder("projects/empty").unwrap();
+        store.create_folder("projects/empty").unwrap();
+        store.write("projects/note.txt", b"note", None).unwrap();
+        assert!(store.create_folder("projects/note.txt").is_err());
+
+        store.rename("projects", "archive/2024").unwrap();
+        assert_eq!(
+            keys(store),
+            entries(&[
+                ("archive", true),
+                ("archive/2024", true),
+                ("archive/2024/empty", true),
+                ("archive/2024/note.txt", false),
+            ])
+        );
+
+        store.delete("archive/2024").unwrap();
+        assert_eq!(keys(store), entries(&[("archive", true)]));
+        store.delete("archive").unwrap();
+    }
+
+    fn check_watch(store: &mut dyn NoteStore, outside: &mut dyn NoteStore) {
+        let model = Arc::new(Mutex::new(Model::default()));
+        let models = store.watch(model.clone()).unwrap();
+        {
+            // written while holding the lock, like the sync engine does
+            let mut model = model.lock().unwrap();
+            store.write("engine.txt", b"Engine\n\nwrite", None).unwrap();
+            read_entry(&mut model, store, "engine.txt").unwrap();
+        }
+        outside
+            .write("outside.txt", b"Outside\n\nwrite", None)
+            .unwrap();
+        let received_model = models.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(received_model.notes["outside.txt"].title, "Outside");
+        assert_eq!(received_model.notes["engine.txt"].title, "Engine");
+        assert!(received_model == *model.lock().unwrap());
+    }
+}
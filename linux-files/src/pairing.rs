@@ -0,0 +1,445 @@
+use hmac::{Hmac, Mac};
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use spake2::{Ed25519Group, Identity, Password, Spake2};
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use crate::atomic_file::{self, Durability};
+use crate::chunks::Chunk;
+use crate::networking::Message;
+
+/// Messages of the pairing of two instances.
+#[derive(Serialize, Deserialize, Clone)]
+pub enum PairingMessage {
+    /// First message: the instance and its SPAKE2 element, derived from the code.
+    Start {
+        name: String,
+        instance_id: String,
+        element: String,
+    },
+    /// Proof that the instance derived the same key, which it only does with the same code.
+    Confirm(String),
+}
+
+/// Side of the pairing: the instance showing the code or the one it is typed on.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Show,
+    Join,
+}
+
+/// Short code shown on one instance and typed on the other, like `492-015`.
+pub fn new_code() -> String {
+    let number = OsRng.next_u32() % 1_000_000;
+    format!("{:03}-{:03}", number / 1000, number % 1000)
+}
+
+/// SPAKE2 exchange, with the `spake2` crate: each side sends its element, blinded by the code,
+/// and both derive the same key only if they used the same code. An eavesdropper learns
+/// nothing about the code, and a peer with the wrong code gets a single guess.
+pub struct Exchange {
+    side: Side,
+    instance_id: String,
+    spake2: Spake2<Ed25519Group>,
+    element: Vec<u8>,
+}
+
+impl Exchange {
+    pub fn new(side: Side, code: &str, instance_id: &str) -> Exchange {
+        let digits: String = code.chars().filter(|c| c.is_ascii_digit()).collect();
+        let password = Password::new(digits.as_bytes());
+        // the instance ids are only known once the elements are exchanged, they are part of
+        // the key instead
+        let show = Identity::new(b"notes-sync pairing show");
+        let join = Identity::new(b"notes-sync pairing join");
+        let (spake2, element) = match side {
+            Side::Show => Spake2::<Ed25519Group>::start_a(&password, &show, &join),
+            Side::Join => Spake2::<Ed25519Group>::start_b(&password, &show, &join),
+        };
+        Exchange {
+            side,
+            instance_id: instance_id.to_string(),
+            spake2,
+            element,
+        }
+    }
+
+    /// Element to send to the peer, hex encoded.
+    pub fn element(&self) -> String {
+        hex::encode(&self.element)
+    }
+
+    /// Key shared with the peer, from its element. An exchange gives a single key.
+    pub fn key(self, peer_instance_id: &str, peer_element: &str) -> Result<Vec<u8>, String> {
+        let bytes = hex::decode(peer_element)
+            .map_err(|error| format!("invalid element of the peer -> {}", error))?;
+        let shared = self
+            .spake2
+            .finish(&bytes)
+            .map_err(|error| format!("invalid element of the peer -> {}", error))?;
+        // the instance ids are hashed in the same order on both sides
+        let (show, join) = match self.side {
+            Side::Show => (self.instance_id.as_str(), peer_instance_id),
+            Side::Join => (peer_instance_id, self.instance_id.as_str()),
+        };
+        let mut hasher = Sha256::new();
+        hasher.update(b"notes-sync pairing");
+        for instance_id in [show, join] {
+            hasher.update((instance_id.len() as u64).to_be_bytes());
+            hasher.update(instance_id.as_bytes());
+        }
+        hasher.update(shared);
+        Ok(hasher.finalize().to_vec())
+    }
+}
+
+/// Confirmation a side sends once it has the key.
+pub fn confirmation(key: &[u8], side: Side) -> String {
+    let label: &[u8] = match side {
+        Side::Show => b"show",
+        Side::Join => b"join",
+    };
+    hex::encode(mac(key, &[label]))
+}
+
+/// Message sent to a paired peer, authenticated with the key of the session.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SealedMessage {
+    /// The message, serialized.
+    pub payload: String,
+    pub mac: String,
+}
+
+/// Chunk sent to a paired peer, authenticated like the other messages but sent as it is in a
+/// binary frame.
+#[derive(Clone)]
+pub struct SealedChunk {
+    pub data: Vec<u8>,
+    pub mac: Vec<u8>,
+}
+
+/// Connection with a paired peer. Its key is derived from the key shared with the peer and the
+/// nonces of both hellos, so the proofs and messages of a connection are refused on any other.
+pub struct Session {
+    key: Vec<u8>,
+    instance_id: String,
+    peer_instance_id: String,
+    /// Numbers of the next messages sent and received, a replayed message doesn't have the
+    /// expected one.
+    sent: Cell<u64>,
+    received: Cell<u64>,
+}
+
+impl Session {
+    pub fn new(
+        key: &[u8],
+        nonce: &str,
+        peer_nonce: &str,
+        instance_id: &str,
+        peer_instance_id: &str,
+    ) -> Session {
+        // both instances derive the same key
+        let (first_nonce, second_nonce) = if nonce < peer_nonce {
+            (nonce, peer_nonce)
+        } else {
+            (peer_nonce, nonce)
+        };
+        Session {
+            key: mac(
+                key,
+                &[b"session", first_nonce.as_bytes(), second_nonce.as_bytes()],
+            ),
+            instance_id: instance_id.to_string(),
+            peer_instance_id: peer_instance_id.to_string(),
+            sent: Cell::new(0),
+            received: Cell::new(0),
+        }
+    }
+
+    /// Proof that the instance has the key, sent to the peer.
+    pub fn proof(&self) -> String {
+        proof(&self.key, &self.instance_id, &self.peer_instance_id)
+    }
+
+    /// Whether the peer proved it has the key.
+    pub fn verify_proof(&self, proof_received: &str) -> bool {
+        let expected = proof(&self.key, &self.peer_instance_id, &self.instance_id);
+        verify(&expected, proof_received)
+    }
+
+    /// Message to send to the peer in place of `message`, numbered so that it can't be replayed.
+    pub fn seal(&self, message: &Message) -> Result<Message, String> {
+        if let Message::Chunk(chunk) = message {
+            let mac = message_mac(
+                &self.key,
+                b"chunk",
+                &self.instance_id,
+                self.next_sent(),
+                &chunk.data,
+            );
+            return Ok(Message::SealedChunk(SealedChunk {
+                data: chunk.data.clone(),
+                mac,
+            }));
+        }
+        let payload = serde_json::to_string(message)
+            .map_err(|error| format!("cannot serialize message -> {}", error))?;
+        let mac = message_mac(
+            &self.key,
+            b"message",
+            &self.instance_id,
+            self.next_sent(),
+            payload.as_bytes(),
+        );
+        Ok(Message::Sealed(SealedMessage {
+            payload,
+            mac: hex::encode(mac),
+        }))
+    }
+
+    /// Message sealed by the peer, refused when it was changed, replayed or sealed for another
+    /// connection.
+    pub fn open(&self, sealed_message: SealedMessage) -> Result<Message, String> {
+        self.check_mac(
+            b"message",
+            sealed_message.payload.as_bytes(),
+            &hex::decode(&sealed_message.mac).unwrap_or_default(),
+        )?;
+        serde_json::from_str(&sealed_message.payload)
+            .map_err(|error| format!("invalid sealed message -> {}", error))
+    }
+
+    /// Chunk sealed by the peer, refused like the other messages.
+    pub fn open_chunk(&self, sealed_chunk: SealedChunk) -> Result<Message, String> {
+        self.check_mac(b"chunk", &sealed_chunk.data, &sealed_chunk.mac)?;
+        Ok(Message::Chunk(Chunk::new(sealed_chunk.data)))
+    }
+
+    /// Number of the next message sent, chunks and other messages being numbered together.
+    fn next_sent(&self) -> u64 {
+        let number = self.sent.get();
+        self.sent.set(number + 1);
+        number
+    }
+
+    /// Checks that a message is the next one sealed by the peer.
+    fn check_mac(&self, kind: &[u8], payload: &[u8], mac: &[u8]) -> Result<(), String> {
+        let number = self.received.get();
+        let expected = message_mac(&self.key, kind, &self.peer_instance_id, number, payload);
+        if !verify(&expected, mac) {
+            return Err(String::from(
+                "message not sealed with the key of the session",
+            ));
+        }
+        self.received.set(number + 1);
+        Ok(())
+    }
+}
+
+/// Proof from an instance to a peer, which can't be sent back as the peer's proof.
+fn proof(session_key: &[u8], prover_instance_id: &str, verifier_instance_id: &str) -> String {
+    hex::encode(mac(
+        session_key,
+        &[
+            b"proof",
+            prover_instance_id.as_bytes(),
+            verifier_instance_id.as_bytes(),
+        ],
+    ))
+}
+
+/// MAC of a message, whose kind is part of it so that a chunk can't be opened as a message.
+fn message_mac(
+    session_key: &[u8],
+    kind: &[u8],
+    sender_instance_id: &str,
+    number: u64,
+    payload: &[u8],
+) -> Vec<u8> {
+    mac(
+        session_key,
+        &[
+            kind,
+            sender_instance_id.as_bytes(),
+            &number.to_be_bytes(),
+            payload,
+        ],
+    )
+}
+
+/// Random nonce sent in the hello of a connection, the keys of its sessions depend on it.
+pub fn new_nonce() -> String {
+    let mut bytes = [0; 16];
+    OsRng.fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+/// Whether a confirmation or proof received is the expected one, compared in constant time.
+pub fn verify(expected: impl AsRef<[u8]>, received: impl AsRef<[u8]>) -> bool {
+    let expected = expected.as_ref();
+    let received = received.as_ref();
+    expected.len() == received.len()
+        && expected
+            .iter()
+            .zip(received)
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+}
+
+fn mac(key: &[u8], parts: &[&[u8]]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+    for part in parts {
+        mac.update(&(part.len() as u64).to_be_bytes());
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Key shared with a paired peer.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PeerKey {
+    pub name: String,
+    /// Hex encoded.
+    pub key: String,
+}
+
+/// Keys of the peers an instance is paired with, kept next to the workspace in
+/// `<workspace>.keys`, readable by its owner only.
+pub struct PeerKeys {
+    path: PathBuf,
+    /// By instance id.
+    pub keys: BTreeMap<String, PeerKey>,
+}
+
+impl PeerKeys {
+    pub fn open(workspace_path: &Path) -> Result<PeerKeys, String> {
+        let mut path = workspace_path.as_os_str().to_owned();
+        path.push(".keys");
+        let path = PathBuf::from(path);
+        let keys = match fs::read_to_string(&path) {
+            Ok(text) => serde_json::from_str(&text)
+                .map_err(|error| format!("invalid '{:?}' -> {}", path, error))?,
+            Err(error) if error.kind() == ErrorKind::NotFound => BTreeMap::new(),
+            Err(error) => return Err(format!("cannot read '{:?}' -> {}", path, error)),
+        };
+        Ok(PeerKeys { path, keys })
+    }
+
+    /// Key shared with a peer, `None` if it isn't paired.
+    pub fn get(&self, instance_id: &str) -> Option<Vec<u8>> {
+        self.keys
+            .get(instance_id)
+            .and_then(|peer_key| hex::decode(&peer_key.key).ok())
+    }
+
+    pub fn insert(&mut self, instance_id: &str, name: &str, key: &[u8]) -> Result<(), String> {
+        self.keys.insert(
+            instance_id.to_string(),
+            PeerKey {
+                name: name.to_string(),
+                key: hex::encode(key),
+            },
+        );
+        let text = serde_json::to_string_pretty(&self.keys)
+            .map_err(|error| format!("cannot serialize keys -> {}", error))?;
+        atomic_file::write_private(&self.path, text.as_bytes(), Durability::File)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sessions(nonce: &str, peer_nonce: &str) -> (Session, Session) {
+        (
+            Session::new(b"key", nonce, peer_nonce, "alice", "bob"),
+            Session::new(b"key", peer_nonce, nonce, "bob", "alice"),
+        )
+    }
+
+    fn exchange_keys(show_code: &str, join_code: &str) -> (Vec<u8>, Vec<u8>) {
+        let show = Exchange::new(Side::Show, show_code, "alice");
+        let join = Exchange::new(Side::Join, join_code, "bob");
+        let (show_element, join_element) = (show.element(), join.element());
+        (
+            show.key("bob", &join_element).unwrap(),
+            join.key("alice", &show_element).unwrap(),
+        )
+    }
+
+    #[test]
+    fn only_the_same_code_gives_the_same_key() {
+        let (show_key, join_key) = exchange_keys("492-015", "492015");
+        assert_eq!(show_key, join_key);
+        let (show_key, join_key) = exchange_keys("492-015", "492-016");
+        assert_ne!(show_key, join_key);
+        assert!(Exchange::new(Side::Join, "492-015", "bob")
+            .key("alice", "00")
+            .is_err());
+    }
+
+    #[test]
+    fn proofs_are_only_accepted_from_the_peer_of_the_session() {
+        let (alice, bob) = sessions("1", "2");
+        assert!(bob.verify_proof(&alice.proof()));
+        assert!(alice.verify_proof(&bob.proof()));
+        // reflected back to its prover
+        assert!(!alice.verify_proof(&alice.proof()));
+        let (other_alice, _) = sessions("1", "3");
+        assert!(!bob.verify_proof(&other_alice.proof()));
+    }
+
+    #[test]
+    fn sealed_messages_are_opened_once_and_in_order() {
+        let (alice, bob) = sessions("1", "2");
+        let seal = |session: &Session, keys: &[&str]| match session
+            .seal(&Message::Rejected(
+                keys.iter().map(|key| key.to_string()).collect(),
+            ))
+            .unwrap()
+        {
+            Message::Sealed(sealed_message) => sealed_message,
+            _ => unreachable!(),
+        };
+        let first = seal(&alice, &["first.txt"]);
+        let second = seal(&alice, &["second.txt"]);
+        assert!(bob.open(second.clone()).is_err());
+        assert!(
+            matches!(bob.open(first.clone()), Ok(Message::Rejected(keys)) if keys == ["first.txt"])
+        );
+        assert!(bob.open(first).is_err());
+        let mut changed = second.clone();
+        changed.payload = changed.payload.replace("second", "third");
+        assert!(bob.open(changed).is_err());
+        assert!(bob.open(second).is_ok());
+        // sealed by bob for alice, not by alice
+        assert!(alice.open(seal(&alice, &["own.txt"])).is_err());
+    }
+
+    #[test]
+    fn sealed_chunks_are_numbered_with_the_other_messages() {
+        let (alice, bob) = sessions("1", "2");
+        let chunk = Chunk::new(b"chunk".to_vec());
+        let sealed_chunk = match alice.seal(&Message::Chunk(chunk.clone())).unwrap() {
+            Message::SealedChunk(sealed_chunk) => sealed_chunk,
+            _ => unreachable!(),
+        };
+        let sealed_message = match alice.seal(&Message::Rejected(Vec::new())).unwrap() {
+            Message::Sealed(sealed_message) => sealed_message,
+            _ => unreachable!(),
+        };
+        assert!(bob.open(sealed_message.clone()).is_err());
+        let mut changed = sealed_chunk.clone();
+        changed.data[0] ^= 1;
+        assert!(bob.open_chunk(changed).is_err());
+        assert!(
+            matches!(bob.open_chunk(sealed_chunk), Ok(Message::Chunk(opened)) if opened.hash == chunk.hash)
+        );
+        assert!(bob.open(sealed_message).is_ok());
+    }
+}
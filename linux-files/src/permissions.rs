@@ -0,0 +1,220 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::path::Path;
+
+use crate::model::{self, Model};
+use crate::trash;
+
+/// What a peer can do to the notes of a folder.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum Access {
+    ReadOnly,
+    ReadWrite,
+}
+
+/// Access of a peer to the workspace, with exceptions for some folders and their subfolders.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PeerPermissions {
+    pub access: Access,
+    #[serde(default)]
+    pub folders: BTreeMap<String, Access>,
+}
+
+impl PeerPermissions {
+    /// Whether the peer can change a note, attachment or folder: the access of its closest
+    /// folder with one applies. Files of the trash have the access of the note they were.
+    pub fn can_write(&self, key: &str) -> bool {
+        let mut folder = trash::original_key(key).unwrap_or(key);
+        loop {
+            if let Some(access) = self.folders.get(folder) {
+                return *access == Access::ReadWrite;
+            }
+            if folder.is_empty() {
+                return self.access == Access::ReadWrite;
+            }
+            folder = model::parent_folder(folder);
+        }
+    }
+
+    /// Undoes the changes of a model received from the peer that it isn't allowed to make.
+    /// Returns the keys of the rejected changes: notes whose title, body, tags or pinned state
+    /// changed. Their other fields, like the layout or the creation time, differ between
+    /// instances, they are kept as they are here without being reported.
+    pub fn reject_changes(&self, model: &Model, received_model: &mut Model) -> Vec<String> {
+        let mut rejected_keys = BTreeSet::new();
+        let note_keys: BTreeSet<String> = model
+            .notes
+            .keys()
+            .chain(received_model.notes.keys())
+            .cloned()
+            .collect();
+        for key in note_keys {
+            let note = model.notes.get(&key);
+            let received_note = received_model.notes.get(&key);
+            if note == received_note || self.can_write(&key) {
+                continue;
+            }
+            let is_changed = match (note, received_note) {
+                (Some(note), Some(received_note)) => !note.has_same_contents(received_note),
+                _ => true,
+            };
+            match note {
+                Some(note) => received_model.notes.insert(key.clone(), note.clone()),
+                None => received_model.notes.remove(&key),
+            };
+            if is_changed {
+                rejected_keys.insert(key);
+            }
+        }
+        let attachment_keys: BTreeSet<String> = model
+            .attachments
+            .keys()
+            .chain(received_model.attachments.keys())
+            .cloned()
+            .collect();
+        for key in attachment_keys {
+            let attachment = model.attachments.get(&key);
+            if attachment != received_model.attachments.get(&key) && !self.can_write(&key) {
+                match attachment {
+                    Some(attachment) => received_model
+                        .attachments
+                        .insert(key.clone(), attachment.clone()),
+                    None => received_model.attachments.remove(&key),
+                };
+                rejected_keys.insert(key);
+            }
+        }
+        let changed_folders: Vec<String> = model
+            .folders
+            .symmetric_difference(&received_model.folders)
+            .cloned()
+            .collect();
+        for folder in changed_folders {
+            if !self.can_write(&folder) {
+                if model.folders.contains(&folder) {
+                    received_model.folders.insert(folder.clone());
+                } else {
+                    received_model.folders.remove(&folder);
+                }
+                rejected_keys.insert(folder);
+            }
+        }
+        // the tombstones follow the notes they are for
+        let tombstone_keys: BTreeSet<String> = model
+            .tombstones
+            .keys()
+            .chain(received_model.tombstones.keys())
+            .cloned()
+            .collect();
+        for key in tombstone_keys {
+            if !self.can_write(&key) {
+                match model.tombstones.get(&key) {
+                    Some(time) => received_model.tombstones.insert(key, *time),
+                    None => received_model.tombstones.remove(&key),
+                };
+            }
+        }
+        rejected_keys.into_iter().collect()
+    }
+}
+
+/// Access of each paired peer, by instance id, read from a YAML file like:
+///
+/// ```yaml
+/// default: read-only
+/// peers:
+///   dc10cd63-50fa-428f-a533-936390c58afa:
+///     access: read-write
+///   7f3b2e91-0c4d-4a8e-9b61-5d2f8e0a4c17:
+///     access: read-only
+///     folders:
+///       shared: read-write
+/// ```
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Permissions {
+    /// Access of the peers that are not listed, and of the ones that are not paired.
+    pub default: Access,
+    #[serde(default)]
+    pub peers: HashMap<String, PeerPermissions>,
+}
+
+impl Permissions {
+    pub fn load(path: &Path) -> Result<Permissions, String> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(error) => return Err(format!("cannot read '{:?}' -> {}", path, error)),
+        };
+        serde_yaml::from_str(&text).map_err(|error| format!("invalid '{:?}' -> {}", path, error))
+    }
+
+    /// Permissions of a peer, by the instance id it proved with its pairing key: an instance
+    /// that isn't paired could claim any id.
+    pub fn for_peer(&self, paired_instance_id: Option<&str>) -> PeerPermissions {
+        match paired_instance_id.and_then(|instance_id| self.peers.get(instance_id)) {
+            Some(permissions) => permissions.clone(),
+            None => PeerPermissions {
+                access: self.default,
+                folders: BTreeMap::new(),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn peers_get_their_access_only_once_paired() {
+        let permissions: Permissions = serde_yaml::from_str(
+            "default: read-only\npeers:\n  dc10cd63:\n    access: read-write\n",
+        )
+        .unwrap();
+        assert_eq!(
+            permissions.for_peer(Some("dc10cd63")).access,
+            Access::ReadWrite
+        );
+        assert_eq!(
+            permissions.for_peer(Some("1b047713")).access,
+            Access::ReadOnly
+        );
+        assert_eq!(permissions.for_peer(None).access, Access::ReadOnly);
+    }
+
+    fn read_only() -> PeerPermissions {
+        PeerPermissions {
+            access: Access::ReadOnly,
+            folders: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn read_only_peers_cannot_change_notes() {
+        let model = Model::new();
+        let mut received_model = model.clone();
+        received_model.notes.get_mut("note_1.txt").unwrap().body = String::from("changed");
+        received_model.notes.remove("note_2.txt");
+
+        let rejected_keys = read_only().reject_changes(&model, &mut received_model);
+
+        assert_eq!(rejected_keys, ["note_1.txt", "note_2.txt"]);
+        assert!(received_model == model);
+    }
+
+    #[test]
+    fn other_fields_of_notes_are_kept_without_rejection() {
+        let model = Model::new();
+        let mut received_model = model.clone();
+        let note = received_model.notes.get_mut("note_1.txt").unwrap();
+        note.created = 1_700_000_000;
+        note.layout.line_ending = String::from("\r\n");
+        note.front_matter = Some("---\ninjected: true\n---\n".to_string());
+
+        let rejected_keys = read_only().reject_changes(&model, &mut received_model);
+
+        assert!(rejected_keys.is_empty());
+        assert!(received_model == model);
+    }
+}
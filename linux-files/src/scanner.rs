@@ -0,0 +1,223 @@
+use std::collections::HashMap;
+use std::fs::{metadata, read, read_dir, Metadata};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tracing::warn;
+
+use crate::chunks;
+use crate::fs_watcher::{insert_entry, note_key};
+use crate::ignore_rules::IgnoreRules;
+use crate::model::Model;
+
+/// State of a file when it was last scanned. The status change time can't be set back like the
+/// modification time, a rewrite keeping the size and modification time still changes it.
+#[derive(Clone, PartialEq)]
+struct FileState {
+    modified: Option<SystemTime>,
+    changed: (i64, i64),
+    size: u64,
+    hash: String,
+}
+
+/// Reads the whole workspace to find the changes the watcher missed. Files whose modification
+/// and status change times and size didn't change since the last scan are not read again, and the ones whose
+/// contents didn't change are not parsed again.
+pub struct Scanner {
+    files: HashMap<PathBuf, FileState>,
+}
+
+impl Scanner {
+    pub fn new() -> Scanner {
+        Scanner {
+            files: HashMap::new(),
+        }
+    }
+
+    /// Model of the workspace as it is on disk, without tombstones. Files that didn't change
+    /// since the last scan keep their entry of `model`.
+    pub fn scan(
+        &mut self,
+        model: &Model,
+        workspace_path: &Path,
+        ignore_rules: &IgnoreRules,
+    ) -> Result<Model, String> {
+        let mut scanned_model = Model::default();
+        let mut files = HashMap::new();
+        let mut folders = vec![workspace_path.to_path_buf()];
+        while let Some(folder) = folders.pop() {
+            let entries = match read_dir(&folder) {
+                Ok(entries) => entries,
+                Err(error) if folder == workspace_path => {
+                    return Err(format!("cannot read folder '{:?}' -> {}", folder, error))
+                }
+                Err(error) => {
+                    // removed during the scan, the next one will see it gone
+                    warn!(?folder, %error, "cannot read folder");
+                    continue;
+                }
+            };
+            if folder != workspace_path {
+                scanned_model
+                    .folders
+                    .insert(note_key(workspace_path, &folder)?);
+            }
+            for entry in entries {
+                let entry = match entry {
+                    Ok(entry) => entry,
+                    Err(error) => {
+                        warn!(?folder, %error, "cannot read entry of folder");
+                        continue;
+                    }
+                };
+                let path = entry.path();
+                let is_dir = entry
+                    .file_type()
+                    .map(|file_type| file_type.is_dir())
+                    .unwrap_or(false);
+                if ignore_rules.is_ignored(&path, is_dir) {
+                    continue;
+                }
+                if is_dir {
+                    folders.push(path);
+                    continue;
+                }
+                match self.scan_file(model, &mut scanned_model, workspace_path, &path) {
+                    Ok(state) => {
+                        files.insert(path, state);
+                    }
+                    Err(error) => warn!(?path, %error, "could not scan"),
+                }
+            }
+        }
+        self.files = files;
+        Ok(scanned_model)
+    }
+
+    fn scan_file(
+        &self,
+        model: &Model,
+        scanned_model: &mut Model,
+        workspace_path: &Path,
+        path: &Path,
+    ) -> Result<FileState, String> {
+        let key = note_key(workspace_path, path)?;
+        let metadata = match metadata(path) {
+            Ok(metadata) => metadata,
+            Err(error) => return Err(format!("cannot read metadata -> {}", error)),
+        };
+        let last_state = self.files.get(path);
+        if let Some(state) = last_state {
+            if state.modified == metadata.modified().ok()
+                && state.changed == changed(&metadata)
+                && state.size == metadata.len()
+                && copy_entry(model, scanned_model, &key)
+            {
+                return Ok(state.clone());
+            }
+        }
+        let data = match read(path) {
+            Ok(data) => data,
+            Err(error) => return Err(format!("cannot read -> {}", error)),
+        };
+        let state = FileState {
+            modified: metadata.modified().ok(),
+            changed: changed(&metadata),
+            size: data.len() as u64,
+            hash: chunks::hash(&data),
+        };
+        let unchanged = last_state.map(|last_state| last_state.hash == state.hash) == Some(true);
+        if !(unchanged && copy_entry(model, scanned_model, &key)) {
+            insert_entry(scanned_model, workspace_path, path, data)?;
+        }
+        Ok(state)
+    }
+}
+
+impl Default for Scanner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn changed(metadata: &Metadata) -> (i64, i64) {
+    (metadata.ctime(), metadata.ctime_nsec())
+}
+
+/// Copies the note or attachment of a key from a model to another, returns whether there was
+/// one.
+fn copy_entry(model: &Model, scanned_model: &mut Model, key: &str) -> bool {
+    if let Some(note) = model.notes.get(key) {
+        scanned_model.notes.insert(key.to_string(), note.clone());
+        true
+    } else if let Some(attachment) = model.attachments.get(key) {
+        scanned_model
+            .attachments
+            .insert(key.to_string(), attachment.clone());
+        true
+    } else {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{remove_file, write, File};
+
+    fn scan(scanner: &mut Scanner, model: &Model, workspace_path: &Path) -> Model {
+        let ignore_rules = IgnoreRules::load(workspace_path).unwrap();
+        scanner.scan(model, workspace_path, &ignore_rules).unwrap()
+    }
+
+    #[test]
+    fn unchanged_files_keep_their_entry() {
+        let workspace = tempfile::tempdir().unwrap();
+        write(workspace.path().join("note.txt"), "Title\n\nbody").unwrap();
+        let mut scanner = Scanner::new();
+        let mut model = scan(&mut scanner, &Model::default(), workspace.path());
+        assert_eq!(model.notes["note.txt"].body, "body");
+
+        // not read again, the entry of the model is kept
+        model.notes.get_mut("note.txt").unwrap().body = String::from("kept");
+        let scanned_model = scan(&mut scanner, &model, workspace.path());
+        assert_eq!(scanned_model.notes["note.txt"].body, "kept");
+    }
+
+    #[test]
+    fn rewrites_keeping_the_size_and_modification_time_are_read() {
+        let workspace = tempfile::tempdir().unwrap();
+        let path = workspace.path().join("note.txt");
+        write(&path, "Title\n\nfirst").unwrap();
+        let modified = metadata(&path).unwrap().modified().unwrap();
+        let mut scanner = Scanner::new();
+        let model = scan(&mut scanner, &Model::default(), workspace.path());
+
+        write(&path, "Title\n\nsecnd").unwrap();
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        assert_eq!(metadata(&path).unwrap().modified().unwrap(), modified);
+        let scanned_model = scan(&mut scanner, &model, workspace.path());
+        assert_eq!(scanned_model.notes["note.txt"].body, "secnd");
+    }
+
+    #[test]
+    fn deleted_files_are_not_in_the_scanned_model() {
+        let workspace = tempfile::tempdir().unwrap();
+        write(workspace.path().join("kept.txt"), "Kept\n\nbody").unwrap();
+        write(workspace.path().join("deleted.txt"), "Deleted\n\nbody").unwrap();
+        let mut scanner = Scanner::new();
+        let model = scan(&mut scanner, &Model::default(), workspace.path());
+        assert_eq!(model.notes.len(), 2);
+
+        remove_file(workspace.path().join("deleted.txt")).unwrap();
+        let scanned_model = scan(&mut scanner, &model, workspace.path());
+        assert!(scanned_model.notes.contains_key("kept.txt"));
+        assert!(!scanned_model.notes.contains_key("deleted.txt"));
+        assert_eq!(scanner.files.len(), 1);
+    }
+}
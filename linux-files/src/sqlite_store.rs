@@ -0,0 +1,486 @@
+use rusqlite::{params, Connection, OptionalExtension};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::error;
+
+use crate::model::Model;
+use crate::note_store::{load_model, update_model, NoteStore, StoreEntry, StoredFile};
+
+/// Time between two checks for changes made by other connections to the database.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Every change of a file is recorded in `revisions` and indexed in `search` by triggers, so
+/// that changes made by other connections are recorded too. Revisions of deleted files have no
+/// data. Files with a NUL byte are binary and are not indexed.
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS files (
+        key TEXT PRIMARY KEY,
+        is_folder INTEGER NOT NULL,
+        data BLOB,
+        created INTEGER,
+        modified INTEGER
+    );
+    CREATE TABLE IF NOT EXISTS revisions (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        key TEXT NOT NULL,
+        data BLOB,
+        modified INTEGER,
+        recorded INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS revisions_key ON revisions (key, recorded);
+    CREATE VIRTUAL TABLE IF NOT EXISTS search USING fts5 (key UNINDEXED, text);
+
+    CREATE TRIGGER IF NOT EXISTS files_insert AFTER INSERT ON files WHEN NEW.is_folder = 0
+    BEGIN
+        INSERT INTO revisions (key, data, modified, recorded)
+        VALUES (NEW.key, NEW.data, NEW.modified, CAST(unixepoch('subsec') * 1e9 AS INTEGER));
+        INSERT INTO search (key, text)
+        SELECT NEW.key, CAST(NEW.data AS TEXT) WHERE instr(NEW.data, X'00') = 0;
+    END;
+    CREATE TRIGGER IF NOT EXISTS files_update AFTER UPDATE ON files WHEN NEW.is_folder = 0
+    BEGIN
+        INSERT INTO revisions (key, data, modified, recorded)
+        SELECT OLD.key, NULL, NULL, CAST(unixepoch('subsec') * 1e9 AS INTEGER)
+        WHERE OLD.key != NEW.key;
+        INSERT INTO revisions (key, data, modified, recorded)
+        SELECT NEW.key, NEW.data, NEW.modified, CAST(unixepoch('subsec') * 1e9 AS INTEGER)
+        WHERE OLD.key != NEW.key OR OLD.data IS NOT NEW.data;
+        DELETE FROM search WHERE key = OLD.key;
+        INSERT INTO search (key, text)
+        SELECT NEW.key, CAST(NEW.data AS TEXT) WHERE instr(NEW.data, X'00') = 0;
+    END;
+    CREATE TRIGGER IF NOT EXISTS files_delete AFTER DELETE ON files WHEN OLD.is_folder = 0
+    BEGIN
+        INSERT INTO revisions (key, data, modified, recorded)
+        VALUES (OLD.key, NULL, NULL, CAST(unixepoch('subsec') * 1e9 AS INTEGER));
+        DELETE FROM search WHERE key = OLD.key;
+    END;
+";
+
+/// Revision of a file of a `SqliteStore`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Revision {
+    pub id: i64,
+    pub key: String,
+    /// Contents of the file, `None` when the file was deleted.
+    pub data: Option<Vec<u8>>,
+    pub modified: Option<SystemTime>,
+    /// When the revision was written to the database.
+    pub recorded: SystemTime,
+}
+
+/// File matching a full-text search, with an extract of its text around the match.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SearchResult {
+    pub key: String,
+    pub snippet: String,
+}
+
+/// Store keeping the whole workspace in a single SQLite database, with the revisions of every
+/// file. Timestamps are stored in nanoseconds since the Unix epoch.
+pub struct SqliteStore {
+    path: PathBuf,
+    connection: Connection,
+}
+
+impl SqliteStore {
+    /// Opens a database, creating it if it doesn't exist.
+    pub fn open(path: &Path) -> Result<SqliteStore, String> {
+        let connection = match Connection::open(path) {
+            Ok(connection) => connection,
+            Err(error) => return Err(format!("cannot open database '{:?}' -> {}", path, error)),
+        };
+        if let Err(error) = connection.execute_batch(SCHEMA) {
+            return Err(format!("cannot create tables of '{:?}' -> {}", path, error));
+        }
+        Ok(SqliteStore {
+            path: path.to_path_buf(),
+            connection,
+        })
+    }
+
+    /// Creates the missing parent folders of a key.
+    fn create_parents(&self, key: &str) -> Result<(), String> {
+        let mut parent = key;
+        while let Some((folder, _)) = parent.rsplit_once('/') {
+            self.connection
+                .execute(
+                    "INSERT OR IGNORE INTO files (key, is_folder) VALUES (?1, 1)",
+                    params![folder],
+                )
+                .map_err(|error| format!("cannot create folder '{}' -> {}", folder, error))?;
+            parent = folder;
+        }
+        Ok(())
+    }
+
+    /// Keys of the files modified since a time, most recent first.
+    pub fn modified_since(&self, time: SystemTime) -> Result<Vec<String>, String> {
+        let mut statement = self
+            .connection
+            .prepare(
+                "SELECT key FROM files WHERE is_folder = 0 AND modified >= ?1
+                 ORDER BY modified DESC",
+            )
+            .map_err(|error| format!("cannot query files -> {}", error))?;
+        let rows = statement
+            .query_map(params![to_nanoseconds(time)], |row| row.get(0))
+            .map_err(|error| format!("cannot query files -> {}", error))?;
+        rows.collect::<Result<Vec<String>, rusqlite::Error>>()
+            .map_err(|error| format!("cannot query files -> {}", error))
+    }
+
+    /// Files matching a full-text search query, in the FTS5 query syntax, best matches first.
+    pub fn search(&self, query: &str) -> Result<Vec<SearchResult>, String> {
+        let mut statement = self
+            .connection
+            .prepare(
+                "SELECT key, snippet(search, 1, '[', ']', '...', 12) FROM search
+                 WHERE search MATCH ?1 ORDER BY rank",
+            )
+            .map_err(|error| format!("cannot search -> {}", error))?;
+        let rows = statement
+            .query_map(params![query], |row| {
+                Ok(SearchResult {
+                    key: row.get(0)?,
+                    snippet: row.get(1)?,
+                })
+            })
+            .map_err(|error| format!("cannot search -> {}", error))?;
+        rows.collect::<Result<Vec<SearchResult>, rusqlite::Error>>()
+            .map_err(|error| format!("cannot search '{}' -> {}", query, error))
+    }
+
+    /// Revisions of a file, oldest first.
+    pub fn revisions(&self, key: &str) -> Result<Vec<Revision>, String> {
+        let mut statement = self
+            .connection
+            .prepare(
+                "SELECT id, key, data, modified, recorded FROM revisions WHERE key = ?1
+                 ORDER BY id",
+            )
+            .map_err(|error| format!("cannot read revisions -> {}", error))?;
+        let rows = statement
+            .query_map(params![key], read_revision)
+            .map_err(|error| format!("cannot read revisions -> {}", error))?;
+        rows.collect::<Result<Vec<Revision>, rusqlite::Error>>()
+            .map_err(|error| format!("cannot read revisions of '{}' -> {}", key, error))
+    }
+
+    /// Puts every file back as it was at a time: files created since are deleted, and the
+    /// others get the contents of their last revision before that time. Folders are kept. The
+    /// restore is itself recorded as new revisions.
+    pub fn restore(&mut self, time: SystemTime) -> Result<(), String> {
+        let transaction = self
+            .connection
+            .transaction()
+            .map_err(|error| format!("cannot start restore -> {}", error))?;
+        let revisions = {
+            let mut statement = transaction
+                .prepare(
+                    "SELECT id, key, data, modified, recorded FROM revisions WHERE id IN (
+                        SELECT max(id) FROM revisions WHERE recorded <= ?1 GROUP BY key
+                    )",
+                )
+                .map_err(|error| format!("cannot read revisions -> {}", error))?;
+            let rows = statement
+                .query_map(params![to_nanoseconds(time)], read_revision)
+                .map_err(|error| format!("cannot read revisions -> {}", error))?;
+            rows.collect::<Result<Vec<Revision>, rusqlite::Error>>()
+                .map_err(|error| format!("cannot read revisions -> {}", error))?
+        };
+        // only the files that differ are changed, so that they are the only ones to get a new
+        // revision
+        for revision in revisions {
+            if let Some(data) = revision.data {
+                transaction
+                    .execute(
+                        "INSERT INTO files (key, is_folder, data, created, modified)
+                         VALUES (?1, 0, ?2, ?3, ?3)
+                         ON CONFLICT (key) DO UPDATE SET data = ?2, modified = ?3
+                         WHERE data IS NOT ?2",
+                        params![
+                            revision.key,
+                            data,
+                            revision.modified.and_then(to_nanoseconds)
+                        ],
+                    )
+                    .map_err(|error| format!("cannot restore '{}' -> {}", revision.key, error))?;
+            }
+        }
+        transaction
+            .execute(
+                "DELETE FROM files WHERE is_folder = 0 AND key NOT IN (
+                    SELECT key FROM revisions WHERE data IS NOT NULL AND id IN (
+                        SELECT max(id) FROM revisions WHERE recorded <= ?1 GROUP BY key
+                    )
+                )",
+                params![to_nanoseconds(time)],
+            )
+            .map_err(|error| format!("cannot restore -> {}", error))?;
+        transaction
+            .commit()
+            .map_err(|error| format!("cannot restore -> {}", error))?;
+        // folders that only existed then are created again
+        for entry in self.list()? {
+            if !entry.is_folder {
+                self.create_parents(&entry.key)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Parses a date like `2024-05-01` or `2024-05-01 18:30`, in local time.
+    pub fn parse_time(&self, text: &str) -> Result<SystemTime, String> {
+        let nanoseconds: Option<i64> = self
+            .connection
+            .query_row(
+                "SELECT CAST(unixepoch(?1, 'utc', 'subsec') * 1e9 AS INTEGER)",
+                params![text],
+                |row| row.get(0),
+            )
+            .map_err(|error| format!("cannot parse date '{}' -> {}", text, error))?;
+        match nanoseconds.and_then(from_nanoseconds) {
+            Some(time) => Ok(time),
+            None => Err(format!("invalid date '{}'", text)),
+        }
+    }
+
+    /// Whether a key exists, and is a folder.
+    fn is_folder(&self, key: &str) -> Result<Option<bool>, String> {
+        self.connection
+            .query_row(
+                "SELECT is_folder FROM files WHERE key = ?1",
+                params![key],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(|error| format!("cannot read '{}' -> {}", key, error))
+    }
+}
+
+fn read_revision(row: &rusqlite::Row) -> Result<Revision, rusqlite::Error> {
+    Ok(Revision {
+        id: row.get(0)?,
+        key: row.get(1)?,
+        data: row.get(2)?,
+        modified: row.get::<_, Option<i64>>(3)?.and_then(from_nanoseconds),
+        recorded: from_nanoseconds(row.get(4)?).unwrap_or(UNIX_EPOCH),
+    })
+}
+
+fn to_nanoseconds(time: SystemTime) -> Option<i64> {
+    time.duration_since(UNIX_EPOCH)
+        .ok()
+        .and_then(|duration| i64::try_from(duration.as_nanos()).ok())
+}
+
+fn from_nanoseconds(nanoseconds: i64) -> Option<SystemTime> {
+    u64::try_from(nanoseconds)
+        .ok()
+        .map(|nanoseconds| UNIX_EPOCH + Duration::from_nanos(nanoseconds))
+}
+
+impl NoteStore for SqliteStore {
+    fn list(&self) -> Result<Vec<StoreEntry>, String> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT key, is_folder FROM files ORDER BY key")
+            .map_err(|error| format!("cannot list files -> {}", error))?;
+        let rows = statement
+            .query_map([], |row| {
+                Ok(StoreEntry {
+                    key: row.get(0)?,
+                    is_folder: row.get(1)?,
+                })
+            })
+            .map_err(|error| format!("cannot list files -> {}", error))?;
+        rows.collect::<Result<Vec<StoreEntry>, rusqlite::Error>>()
+            .map_err(|error| format!("cannot list files -> {}", error))
+    }
+
+    fn read(&self, key: &str) -> Result<StoredFile, String> {
+        let row = self
+            .connection
+            .query_row(
+                "SELECT data, created, modified FROM files WHERE key = ?1 AND is_folder = 0",
+                params![key],
+                |row| {
+                    Ok((
+                        row.get::<_, Vec<u8>>(0)?,
+                        row.get::<_, Option<i64>>(1)?,
+                        row.get::<_, Option<i64>>(2)?,
+                    ))
+                },
+            )
+            .optional()
+            .map_err(|error| format!("cannot read '{}' -> {}", key, error))?;
+        match row {
+            Some((data, created, modified)) => Ok(StoredFile {
+                data,
+                created: created.and_then(from_nanoseconds),
+                modified: modified.and_then(from_nanoseconds),
+            }),
+            None => Err(format!("'{}' doesn't exist", key)),
+        }
+    }
+
+    fn write(
+        &mut self,
+        key: &str,
+        data: &[u8],
+        modified: Option<SystemTime>,
+    ) -> Result<(), String> {
+        if self.is_folder(key)? == Some(true) {
+            return Err(format!("'{}' is a folder", key));
+        }
+        self.create_parents(key)?;
+        let now = to_nanoseconds(SystemTime::now());
+        let modified = modified.and_then(to_nanoseconds).or(now);
+        self.connection
+            .execute(
+                "INSERT INTO files (key, is_folder, data, created, modified)
+                 VALUES (?1, 0, ?2, ?3, ?4)
+                 ON CONFLICT (key) DO UPDATE SET data = ?2, modified = ?4",
+                params![key, data, now, modified],
+            )
+            .map_err(|error| format!("cannot write '{}' -> {}", key, error))?;
+        Ok(())
+    }
+
+    fn create_folder(&mut self, key: &str) -> Result<(), String> {
+        match self.is_folder(key)? {
+            Some(true) => return Ok(()),
+            Some(false) => return Err(format!("'{}' is a file", key)),
+            None => {}
+        }
+        self.create_parents(key)?;
+        self.connection
+            .execute(
+                "INSERT INTO files (key, is_folder) VALUES (?1, 1)",
+                params![key],
+            )
+            .map_err(|error| format!("cannot create folder '{}' -> {}", key, error))?;
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &str) -> Result<(), String> {
+        self.connection
+            .execute(
+                "DELETE FROM files WHERE key = ?1 OR substr(key, 1, length(?1) + 1) = ?1 || '/'",
+                params![key],
+            )
+            .map_err(|error| format!("cannot delete '{}' -> {}", key, error))?;
+        Ok(())
+    }
+
+    fn rename(&mut self, from: &str, to: &str) -> Result<(), String> {
+        if self.is_folder(from)?.is_none() {
+            return Err(format!("'{}' doesn't exist", from));
+        }
+        self.create_parents(to)?;
+        self.connection
+            .execute(
+                "UPDATE files SET key = ?2 || substr(key, length(?1) + 1)
+                 WHERE key = ?1 OR substr(key, 1, length(?1) + 1) = ?1 || '/'",
+                params![from, to],
+            )
+            .map_err(|error| format!("cannot rename '{}' to '{}' -> {}", from, to, error))?;
+        Ok(())
+    }
+
+    fn watch(&mut self, model: Arc<Mutex<Model>>) -> Result<Receiver<Model>, String> {
+        // a connection of its own, its data version changes with the commits of the others
+        let store = SqliteStore::open(&self.path)?;
+        let (sender, receiver) = channel();
+        thread::spawn(move || {
+            let mut last_version = None;
+            loop {
+                let version: Option<i64> =
+                    match store
+                        .connection
+                        .query_row("PRAGMA data_version", [], |row| row.get(0))
+                    {
+                        Ok(version) => Some(version),
+                        Err(error) => {
+                            error!(%error, "cannot read database version");
+                            None
+                        }
+                    };
+                if version.is_some() && version != last_version {
+                    last_version = version;
+                    // read under the lock, so that the model has the writes made before
+                    let mut current_model = model.lock().unwrap();
+                    match load_model(&store) {
+                        Ok(stored_model) => {
+                            if update_model(&mut current_model, stored_model)
+                                && sender.send(current_model.clone()).is_err()
+                            {
+                                return;
+                            }
+                        }
+                        Err(error) => error!(%error, "cannot read database"),
+                    }
+                }
+                thread::sleep(POLL_INTERVAL);
+            }
+        });
+        Ok(receiver)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::note_store::contract;
+
+    #[test]
+    fn contract() {
+        let folder = tempfile::tempdir().unwrap();
+        let path = folder.path().join("workspace.sqlite");
+        contract::check(
+            &mut SqliteStore::open(&path).unwrap(),
+            &mut SqliteStore::open(&path).unwrap(),
+        );
+    }
+
+    #[test]
+    fn restore_from_revisions() {
+        let folder = tempfile::tempdir().unwrap();
+        let mut store = SqliteStore::open(&folder.path().join("workspace.sqlite")).unwrap();
+        store.write("kept.txt", b"first", None).unwrap();
+        store.write("deleted.txt", b"deleted", None).unwrap();
+        // revisions are recorded to the millisecond
+        thread::sleep(Duration::from_millis(10));
+        let time = SystemTime::now();
+        thread::sleep(Duration::from_millis(10));
+        store.write("kept.txt", b"second", None).unwrap();
+        store.delete("deleted.txt").unwrap();
+        store.write("folder/created.txt", b"created", None).unwrap();
+
+        store.restore(time).unwrap();
+        assert_eq!(store.read("kept.txt").unwrap().data, b"first");
+        assert_eq!(store.read("deleted.txt").unwrap().data, b"deleted");
+        assert!(store.read("folder/created.txt").is_err());
+        let revisions: Vec<Option<Vec<u8>>> = store
+            .revisions("kept.txt")
+            .unwrap()
+            .into_iter()
+            .map(|revision| revision.data)
+            .collect();
+        assert_eq!(
+            revisions,
+            [
+                Some(b"first".to_vec()),
+                Some(b"second".to_vec()),
+                Some(b"first".to_vec())
+            ]
+        );
+        let deleted = store.revisions("deleted.txt").unwrap();
+        assert_eq!(deleted.len(), 3);
+        assert_eq!(deleted[1].data, None);
+    }
+}
@@ -0,0 +1,195 @@
+use serde::{Deserialize, Serialize};
+use std::time::SystemTime;
+use tracing::error;
+
+use crate::note_store::seconds_since_epoch;
+
+/// How many conflicts and errors are kept, the older ones are forgotten.
+const MAX_ENTRIES: usize = 50;
+
+/// State of a running instance, as `status` shows it.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct Status {
+    pub name: String,
+    pub instance_id: String,
+    pub workspace: String,
+    /// Peers synced with.
+    pub peers: Vec<PeerStatus>,
+    /// Changes made here that weren't sent to any peer yet.
+    pub pending_changes: u64,
+    /// Last time a model was sent to or received from a peer.
+    pub last_sync: Option<u64>,
+    /// Whether sync was paused through the control socket.
+    #[serde(default)]
+    pub paused: bool,
+    /// Last concurrent changes to the same notes, oldest first.
+    pub conflicts: Vec<Conflict>,
+    /// Last errors, oldest first.
+    pub errors: Vec<StatusError>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PeerStatus {
+    pub name: String,
+    pub instance_id: String,
+    pub connected_since: u64,
+    /// Last time a model was sent to or received from the peer.
+    pub last_sync: Option<u64>,
+}
+
+/// Notes changed both here and by a peer, of which only one version was kept.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Conflict {
+    pub time: u64,
+    pub keys: Vec<String>,
+    /// Name of the peer the changes were received from.
+    pub peer: String,
+    /// Name of the instance whose versions were kept.
+    pub kept: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct StatusError {
+    pub time: u64,
+    pub message: String,
+}
+
+impl Status {
+    pub fn new(name: &str, instance_id: &str, workspace: &str) -> Status {
+        Status {
+            name: name.to_string(),
+            instance_id: instance_id.to_string(),
+            workspace: workspace.to_string(),
+            ..Status::default()
+        }
+    }
+
+    pub fn add_peer(&mut self, name: &str, instance_id: &str) {
+        self.remove_peer(instance_id);
+        self.peers.push(PeerStatus {
+            name: name.to_string(),
+            instance_id: instance_id.to_string(),
+            connected_since: now(),
+            last_sync: None,
+        });
+    }
+
+    pub fn remove_peer(&mut self, instance_id: &str) {
+        self.peers.retain(|peer| peer.instance_id != instance_id);
+    }
+
+    /// Notes that a model was sent to or received from a peer.
+    pub fn synced(&mut self, instance_id: &str) {
+        let time = now();
+        self.last_sync = Some(time);
+        if let Some(peer) = self
+            .peers
+            .iter_mut()
+            .find(|peer| peer.instance_id == instance_id)
+        {
+            peer.last_sync = Some(time);
+        }
+    }
+
+    pub fn add_conflict(&mut self, keys: Vec<String>, peer: &str, kept: &str) {
+        push_bounded(
+            &mut self.conflicts,
+            Conflict {
+                time: now(),
+                keys,
+                peer: peer.to_string(),
+                kept: kept.to_string(),
+            },
+        );
+    }
+
+    pub fn has_conflict(&self, key: &str) -> bool {
+        self.conflicts
+            .iter()
+            .any(|conflict| conflict.keys.iter().any(|conflict_key| conflict_key == key))
+    }
+
+    /// Ends the conflicts on a note.
+    pub fn resolve(&mut self, key: &str) {
+        for conflict in self.conflicts.iter_mut() {
+            conflict.keys.retain(|conflict_key| conflict_key != key);
+        }
+        self.conflicts.retain(|conflict| !conflict.keys.is_empty());
+    }
+
+    /// Logs an error and keeps it for `status`.
+    pub fn add_error(&mut self, message: String) {
+        error!("{}", message);
+        push_bounded(
+            &mut self.errors,
+            StatusError {
+                time: now(),
+                message,
+            },
+        );
+    }
+}
+
+fn push_bounded<T>(entries: &mut Vec<T>, entry: T) {
+    if entries.len() == MAX_ENTRIES {
+        entries.remove(0);
+    }
+    entries.push(entry);
+}
+
+fn now() -> u64 {
+    seconds_since_epoch(SystemTime::now())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_last_entries_are_kept() {
+        let mut entries = Vec::new();
+        for entry in 0..MAX_ENTRIES + 10 {
+            push_bounded(&mut entries, entry);
+        }
+        assert_eq!(entries.len(), MAX_ENTRIES);
+        assert_eq!(entries.first(), Some(&10));
+        assert_eq!(entries.last(), Some(&(MAX_ENTRIES + 9)));
+    }
+
+    #[test]
+    fn resolve_ends_the_conflicts_on_a_note() {
+        let mut status = Status::new("alice", "1", "workspace");
+        status.add_conflict(
+            vec![String::from("a.txt"), String::from("b.txt")],
+            "bob",
+            "bob",
+        );
+        status.add_conflict(vec![String::from("a.txt")], "carol", "alice");
+        assert!(status.has_conflict("a.txt"));
+
+        status.resolve("a.txt");
+        assert!(!status.has_conflict("a.txt"));
+        assert!(status.has_conflict("b.txt"));
+        assert_eq!(status.conflicts.len(), 1);
+        assert_eq!(status.conflicts[0].peer, "bob");
+        status.resolve("b.txt");
+        assert!(status.conflicts.is_empty());
+    }
+
+    #[test]
+    fn a_reconnecting_peer_replaces_its_previous_connection() {
+        let mut status = Status::new("alice", "1", "workspace");
+        status.add_peer("bob", "2");
+        status.synced("2");
+        status.add_peer("carol", "3");
+        status.add_peer("bob again", "2");
+        let peers: Vec<(&str, Option<u64>)> = status
+            .peers
+            .iter()
+            .map(|peer| (peer.name.as_str(), peer.last_sync))
+            .collect();
+        assert_eq!(peers, [("carol", None), ("bob again", None)]);
+        status.remove_peer("2");
+        assert_eq!(status.peers.len(), 1);
+    }
+}
@@ -0,0 +1,232 @@
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use serde::{Deserialize, Serialize};
+
+use crate::model::Model;
+use crate::trash;
+
+/// Notes an instance syncs, as glob patterns on their keys like `work/**`: the ones matching
+/// an include pattern, or every note if there are none, except the ones matching an exclude
+/// pattern.
+#[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq)]
+pub struct Subscription {
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+}
+
+/// Notes synced with a peer: the ones both instances are subscribed to.
+pub struct Scope {
+    /// Include then exclude patterns of each subscription.
+    matchers: Vec<(Option<GlobSet>, GlobSet)>,
+}
+
+impl Scope {
+    pub fn new(subscriptions: &[&Subscription]) -> Result<Scope, String> {
+        let mut matchers = Vec::new();
+        for subscription in subscriptions {
+            let include = if subscription.include.is_empty() {
+                None
+            } else {
+                Some(glob_set(&subscription.include)?)
+            };
+            matchers.push((include, glob_set(&subscription.exclude)?));
+        }
+        Ok(Scope { matchers })
+    }
+
+    /// Whether a note, attachment or folder is synced. Files of the trash are synced with the
+    /// note they were.
+    pub fn contains(&self, key: &str) -> bool {
+        let key = trash::original_key(key).unwrap_or(key);
+        self.matchers.iter().all(|(include, exclude)| {
+            include
+                .as_ref()
+                .map(|include| include.is_match(key))
+                .unwrap_or(true)
+                && !exclude.is_match(key)
+        })
+    }
+
+    /// Part of a model sent to the peer: the notes and attachments of the scope, with their
+    /// folders and the folders of the scope.
+    pub fn filter(&self, model: &Model) -> Model {
+        let mut filtered_model = Model {
+            notes: model
+                .notes
+                .iter()
+                .filter(|(key, _)| self.contains(key))
+                .map(|(key, note)| (key.clone(), note.clone()))
+                .collect(),
+            attachments: model
+                .attachments
+                .iter()
+                .filter(|(key, _)| self.contains(key))
+                .map(|(key, attachment)| (key.clone(), attachment.clone()))
+                .collect(),
+            tombstones: model
+                .tombstones
+                .iter()
+                .filter(|(key, _)| self.contains(key))
+                .map(|(key, time)| (key.clone(), *time))
+                .collect(),
+            ..Model::default()
+        };
+        let parent_folders = filtered_model.all_folders();
+        filtered_model.folders = model
+            .folders
+            .iter()
+            .filter(|folder| self.contains(folder) || parent_folders.contains(*folder))
+            .cloned()
+            .collect();
+        filtered_model
+    }
+
+    /// Model of this instance once a model of the peer is received: the received one, which only
+    /// has the notes of the scope, and everything of `model` outside of it, which the peer never
+    /// changes.
+    pub fn merge(&self, model: &Model, received_model: Model) -> Model {
+        let mut merged_model = received_model;
+        for (key, note) in model.notes.iter() {
+            if !self.contains(key) {
+                merged_model.notes.insert(key.clone(), note.clone());
+            }
+        }
+        for (key, attachment) in model.attachments.iter() {
+            if !self.contains(key) {
+                merged_model
+                    .attachments
+                    .insert(key.clone(), attachment.clone());
+            }
+        }
+        for (key, time) in model.tombstones.iter() {
+            if !self.contains(key) {
+                merged_model.tombstones.insert(key.clone(), *time);
+            }
+        }
+        for folder in model.folders.iter() {
+            if !self.contains(folder) {
+                merged_model.folders.insert(folder.clone());
+            }
+        }
+        merged_model
+    }
+}
+
+fn glob_set(patterns: &[String]) -> Result<GlobSet, String> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        // `*` stays in a folder, `**` goes through folders
+        match GlobBuilder::new(pattern.trim_end_matches('/'))
+            .literal_separator(true)
+            .build()
+        {
+            Ok(glob) => {
+                builder.add(glob);
+            }
+            Err(error) => return Err(format!("invalid pattern '{}' -> {}", pattern, error)),
+        }
+    }
+    builder
+        .build()
+        .map_err(|error| format!("cannot build patterns -> {}", error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::Note;
+
+    fn subscription(include: &[&str], exclude: &[&str]) -> Subscription {
+        Subscription {
+            include: include.iter().map(|pattern| pattern.to_string()).collect(),
+            exclude: exclude.iter().map(|pattern| pattern.to_string()).collect(),
+        }
+    }
+
+    fn model_with(keys: &[&str], body: &str) -> Model {
+        let mut model = Model::default();
+        for key in keys {
+            let mut note = Note::new();
+            note.body = body.to_string();
+            model.notes.insert(key.to_string(), note);
+        }
+        model
+    }
+
+    #[test]
+    fn excludes_win_over_includes() {
+        let work = subscription(&["work/**"], &["work/drafts/**"]);
+        let scope = Scope::new(&[&work]).unwrap();
+        assert!(scope.contains("work/plan.md"));
+        assert!(scope.contains("work/projects/plan.md"));
+        assert!(!scope.contains("work/drafts/plan.md"));
+        assert!(!scope.contains("home.md"));
+        assert!(scope.contains(".trash/work/plan.md"));
+        assert!(!scope.contains(".trash/work/drafts/plan.md"));
+
+        let everything = Scope::new(&[&Subscription::default()]).unwrap();
+        assert!(everything.contains("home.md"));
+    }
+
+    #[test]
+    fn single_stars_stay_in_a_folder() {
+        let top = subscription(&["*.md"], &[]);
+        let scope = Scope::new(&[&top]).unwrap();
+        assert!(scope.contains("plan.md"));
+        assert!(!scope.contains("work/plan.md"));
+        assert!(Scope::new(&[&subscription(&["["], &[])]).is_err());
+    }
+
+    #[test]
+    fn scopes_of_two_peers_have_the_notes_both_are_subscribed_to() {
+        let alice = subscription(&["work/**"], &[]);
+        let bob = subscription(&[], &["work/private/**"]);
+        let scope = Scope::new(&[&alice, &bob]).unwrap();
+        assert!(scope.contains("work/plan.md"));
+        assert!(!scope.contains("work/private/plan.md"));
+        assert!(!scope.contains("home.md"));
+    }
+
+    #[test]
+    fn filtered_models_keep_the_folders_of_their_notes() {
+        let scope = Scope::new(&[&subscription(&["work/projects/**"], &[])]).unwrap();
+        let mut model = model_with(&["work/projects/plan.md", "home.md"], "body");
+        model.folders.insert(String::from("work"));
+        model.folders.insert(String::from("work/projects"));
+        model.folders.insert(String::from("work/projects/empty"));
+        model.folders.insert(String::from("personal"));
+        model
+            .tombstones
+            .insert(String::from("work/projects/old.md"), 1);
+        model.tombstones.insert(String::from("old.md"), 1);
+
+        let filtered_model = scope.filter(&model);
+        let mut keys: Vec<&str> = filtered_model.notes.keys().map(String::as_str).collect();
+        keys.sort();
+        assert_eq!(keys, ["work/projects/plan.md"]);
+        let folders: Vec<&str> = filtered_model.folders.iter().map(String::as_str).collect();
+        assert_eq!(folders, ["work", "work/projects", "work/projects/empty"]);
+        let tombstones: Vec<&str> = filtered_model
+            .tombstones
+            .keys()
+            .map(String::as_str)
+            .collect();
+        assert_eq!(tombstones, ["work/projects/old.md"]);
+    }
+
+    #[test]
+    fn merged_models_keep_what_is_outside_of_the_scope() {
+        let scope = Scope::new(&[&subscription(&["work/**"], &[])]).unwrap();
+        let model = model_with(&["work/plan.md", "work/old.md", "home.md"], "local");
+        // the peer changed a note and deleted another
+        let received_model = model_with(&["work/plan.md"], "received");
+
+        let merged_model = scope.merge(&model, received_model);
+        let mut notes: Vec<(&str, &str)> = merged_model
+            .notes
+            .iter()
+            .map(|(key, note)| (key.as_str(), note.body.as_str()))
+            .collect();
+        notes.sort();
+        assert_eq!(notes, [("home.md", "local"), ("work/plan.md", "received")]);
+    }
+}
@@ -0,0 +1,585 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use crate::atomic_file::{self, Durability};
+use crate::model::{Model, Note};
+
+/// Causal metadata of a model: how many changes of each instance it includes, and which
+/// instance made the last one.
+#[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq)]
+pub struct Version {
+    /// Number of changes made by each instance, by instance id.
+    pub clock: BTreeMap<String, u64>,
+    /// Id of the instance that made the last change.
+    pub origin: String,
+    /// Name of the instance that made the last change, the author of its revisions.
+    pub author: String,
+    /// Last change of each note and attachment, including the deleted ones, by key.
+    #[serde(default)]
+    pub changes: BTreeMap<String, Change>,
+}
+
+/// Change of the model that last touched a key: the instance that made it, and its number
+/// among the changes of that instance.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Change {
+    pub origin: String,
+    pub count: u64,
+}
+
+/// How two versions of a model relate.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Order {
+    /// The other version includes every change of this one and more.
+    Before,
+    Same,
+    /// This version includes every change of the other one and more.
+    After,
+    /// Both versions have changes the other doesn't have.
+    Concurrent,
+}
+
+impl Version {
+    pub fn compare(&self, other: &Version) -> Order {
+        let mut before = false;
+        let mut after = false;
+        for id in self.clock.keys().chain(other.clock.keys()) {
+            let count = self.clock.get(id).copied().unwrap_or(0);
+            let other_count = other.clock.get(id).copied().unwrap_or(0);
+            before |= count < other_count;
+            after |= count > other_count;
+        }
+        match (before, after) {
+            (false, false) => Order::Same,
+            (true, false) => Order::Before,
+            (false, true) => Order::After,
+            (true, true) => Order::Concurrent,
+        }
+    }
+
+    /// Whether this version wins over a concurrent one: the one whose last change was made by
+    /// the instance with the greatest id, so that every instance keeps the same one.
+    pub fn wins_over(&self, other: &Version) -> bool {
+        self.origin >= other.origin
+    }
+
+    /// Whether the version includes a change.
+    pub fn includes(&self, change: &Change) -> bool {
+        self.clock.get(&change.origin).copied().unwrap_or(0) >= change.count
+    }
+
+    /// Adds the changes of another version to the clock.
+    pub fn merge(&mut self, other: &Version) {
+        for (id, other_count) in other.clock.iter() {
+            let count = self.clock.entry(id.clone()).or_insert(0);
+            *count = (*count).max(*other_count);
+        }
+    }
+}
+
+/// Sync state of a workspace, kept next to it in `<workspace>.sync`: the id of the instance
+/// and the version of its model.
+#[derive(Serialize, Deserialize)]
+pub struct SyncState {
+    #[serde(skip)]
+    path: PathBuf,
+    /// Unique id of the instance, generated once for the workspace.
+    pub instance_id: String,
+    pub version: Version,
+    /// Hash of the model the version is for, to notice the changes made while not running.
+    model_hash: String,
+    /// Hash of each note and attachment of that model, to tell which keys a change touched.
+    #[serde(default)]
+    key_hashes: BTreeMap<String, String>,
+    /// Versions of the notes discarded by conflicts, until the conflicts are resolved.
+    #[serde(default)]
+    discarded_notes: BTreeMap<String, Note>,
+}
+
+impl SyncState {
+    pub fn open(workspace_path: &Path) -> Result<SyncState, String> {
+        let mut path = workspace_path.as_os_str().to_owned();
+        path.push(".sync");
+        let path = PathBuf::from(path);
+        match fs::read_to_string(&path) {
+            Ok(text) => {
+                let mut state: SyncState = serde_json::from_str(&text)
+                    .map_err(|error| format!("invalid '{:?}' -> {}", path, error))?;
+                state.path = path;
+                Ok(state)
+            }
+            // a new workspace has no changes until files are added to it, its id is kept
+            // from now on
+            Err(error) if error.kind() == ErrorKind::NotFound => {
+                let state = SyncState {
+                    path,
+                    instance_id: uuid::Uuid::new_v4().to_string(),
+                    version: Version::default(),
+                    model_hash: hash_model(&Model::default())?,
+                    key_hashes: BTreeMap::new(),
+                    discarded_notes: BTreeMap::new(),
+                };
+                state.save()?;
+                Ok(state)
+            }
+            Err(error) => Err(format!("cannot read '{:?}' -> {}", path, error)),
+        }
+    }
+
+    /// Counts a change made by this instance to the model, as the last change of the keys it
+    /// touched.
+    pub fn record_change(&mut self, model: &Model, author: &str) -> Result<(), String> {
+        let count = self
+            .version
+            .clock
+            .entry(self.instance_id.clone())
+            .or_insert(0);
+        *count += 1;
+        let change = Change {
+            origin: self.instance_id.clone(),
+            count: *count,
+        };
+        let key_hashes = hash_keys(model)?;
+        for key in self.key_hashes.keys().chain(key_hashes.keys()) {
+            if self.key_hashes.get(key) != key_hashes.get(key) {
+                self.version.changes.insert(key.clone(), change.clone());
+            }
+        }
+        self.version.origin = self.instance_id.clone();
+        self.version.author = author.to_string();
+        self.update(model)
+    }
+
+    /// Sets the version of a model received from a peer.
+    pub fn record_received(&mut self, model: &Model, version: Version) -> Result<(), String> {
+        self.version = version;
+        self.update(model)
+    }
+
+    /// Whether the model is not the one of the version, it was then changed while the
+    /// instance wasn't running.
+    pub fn has_changed(&self, model: &Model) -> Result<bool, String> {
+        Ok(hash_model(model)? != self.model_hash)
+    }
+
+    /// Version of a note discarded by a conflict.
+    pub fn discarded_note(&self, key: &str) -> Option<&Note> {
+        self.discarded_notes.get(key)
+    }
+
+    /// Keeps the version of a note discarded by a conflict, until `forget_discarded`.
+    pub fn keep_discarded(&mut self, key: &str, note: Note) -> Result<(), String> {
+        self.discarded_notes.insert(key.to_string(), note);
+        self.save()
+    }
+
+    pub fn forget_discarded(&mut self, key: &str) -> Result<(), String> {
+        if self.discarded_notes.remove(key).is_some() {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    fn update(&mut self, model: &Model) -> Result<(), String> {
+        self.model_hash = hash_model(model)?;
+        self.key_hashes = hash_keys(model)?;
+        self.save()
+    }
+
+    fn save(&self) -> Result<(), String> {
+        let text = serde_json::to_string(self)
+            .map_err(|error| format!("cannot serialize sync state -> {}", error))?;
+        atomic_file::write(&self.path, text.as_bytes(), None, Durability::File)
+    }
+}
+
+fn hash_model(model: &Model) -> Result<String, String> {
+    // values have their keys sorted, unlike the maps of the model
+    let value = serde_json::to_value(model)
+        .map_err(|error| format!("cannot serialize model -> {}", error))?;
+    Ok(hex::encode(Sha256::digest(value.to_string().as_bytes())))
+}
+
+fn hash_keys(model: &Model) -> Result<BTreeMap<String, String>, String> {
+    let notes = model.notes.iter().map(|(key, note)| {
+        serde_json::to_string(note)
+            .map(|json| (key.clone(), hex::encode(Sha256::digest(json.as_bytes()))))
+            .map_err(|error| format!("cannot serialize note '{}' -> {}", key, error))
+    });
+    let attachments = model
+        .attachments
+        .iter()
+        .map(|(key, attachment)| Ok((key.clone(), attachment.hash.clone())));
+    notes.chain(attachments).collect()
+}
+
+/// Result of the merge of two models with concurrent changes.
+pub struct Merge {
+    pub model: Model,
+    pub version: Version,
+    /// Keys changed by both models with different contents, with whether the version of the
+    /// other model was kept.
+    pub conflicts: Vec<(String, bool)>,
+}
+
+/// Merges the concurrent changes of two models key by key: a note or attachment changed by
+/// only one of them gets that change, deletions included, and one changed by both gets the
+/// change of the instance with the greatest id, so that every instance keeps the same one.
+/// Folders of both are kept.
+pub fn merge_concurrent(
+    model: &Model,
+    version: &Version,
+    other_model: &Model,
+    other_version: &Version,
+) -> Merge {
+    let mut merged_model = model.clone();
+    let mut merged_version = if other_version.wins_over(version) {
+        other_version.clone()
+    } else {
+        version.clone()
+    };
+    merged_version.merge(if other_version.wins_over(version) {
+        version
+    } else {
+        other_version
+    });
+    merged_version.changes = version.changes.clone();
+    let mut conflicts = Vec::new();
+    let keys: BTreeSet<&String> = [model, other_model]
+        .iter()
+        .flat_map(|model| model.notes.keys().chain(model.attachments.keys()))
+        .chain(other_version.changes.keys())
+        .collect();
+    for key in keys {
+        let change = version.changes.get(key);
+        let other_change = other_version.changes.get(key);
+        let has_other_change = other_change.is_none_or(|change| version.includes(change));
+        let other_has_change = change.is_none_or(|change| other_version.includes(change));
+        let take_other = match (change, other_change) {
+            _ if change == other_change || has_other_change => false,
+            _ if other_has_change => true,
+            (Some(change), Some(other_change)) => {
+                let other_wins = other_change.origin > change.origin;
+                // a note modified while it was deleted elsewhere is kept
+                let is_deleted = |model: &Model| {
+                    !model.notes.contains_key(key) && !model.attachments.contains_key(key)
+                };
+                let take_other = match (is_deleted(model), is_deleted(other_model)) {
+                    (true, false) => true,
+                    (false, true) => false,
+                    _ => other_wins,
+                };
+                if differ(model, other_model, key) {
+                    conflicts.push((key.clone(), take_other));
+                }
+                take_other
+            }
+            _ => false,
+        };
+        if take_other {
+            take_key(&mut merged_model, other_model, key);
+            match other_change {
+                Some(other_change) => merged_version
+                    .changes
+                    .insert(key.clone(), other_change.clone()),
+                None => merged_version.changes.remove(key),
+            };
+        }
+    }
+    merged_model
+        .folders
+        .extend(other_model.folders.iter().cloned());
+    for (key, time) in other_model.tombstones.iter() {
+        let merged_time = merged_model.tombstones.entry(key.clone()).or_insert(0);
+        *merged_time = (*merged_time).max(*time);
+    }
+    let Model {
+        notes,
+        attachments,
+        tombstones,
+        ..
+    } = &mut merged_model;
+    tombstones.retain(|key, _| !notes.contains_key(key) && !attachments.contains_key(key));
+    Merge {
+        model: merged_model,
+        version: merged_version,
+        conflicts,
+    }
+}
+
+/// Whether two models have different notes or attachments at a key, both having one.
+fn differ(model: &Model, other_model: &Model, key: &str) -> bool {
+    let notes = (model.notes.get(key), other_model.notes.get(key));
+    let attachments = (model.attachments.get(key), other_model.attachments.get(key));
+    match (notes, attachments) {
+        ((Some(note), Some(other_note)), _) => !note.has_same_contents(other_note),
+        (_, (Some(attachment), Some(other_attachment))) => attachment.hash != other_attachment.hash,
+        ((Some(_), None), (None, Some(_))) | ((None, Some(_)), (Some(_), None)) => true,
+        _ => false,
+    }
+}
+
+/// Replaces the note or attachment at a key with the one of another model, or removes it.
+fn take_key(model: &mut Model, other_model: &Model, key: &str) {
+    replace(&mut model.notes, &other_model.notes, key);
+    replace(&mut model.attachments, &other_model.attachments, key);
+}
+
+fn replace<T: Clone>(
+    entries: &mut HashMap<String, T>,
+    other_entries: &HashMap<String, T>,
+    key: &str,
+) {
+    match other_entries.get(key) {
+        Some(entry) => entries.insert(key.to_string(), entry.clone()),
+        None => entries.remove(key),
+    };
+}
+
+/// Keys of the notes, attachments and folders added, changed or removed from one model to the
+/// next.
+pub fn changed_keys(model: &Model, updated_model: &Model) -> Vec<String> {
+    let mut keys = BTreeSet::new();
+    for (key, note) in model.notes.iter() {
+        if updated_model.notes.get(key) != Some(note) {
+            keys.insert(key.clone());
+        }
+    }
+    for (key, note) in updated_model.notes.iter() {
+        if model.notes.get(key) != Some(note) {
+            keys.insert(key.clone());
+        }
+    }
+    for (key, attachment) in model.attachments.iter() {
+        if updated_model.attachments.get(key) != Some(attachment) {
+            keys.insert(key.clone());
+        }
+    }
+    for (key, attachment) in updated_model.attachments.iter() {
+        if model.attachments.get(key) != Some(attachment) {
+            keys.insert(key.clone());
+        }
+    }
+    keys.extend(
+        model
+            .folders
+            .symmetric_difference(&updated_model.folders)
+            .cloned(),
+    );
+    keys.into_iter().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn version(clock: &[(&str, u64)], origin: &str) -> Version {
+        Version {
+            clock: clock
+                .iter()
+                .map(|(id, count)| (id.to_string(), *count))
+                .collect(),
+            origin: origin.to_string(),
+            ..Version::default()
+        }
+    }
+
+    /// Instance syncing its model like the sync engine does.
+    struct Instance {
+        _folder: TempDir,
+        state: SyncState,
+        model: Model,
+        conflicts: Vec<(String, bool)>,
+    }
+
+    impl Instance {
+        fn new(id: &str) -> Instance {
+            let folder = tempfile::tempdir().unwrap();
+            let mut state = SyncState::open(&folder.path().join("workspace")).unwrap();
+            state.instance_id = id.to_string();
+            Instance {
+                _folder: folder,
+                state,
+                model: Model::default(),
+                conflicts: Vec::new(),
+            }
+        }
+
+        fn write(&mut self, key: &str, body: &str) {
+            let note = Note {
+                body: body.to_string(),
+                ..Note::new()
+            };
+            self.model.notes.insert(key.to_string(), note);
+            self.state.record_change(&self.model, "test").unwrap();
+        }
+
+        fn delete(&mut self, key: &str) {
+            self.model.notes.remove(key);
+            self.state.record_change(&self.model, "test").unwrap();
+        }
+
+        /// Applies the model of another instance, returns whether it changed anything.
+        fn receive(&mut self, other: &Instance) -> bool {
+            let (model, version) = match self.state.version.compare(&other.state.version) {
+                Order::Same | Order::After => return false,
+                Order::Before => (other.model.clone(), other.state.version.clone()),
+                Order::Concurrent => {
+                    let merge = merge_concurrent(
+                        &self.model,
+                        &self.state.version,
+                        &other.model,
+                        &other.state.version,
+                    );
+                    self.conflicts.extend(merge.conflicts);
+                    (merge.model, merge.version)
+                }
+            };
+            self.model = model;
+            self.state.record_received(&self.model, version).unwrap();
+            if self.model != other.model {
+                self.state.record_change(&self.model, "test").unwrap();
+            }
+            true
+        }
+
+        fn body(&self, key: &str) -> Option<&str> {
+            self.model.notes.get(key).map(|note| note.body.as_str())
+        }
+    }
+
+    /// Two instances with the same notes, as after a sync.
+    fn synced_instances(notes: &[(&str, &str)]) -> (Instance, Instance) {
+        let mut a = Instance::new("a");
+        let mut b = Instance::new("b");
+        for (key, body) in notes {
+            a.write(key, body);
+        }
+        b.receive(&a);
+        (a, b)
+    }
+
+    #[test]
+    fn compare_versions() {
+        let version_1 = version(&[("a", 1)], "a");
+        let version_2 = version(&[("a", 2)], "a");
+        let version_3 = version(&[("a", 1), ("b", 1)], "b");
+        assert_eq!(version_1.compare(&version_1), Order::Same);
+        assert_eq!(version_1.compare(&version_2), Order::Before);
+        assert_eq!(version_2.compare(&version_1), Order::After);
+        assert_eq!(version_1.compare(&version_3), Order::Before);
+        assert_eq!(version_2.compare(&version_3), Order::Concurrent);
+        assert_eq!(Version::default().compare(&Version::default()), Order::Same);
+    }
+
+    #[test]
+    fn greatest_origin_wins() {
+        let version_a = version(&[("a", 2)], "a");
+        let version_b = version(&[("b", 1)], "b");
+        assert!(version_b.wins_over(&version_a));
+        assert!(!version_a.wins_over(&version_b));
+    }
+
+    #[test]
+    fn merge_takes_the_greatest_counts() {
+        let mut merged = version(&[("a", 2), ("b", 1)], "a");
+        merged.merge(&version(&[("b", 3), ("c", 1)], "b"));
+        assert_eq!(
+            merged.clock,
+            version(&[("a", 2), ("b", 3), ("c", 1)], "").clock
+        );
+        assert_eq!(merged.origin, "a");
+    }
+
+    #[test]
+    fn changes_are_relayed_once() {
+        let mut a = Instance::new("a");
+        let mut b = Instance::new("b");
+        let mut c = Instance::new("c");
+        a.write("note.txt", "from a");
+        assert!(b.receive(&a));
+        assert!(c.receive(&b));
+        assert_eq!(c.body("note.txt"), Some("from a"));
+        assert_eq!(c.state.version, a.state.version);
+        // the change comes back from every peer it was relayed to
+        assert!(!c.receive(&a));
+        assert!(!a.receive(&c));
+        assert!(!b.receive(&c));
+    }
+
+    #[test]
+    fn changes_stop_going_around_a_loop() {
+        let mut a = Instance::new("a");
+        let mut b = Instance::new("b");
+        let mut c = Instance::new("c");
+        a.write("note.txt", "from a");
+        b.receive(&a);
+        c.receive(&b);
+        assert!(!a.receive(&c));
+        c.write("note.txt", "from c");
+        assert!(a.receive(&c));
+        assert!(b.receive(&a));
+        assert!(!c.receive(&b));
+        assert_eq!(b.body("note.txt"), Some("from c"));
+    }
+
+    #[test]
+    fn concurrent_changes_to_different_notes_are_all_kept() {
+        let (mut a, mut b) = synced_instances(&[("x.txt", "x"), ("y.txt", "y"), ("z.txt", "z")]);
+        a.write("x.txt", "x from a");
+        a.delete("z.txt");
+        b.write("y.txt", "y from b");
+        b.write("new.txt", "new from b");
+        assert!(a.receive(&b));
+        assert!(b.receive(&a));
+        for instance in [&a, &b] {
+            assert_eq!(instance.body("x.txt"), Some("x from a"));
+            assert_eq!(instance.body("y.txt"), Some("y from b"));
+            assert_eq!(instance.body("new.txt"), Some("new from b"));
+            assert_eq!(instance.body("z.txt"), None);
+            assert!(instance.conflicts.is_empty());
+        }
+        assert!(a.model == b.model);
+        assert!(!a.receive(&b));
+    }
+
+    #[test]
+    fn concurrent_changes_to_the_same_note_keep_the_same_version() {
+        let (mut a, mut b) = synced_instances(&[("x.txt", "x"), ("y.txt", "y")]);
+        a.write("x.txt", "x from a");
+        b.write("x.txt", "x from b");
+        b.write("y.txt", "y from b");
+        assert!(a.receive(&b));
+        assert!(b.receive(&a));
+        for instance in [&a, &b] {
+            assert_eq!(instance.body("x.txt"), Some("x from b"));
+            assert_eq!(instance.body("y.txt"), Some("y from b"));
+        }
+        assert_eq!(a.conflicts, [(String::from("x.txt"), true)]);
+        // the merged model already has the version of b
+        assert!(b.conflicts.is_empty());
+
+        b.write("x.txt", "x from b again");
+        a.write("x.txt", "x from a again");
+        assert!(b.receive(&a));
+        assert_eq!(b.body("x.txt"), Some("x from b again"));
+        assert_eq!(b.conflicts, [(String::from("x.txt"), false)]);
+    }
+
+    #[test]
+    fn a_note_modified_while_deleted_elsewhere_is_kept() {
+        let (mut a, mut b) = synced_instances(&[("x.txt", "x")]);
+        a.write("x.txt", "x from a");
+        b.delete("x.txt");
+        assert!(a.receive(&b));
+        assert!(b.receive(&a));
+        assert_eq!(a.body("x.txt"), Some("x from a"));
+        assert_eq!(b.body("x.txt"), Some("x from a"));
+        assert!(a.conflicts.is_empty());
+    }
+}
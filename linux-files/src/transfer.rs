@@ -0,0 +1,529 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+use crate::chunks::{self, Chunk, ChunkStore};
+use crate::model::Model;
+use crate::networking::{ConnectionId, Message};
+use crate::note_store::is_valid_key;
+use crate::sync_state::Version;
+
+/// Model as it is sent to a peer: the contents of the notes and attachments are replaced by
+/// the hashes of their chunks, which are sent separately. Only the hashes that changed since
+/// the last manifest sent to the peer are sent, it keeps the others.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Manifest {
+    /// The model, with the bodies of its notes left empty.
+    pub model: Model,
+    /// Change to the chunks of the body of each note whose chunks changed, by key.
+    pub bodies: HashMap<String, ChunksDelta>,
+    /// Chunks of the contents of each attachment the last manifest didn't have, by hash of the
+    /// contents.
+    pub blobs: HashMap<String, Vec<String>>,
+    /// Changes the model includes.
+    #[serde(default)]
+    pub version: Version,
+}
+
+/// Chunks of a note as a change to the ones of the last manifest: its first `kept` chunks then
+/// `hashes`. Chunk boundaries stay put when appending, so only the last chunks are sent.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ChunksDelta {
+    pub kept: usize,
+    pub hashes: Vec<String>,
+}
+
+/// Content-addressed transfer of models: only the chunks a peer doesn't have yet are sent,
+/// so appending to a large note only sends its last chunks.
+///
+/// Only the chunks of the last manifest sent to and received from each peer are kept, with
+/// the chunks received since and the ones of the attachments of the local model: the others
+/// are in no model a peer can still reference. The chunks of the notes of the local model are
+/// split again from their contents each time it is sent.
+pub struct Transfer {
+    store: ChunkStore,
+    /// Hashes of the chunks each peer has, by connection: the ones of the last manifest sent
+    /// to it, which it keeps until it receives the next one.
+    peer_chunks: HashMap<ConnectionId, HashSet<String>>,
+    /// Chunks of the last manifest sent to each peer, by connection.
+    sent_lists: HashMap<ConnectionId, ChunkLists>,
+    /// Hashes of the chunks of the last manifest received from each peer and of the chunks
+    /// received since, by connection.
+    received_chunks: HashMap<ConnectionId, HashSet<String>>,
+    /// Chunks of the last manifest received from each peer, by connection.
+    received_lists: HashMap<ConnectionId, ChunkLists>,
+    /// Hashes of the chunks of the attachments of the local model, kept so that the deleted
+    /// ones can still be moved to the trash.
+    local_chunks: HashSet<String>,
+    /// Chunks of the contents of the attachments, by hash of the contents.
+    blob_chunks: HashMap<String, Vec<String>>,
+}
+
+/// Chunks of the notes and attachments of a manifest, with all the hashes.
+#[derive(Default)]
+struct ChunkLists {
+    bodies: HashMap<String, Vec<String>>,
+    blobs: HashMap<String, Vec<String>>,
+}
+
+impl Transfer {
+    pub fn new() -> Transfer {
+        Transfer {
+            store: ChunkStore::new(),
+            peer_chunks: HashMap::new(),
+            sent_lists: HashMap::new(),
+            received_chunks: HashMap::new(),
+            received_lists: HashMap::new(),
+            local_chunks: HashSet::new(),
+            blob_chunks: HashMap::new(),
+        }
+    }
+
+    /// Whether the contents of an attachment must be given to `send`, contents that were
+    /// already sent or received are known.
+    pub fn needs_blob(&self, hash: &str) -> bool {
+        !self.blob_chunks.contains_key(hash)
+    }
+
+    /// Messages sending a model to a peer: the chunks it doesn't have, then the manifest.
+    /// `blobs` has the contents, by hash, of the attachments for which `needs_blob` is true.
+    pub fn send(
+        &mut self,
+        peer: ConnectionId,
+        model: &Model,
+        version: &Version,
+        blobs: &HashMap<String, Vec<u8>>,
+    ) -> Result<Vec<Message>, String> {
+        let mut manifest = Manifest {
+            model: model.clone(),
+            bodies: HashMap::new(),
+            blobs: HashMap::new(),
+            version: version.clone(),
+        };
+        let mut lists = ChunkLists::default();
+        for (key, note) in manifest.model.notes.iter_mut() {
+            let hashes = self.store.insert(note.body.as_bytes());
+            lists.bodies.insert(key.clone(), hashes);
+            note.body = String::new();
+        }
+        for attachment in model.attachments.values() {
+            let hashes = match self.blob_hashes(&attachment.hash, blobs) {
+                Some(hashes) => hashes,
+                None => {
+                    return Err(format!(
+                        "missing contents of attachment '{}'",
+                        attachment.hash
+                    ))
+                }
+            };
+            lists.blobs.insert(attachment.hash.clone(), hashes);
+        }
+        let previous_lists = self.sent_lists.remove(&peer).unwrap_or_default();
+        manifest.bodies = changed_lists(&lists.bodies, &previous_lists.bodies)
+            .into_iter()
+            .map(|(key, hashes)| {
+                let previous_hashes = previous_lists
+                    .bodies
+                    .get(&key)
+                    .map_or(&[][..], |hashes| hashes);
+                let kept = hashes
+                    .iter()
+                    .zip(previous_hashes)
+                    .take_while(|(hash, previous_hash)| hash == previous_hash)
+                    .count();
+                let delta = ChunksDelta {
+                    kept,
+                    hashes: hashes[kept..].to_vec(),
+                };
+                (key, delta)
+            })
+            .collect();
+        manifest.blobs = changed_lists(&lists.blobs, &previous_lists.blobs);
+
+        let previous_chunks = self.peer_chunks.remove(&peer).unwrap_or_default();
+        let mut peer_chunks = HashSet::new();
+        let mut messages = Vec::new();
+        for hash in lists.bodies.values().chain(lists.blobs.values()).flatten() {
+            if peer_chunks.insert(hash.clone()) && !previous_chunks.contains(hash) {
+                messages.push(Message::Chunk(Chunk {
+                    hash: hash.clone(),
+                    data: self.store.get(hash).unwrap().clone(),
+                }));
+            }
+        }
+        self.peer_chunks.insert(peer, peer_chunks);
+        self.sent_lists.insert(peer, lists);
+        self.evict();
+        messages.push(Message::Manifest(Box::new(manifest)));
+        Ok(messages)
+    }
+
+    /// Handles a chunk or manifest from a peer, returns the model and its version once its
+    /// manifest is received. The contents of its attachments are then available with `blob`.
+    /// A manifest with a key that would leave the workspace is refused as a whole.
+    pub fn receive(
+        &mut self,
+        peer: ConnectionId,
+        message: Message,
+    ) -> Result<Option<(Model, Version)>, String> {
+        match message {
+            Message::Chunk(chunk) => {
+                let hash = chunk.hash.clone();
+                self.store.insert_chunk(chunk)?;
+                self.received_chunks.entry(peer).or_default().insert(hash);
+                Ok(None)
+            }
+            Message::Hello(_)
+            | Message::Rejected(_)
+            | Message::Proof(_)
+            | Message::Pairing(_)
+            | Message::Sealed(_)
+            | Message::SealedChunk(_) => Err(String::from(
+                "unexpected handshake, rejection, pairing or sealed message",
+            )),
+            Message::Manifest(manifest) => {
+                let mut model = manifest.model;
+                if let Some(key) = invalid_key(&model) {
+                    return Err(format!("invalid key '{}' in manifest", key));
+                }
+                let mut previous_lists = self.received_lists.remove(&peer).unwrap_or_default();
+                let mut lists = ChunkLists::default();
+                let mut manifest_chunks = HashSet::new();
+                for (key, note) in model.notes.iter_mut() {
+                    let previous_hashes = previous_lists.bodies.remove(key);
+                    let hashes = match (manifest.bodies.get(key), previous_hashes) {
+                        (Some(delta), previous_hashes) => {
+                            let mut hashes = previous_hashes.unwrap_or_default();
+                            if delta.kept > hashes.len() {
+                                return Err(format!("missing chunks of note '{}'", key));
+                            }
+                            hashes.truncate(delta.kept);
+                            hashes.extend(delta.hashes.iter().cloned());
+                            hashes
+                        }
+                        (None, Some(previous_hashes)) => previous_hashes,
+                        (None, None) => return Err(format!("missing chunks of note '{}'", key)),
+                    };
+                    manifest_chunks.extend(hashes.iter().cloned());
+                    note.body = String::from_utf8(self.store.assemble(&hashes)?)
+                        .map_err(|error| format!("invalid body of note '{}' -> {}", key, error))?;
+                    lists.bodies.insert(key.clone(), hashes);
+                }
+                for attachment in model.attachments.values() {
+                    let hashes = match manifest.blobs.get(&attachment.hash) {
+                        Some(hashes) => hashes.clone(),
+                        None => match previous_lists.blobs.remove(&attachment.hash) {
+                            Some(hashes) => hashes,
+                            None => {
+                                return Err(format!(
+                                    "missing chunks of attachment '{}'",
+                                    attachment.hash
+                                ))
+                            }
+                        },
+                    };
+                    manifest_chunks.extend(hashes.iter().cloned());
+                    self.blob_chunks
+                        .insert(attachment.hash.clone(), hashes.clone());
+                    lists.blobs.insert(attachment.hash.clone(), hashes);
+                }
+                self.received_chunks.insert(peer, manifest_chunks);
+                self.received_lists.insert(peer, lists);
+                self.evict();
+                Ok(Some((model, manifest.version)))
+            }
+        }
+    }
+
+    /// Chunks of the contents of an attachment, split from `blobs` when they are not known yet.
+    fn blob_hashes(&mut self, hash: &str, blobs: &HashMap<String, Vec<u8>>) -> Option<Vec<String>> {
+        if let Some(hashes) = self.blob_chunks.get(hash) {
+            return Some(hashes.clone());
+        }
+        let hashes = self.store.insert(blobs.get(hash)?);
+        self.blob_chunks.insert(hash.to_string(), hashes.clone());
+        Some(hashes)
+    }
+
+    /// Keeps the contents of the attachments of the local model until they are not in it
+    /// anymore, so that `blob` still gives the ones deleted from the workspace. `blobs` has the
+    /// contents, by hash, of the attachments for which `needs_blob` is true, the attachments
+    /// missing from it are not kept.
+    pub fn keep_attachments(&mut self, model: &Model, blobs: &HashMap<String, Vec<u8>>) {
+        let mut local_chunks = HashSet::new();
+        for attachment in model.attachments.values() {
+            if let Some(hashes) = self.blob_hashes(&attachment.hash, blobs) {
+                local_chunks.extend(hashes);
+            }
+        }
+        self.local_chunks = local_chunks;
+        self.evict();
+    }
+
+    /// Forgets the chunks a peer has once its connection is closed.
+    pub fn forget_peer(&mut self, peer: ConnectionId) {
+        self.peer_chunks.remove(&peer);
+        self.sent_lists.remove(&peer);
+        self.received_chunks.remove(&peer);
+        self.received_lists.remove(&peer);
+        self.evict();
+    }
+
+    /// Drops the chunks that are in no last manifest nor in an attachment of the local model,
+    /// and the attachments that lost some of their chunks: their contents are given to `send`
+    /// again.
+    fn evict(&mut self) {
+        let kept_chunks: HashSet<&str> = self
+            .peer_chunks
+            .values()
+            .chain(self.received_chunks.values())
+            .flatten()
+            .chain(self.local_chunks.iter())
+            .map(|hash| hash.as_str())
+            .collect();
+        self.store.retain(|hash| kept_chunks.contains(hash));
+        let store = &self.store;
+        self.blob_chunks
+            .retain(|_, hashes| hashes.iter().all(|hash| store.contains(hash)));
+    }
+
+    /// Contents of an attachment that was sent, received or kept.
+    pub fn blob(&self, hash: &str) -> Result<Vec<u8>, String> {
+        let hashes = match self.blob_chunks.get(hash) {
+            Some(hashes) => hashes,
+            None => return Err(format!("unknown attachment '{}'", hash)),
+        };
+        let data = self.store.assemble(hashes)?;
+        if chunks::hash(&data) != hash {
+            return Err(format!(
+                "contents of attachment '{}' don't match its hash",
+                hash
+            ));
+        }
+        Ok(data)
+    }
+}
+
+/// Lists that are not in the previous ones, or differ from them.
+fn changed_lists(
+    lists: &HashMap<String, Vec<String>>,
+    previous_lists: &HashMap<String, Vec<String>>,
+) -> HashMap<String, Vec<String>> {
+    lists
+        .iter()
+        .filter(|(key, hashes)| previous_lists.get(*key) != Some(*hashes))
+        .map(|(key, hashes)| (key.clone(), hashes.clone()))
+        .collect()
+}
+
+/// First key of a model that isn't a relative path staying in the workspace.
+fn invalid_key(model: &Model) -> Option<&str> {
+    model
+        .notes
+        .keys()
+        .chain(model.attachments.keys())
+        .chain(model.folders.iter())
+        .chain(model.tombstones.keys())
+        .map(|key| key.as_str())
+        .find(|key| !is_valid_key(key))
+}
+
+impl Default for Transfer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{Attachment, Note};
+
+    fn model_with_body(body: &str) -> Model {
+        let mut model = Model::default();
+        model.notes.insert(
+            String::from("note.txt"),
+            Note {
+                body: body.to_string(),
+                ..Note::new()
+            },
+        );
+        model
+    }
+
+    fn send(transfer: &mut Transfer, peer: ConnectionId, model: &Model) -> Vec<Message> {
+        transfer
+            .send(peer, model, &Version::default(), &HashMap::new())
+            .unwrap()
+    }
+
+    /// Receives what `send` gives for a model, the result of the manifest.
+    fn transfer(model: &Model) -> Result<Option<(Model, Version)>, String> {
+        let messages = Transfer::new()
+            .send(0, model, &Version::default(), &HashMap::new())
+            .unwrap();
+        let mut receiver = Transfer::new();
+        let mut result = Ok(None);
+        for message in messages {
+            result = receiver.receive(0, message);
+        }
+        result
+    }
+
+    #[test]
+    fn receive_gives_back_the_sent_model() {
+        let mut model = Model::default();
+        model
+            .notes
+            .insert(String::from("projects/notes.txt"), Note::new());
+        model.folders.insert(String::from("projects"));
+        let (received_model, _) = transfer(&model).unwrap().unwrap();
+        assert!(received_model == model);
+    }
+
+    #[test]
+    fn receive_refuses_keys_leaving_the_workspace() {
+        for key in [
+            "../escape.txt",
+            "notes/../../escape.txt",
+            "/etc/passwd",
+            "",
+            "a//b",
+        ] {
+            let mut model = Model::default();
+            model.notes.insert(String::from("kept.txt"), Note::new());
+            model.tombstones.insert(key.to_string(), 1);
+            assert!(transfer(&model).is_err(), "'{}' was accepted", key);
+        }
+    }
+
+    #[test]
+    fn send_only_sends_chunks_missing_from_the_last_manifest() {
+        let mut transfer = Transfer::new();
+        assert_eq!(send(&mut transfer, 0, &model_with_body("first")).len(), 2);
+        assert_eq!(send(&mut transfer, 0, &model_with_body("first")).len(), 1);
+        assert_eq!(send(&mut transfer, 0, &model_with_body("second")).len(), 2);
+        // the peer dropped the chunk once it got a manifest without it
+        assert_eq!(send(&mut transfer, 0, &model_with_body("first")).len(), 2);
+    }
+
+    #[test]
+    fn manifests_only_have_the_chunks_of_changed_notes() {
+        let mut model = model_with_body("first");
+        model.notes.insert(String::from("other.txt"), Note::new());
+        let mut sender = Transfer::new();
+        let mut receiver = Transfer::new();
+        let mut receive = |messages: Vec<Message>| {
+            let mut result = None;
+            for message in messages {
+                result = receiver.receive(0, message).unwrap();
+            }
+            result.unwrap().0
+        };
+        receive(send(&mut sender, 0, &model));
+        model.notes.get_mut("note.txt").unwrap().body = String::from("second");
+        let messages = send(&mut sender, 0, &model);
+        match messages.last() {
+            Some(Message::Manifest(manifest)) => {
+                assert_eq!(manifest.bodies.keys().collect::<Vec<_>>(), ["note.txt"])
+            }
+            _ => panic!("the last message isn't a manifest"),
+        }
+        assert!(receive(messages) == model);
+    }
+
+    #[test]
+    fn manifests_only_have_the_new_chunks_of_appended_notes() {
+        let mut body: String = (0..20_000).map(|line| format!("line {}\n", line)).collect();
+        let mut model = model_with_body(&body);
+        let mut sender = Transfer::new();
+        let mut receiver = Transfer::new();
+        for message in send(&mut sender, 0, &model) {
+            receiver.receive(0, message).unwrap();
+        }
+        let chunk_count = chunks::split(body.as_bytes()).len();
+        body.push_str("appended\n");
+        model.notes.get_mut("note.txt").unwrap().body = body;
+        let mut received_model = None;
+        for message in send(&mut sender, 0, &model) {
+            if let Message::Manifest(manifest) = &message {
+                let delta = &manifest.bodies["note.txt"];
+                assert_eq!(delta.kept, chunk_count - 1);
+                assert_eq!(delta.hashes.len(), 1);
+            }
+            received_model = receiver.receive(0, message).unwrap();
+        }
+        assert!(received_model.unwrap().0 == model);
+    }
+
+    #[test]
+    fn chunks_in_no_last_manifest_are_evicted() {
+        let mut transfer = Transfer::new();
+        send(&mut transfer, 0, &model_with_body("first"));
+        send(&mut transfer, 1, &model_with_body("first"));
+        send(&mut transfer, 0, &model_with_body("second"));
+        assert!(transfer.store.contains(&chunks::hash(b"first")));
+        send(&mut transfer, 1, &model_with_body("second"));
+        assert!(!transfer.store.contains(&chunks::hash(b"first")));
+        assert!(transfer.store.contains(&chunks::hash(b"second")));
+    }
+
+    #[test]
+    fn chunks_of_the_last_received_manifest_are_kept_until_the_peer_leaves() {
+        let mut transfer = Transfer::new();
+        for message in send(&mut Transfer::new(), 0, &model_with_body("theirs")) {
+            transfer.receive(1, message).unwrap();
+        }
+        send(&mut transfer, 0, &model_with_body("ours"));
+        assert!(transfer.store.contains(&chunks::hash(b"theirs")));
+        transfer.forget_peer(1);
+        assert!(!transfer.store.contains(&chunks::hash(b"theirs")));
+        assert!(transfer.store.contains(&chunks::hash(b"ours")));
+    }
+
+    fn model_with_attachment(data: &[u8]) -> Model {
+        let mut model = Model::default();
+        model.attachments.insert(
+            String::from("image.png"),
+            Attachment {
+                hash: chunks::hash(data),
+                size: data.len() as u64,
+                media_type: String::from("image/png"),
+            },
+        );
+        model
+    }
+
+    #[test]
+    fn evicted_attachments_need_their_contents_again() {
+        let data = b"attachment".to_vec();
+        let hash = chunks::hash(&data);
+        let mut transfer = Transfer::new();
+        let blobs = HashMap::from([(hash.clone(), data.clone())]);
+        transfer
+            .send(
+                0,
+                &model_with_attachment(&data),
+                &Version::default(),
+                &blobs,
+            )
+            .unwrap();
+        assert!(!transfer.needs_blob(&hash));
+        send(&mut transfer, 0, &Model::default());
+        assert!(transfer.needs_blob(&hash));
+    }
+
+    #[test]
+    fn attachments_of_the_local_model_are_kept_until_they_leave_it() {
+        let data = b"never sent".to_vec();
+        let hash = chunks::hash(&data);
+        let mut transfer = Transfer::new();
+        let blobs = HashMap::from([(hash.clone(), data.clone())]);
+        transfer.keep_attachments(&model_with_attachment(&data), &blobs);
+        send(&mut transfer, 0, &Model::default());
+        transfer.forget_peer(0);
+        // deleted from the workspace, it is still there to be moved to the trash
+        assert_eq!(transfer.blob(&hash).unwrap(), data);
+        transfer.keep_attachments(&Model::default(), &HashMap::new());
+        assert!(transfer.blob(&hash).is_err());
+    }
+}
@@ -0,0 +1,352 @@
+use std::collections::{BTreeSet, HashMap};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+use crate::model::{self, Model};
+use crate::note_file;
+use crate::note_store::{seconds_since_epoch, NoteStore};
+use crate::transfer::Transfer;
+
+/// Folder of the workspace where deleted notes and attachments are kept, at their key.
+pub const TRASH_FOLDER: &str = ".trash";
+
+/// How long deleted notes are kept by default.
+pub const DEFAULT_RETENTION: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// Time between two checks for files to delete from the trash.
+pub const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Key of a note or attachment in the trash.
+pub fn trash_key(key: &str) -> String {
+    format!("{}/{}", TRASH_FOLDER, key)
+}
+
+/// Key a note or attachment of the trash had before it was deleted, `None` for keys outside of
+/// the trash.
+pub fn original_key(key: &str) -> Option<&str> {
+    key.strip_prefix(TRASH_FOLDER)?.strip_prefix('/')
+}
+
+/// Moves to the trash the notes and attachments of `previous_model` that are not in `model`
+/// anymore, since they were deleted from the store: their copy is written from the previous
+/// model, or from the contents the transfer kept for attachments, and added to `model` with a
+/// tombstone.
+///
+/// The models are the ones before and after a batch of changes of the store. A note or
+/// attachment deleted while a new one with the same contents was created in the same batch was
+/// moved and is not trashed, each new one standing for a single move.
+pub fn trash_deleted(
+    store: &mut dyn NoteStore,
+    previous_model: &Model,
+    model: &mut Model,
+    transfer: &Transfer,
+) {
+    let time = seconds_since_epoch(SystemTime::now());
+    let trashed_notes = deleted_keys(&previous_model.notes, &model.notes, |note, other| {
+        note.title == other.title && note.body == other.body
+    });
+    let trashed_attachments = deleted_keys(
+        &previous_model.attachments,
+        &model.attachments,
+        |attachment, other| attachment.hash == other.hash,
+    );
+    for key in trashed_notes {
+        let note = &previous_model.notes[key];
+        let mut trashed_note = note.clone();
+        trashed_note.modified = time;
+        let result = note_file::serialize(&trashed_note).and_then(|text| {
+            store.write(
+                &trash_key(key),
+                text.as_bytes(),
+                Some(UNIX_EPOCH + Duration::from_secs(time)),
+            )
+        });
+        match result {
+            Ok(()) => {
+                model.notes.insert(trash_key(key), trashed_note);
+                add_tombstone(model, key, time);
+            }
+            Err(error) => warn!(%key, %error, "could not move to the trash"),
+        }
+    }
+    for key in trashed_attachments {
+        let attachment = &previous_model.attachments[key];
+        let result = transfer.blob(&attachment.hash).and_then(|data| {
+            store.write(
+                &trash_key(key),
+                &data,
+                Some(UNIX_EPOCH + Duration::from_secs(time)),
+            )
+        });
+        match result {
+            Ok(()) => {
+                model.attachments.insert(trash_key(key), attachment.clone());
+                add_tombstone(model, key, time);
+            }
+            Err(error) => warn!(%key, %error, "could not move to the trash"),
+        }
+    }
+}
+
+/// Keys of the entries deleted from `previous_entries`, except the ones moved to a new key
+/// with the same contents.
+fn deleted_keys<'a, T>(
+    previous_entries: &'a HashMap<String, T>,
+    entries: &HashMap<String, T>,
+    same_contents: impl Fn(&T, &T) -> bool,
+) -> Vec<&'a String> {
+    let mut move_targets: BTreeSet<&String> = entries
+        .keys()
+        .filter(|key| !previous_entries.contains_key(*key))
+        .collect();
+    let mut deleted_keys: Vec<&String> = previous_entries
+        .keys()
+        .filter(|key| !entries.contains_key(*key) && original_key(key).is_none())
+        .collect();
+    deleted_keys.sort();
+    deleted_keys
+        .into_iter()
+        .filter(|key| {
+            let target = move_targets
+                .iter()
+                .copied()
+                .find(|target| same_contents(&previous_entries[*key], &entries[*target]));
+            // each new entry is the target of a single move
+            target.map(|target| move_targets.remove(target)).is_none()
+        })
+        .collect()
+}
+
+/// Adds a tombstone and the folders of the trash holding the deleted file.
+fn add_tombstone(model: &mut Model, key: &str, time: u64) {
+    model.tombstones.insert(key.to_string(), time);
+    let mut folder = model::parent_folder(&trash_key(key)).to_string();
+    while !folder.is_empty() {
+        let parent = model::parent_folder(&folder).to_string();
+        model.folders.insert(folder);
+        folder = parent;
+    }
+}
+
+/// Makes the tombstones of the model match its trash: one for each file of the trash whose
+/// original key is free, dated with the modification time of the file when it is new.
+pub fn update_tombstones(model: &mut Model, store: &dyn NoteStore) {
+    let trashed_keys: Vec<String> = model
+        .notes
+        .keys()
+        .chain(model.attachments.keys())
+        .filter_map(|key| original_key(key))
+        .filter(|key| !model.notes.contains_key(*key) && !model.attachments.contains_key(*key))
+        .map(|key| key.to_string())
+        .collect();
+    model.tombstones.retain(|key, _| trashed_keys.contains(key));
+    for key in trashed_keys {
+        if model.tombstones.contains_key(&key) {
+            continue;
+        }
+        let time = match model.notes.get(&trash_key(&key)) {
+            Some(note) => note.modified,
+            None => match store.read(&trash_key(&key)) {
+                Ok(file) => file.modified.map(seconds_since_epoch).unwrap_or(0),
+                Err(error) => {
+                    warn!(key = %trash_key(&key), %error, "could not read");
+                    continue;
+                }
+            },
+        };
+        model.tombstones.insert(key, time);
+    }
+}
+
+/// Removes from a model received from the peer the notes that were deleted here after their
+/// last modification, keeping the trash and tombstones of `model` for them. Returns whether
+/// there were any, the peer then needs the model back.
+pub fn remove_stale_notes(model: &Model, received_model: &mut Model) -> bool {
+    let stale_keys: Vec<String> = received_model
+        .notes
+        .iter()
+        .filter(|(key, note)| {
+            model
+                .tombstones
+                .get(*key)
+                .map(|time| note.modified < *time)
+                .unwrap_or(false)
+        })
+        .map(|(key, _)| key.clone())
+        .collect();
+    for key in stale_keys.iter() {
+        received_model.notes.remove(key);
+        let time = model.tombstones[key];
+        if let Some(trashed_note) = model.notes.get(&trash_key(key)) {
+            received_model
+                .notes
+                .insert(trash_key(key), trashed_note.clone());
+            add_tombstone(received_model, key, time);
+        }
+    }
+    !stale_keys.is_empty()
+}
+
+/// Deletes the files of the trash that were deleted more than `retention` ago. Returns whether
+/// there were any.
+pub fn purge(store: &mut dyn NoteStore, model: &mut Model, retention: Duration) -> bool {
+    let limit = seconds_since_epoch(SystemTime::now()).saturating_sub(retention.as_secs());
+    // files of the trash whose original key was used again have no tombstone, notes are then
+    // dated by their modification time
+    let expired_keys: Vec<String> = model
+        .notes
+        .keys()
+        .chain(model.attachments.keys())
+        .filter_map(|key| original_key(key))
+        .filter(|key| {
+            let time = match model.tombstones.get(*key) {
+                Some(time) => Some(*time),
+                None => model.notes.get(&trash_key(key)).map(|note| note.modified),
+            };
+            time.map(|time| time < limit).unwrap_or(false)
+        })
+        .map(|key| key.to_string())
+        .collect();
+    for key in expired_keys.iter() {
+        match store.delete(&trash_key(key)) {
+            Ok(()) => {
+                model.remove(&trash_key(key));
+                model.tombstones.remove(key);
+            }
+            Err(error) => warn!(%key, %error, "could not empty from the trash"),
+        }
+    }
+    !expired_keys.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_store::MemoryStore;
+    use crate::model::Note;
+
+    fn model_with_notes(notes: &[(&str, &str)]) -> Model {
+        let mut model = Model::default();
+        for (key, body) in notes {
+            let note = Note {
+                title: String::from("Title"),
+                body: body.to_string(),
+                ..Note::new()
+            };
+            model.notes.insert(key.to_string(), note);
+        }
+        model
+    }
+
+    /// Keys of the trash once the notes of `previous_notes` became the ones of `notes`.
+    fn trashed_keys(previous_notes: &[(&str, &str)], notes: &[(&str, &str)]) -> Vec<String> {
+        let mut store = MemoryStore::new();
+        let mut model = model_with_notes(notes);
+        trash_deleted(
+            &mut store,
+            &model_with_notes(previous_notes),
+            &mut model,
+            &Transfer::new(),
+        );
+        let mut keys: Vec<String> = model
+            .notes
+            .keys()
+            .filter(|key| original_key(key).is_some())
+            .cloned()
+            .collect();
+        keys.sort();
+        for key in keys.iter() {
+            assert!(store.read(key).is_ok());
+            assert!(model.tombstones.contains_key(original_key(key).unwrap()));
+        }
+        keys
+    }
+
+    #[test]
+    fn deleted_notes_are_trashed_unless_moved() {
+        assert_eq!(
+            trashed_keys(
+                &[("a.txt", "same"), ("b.txt", "same")],
+                &[("b.txt", "same")]
+            ),
+            [".trash/a.txt"]
+        );
+        assert!(trashed_keys(&[("a.txt", "same")], &[("moved/a.txt", "same")]).is_empty());
+        assert_eq!(
+            trashed_keys(&[("a.txt", "same")], &[("c.txt", "other")]),
+            [".trash/a.txt"]
+        );
+        // a new note is the target of a single move
+        assert_eq!(
+            trashed_keys(
+                &[("a.txt", "same"), ("b.txt", "same")],
+                &[("c.txt", "same")]
+            ),
+            [".trash/b.txt"]
+        );
+    }
+
+    #[test]
+    fn expired_files_are_purged() {
+        let now = seconds_since_epoch(SystemTime::now());
+        let mut store = MemoryStore::new();
+        let mut model = model_with_notes(&[(".trash/old.txt", "old"), (".trash/new.txt", "new")]);
+        for key in [".trash/old.txt", ".trash/new.txt"] {
+            store.write(key, b"Title\n\nbody", None).unwrap();
+        }
+        model
+            .tombstones
+            .insert(String::from("old.txt"), now - 3 * 60 * 60);
+        model.tombstones.insert(String::from("new.txt"), now - 60);
+
+        assert!(purge(&mut store, &mut model, Duration::from_secs(60 * 60)));
+        assert!(store.read(".trash/old.txt").is_err());
+        assert!(!model.notes.contains_key(".trash/old.txt"));
+        assert!(!model.tombstones.contains_key("old.txt"));
+        assert!(store.read(".trash/new.txt").is_ok());
+        assert!(model.tombstones.contains_key("new.txt"));
+        assert!(!purge(&mut store, &mut model, Duration::from_secs(60 * 60)));
+    }
+
+    #[test]
+    fn tombstones_follow_the_trash() {
+        let store = MemoryStore::new();
+        let mut model = model_with_notes(&[(".trash/deleted.txt", "deleted")]);
+        model.notes.get_mut(".trash/deleted.txt").unwrap().modified = 100;
+        update_tombstones(&mut model, &store);
+        assert_eq!(model.tombstones.get("deleted.txt"), Some(&100));
+
+        // the key is used again
+        model.notes.insert(String::from("deleted.txt"), Note::new());
+        update_tombstones(&mut model, &store);
+        assert!(model.tombstones.is_empty());
+    }
+
+    #[test]
+    fn notes_deleted_here_after_their_last_change_stay_deleted() {
+        let mut model = model_with_notes(&[(".trash/deleted.txt", "deleted")]);
+        model.tombstones.insert(String::from("deleted.txt"), 100);
+        let mut received_model = model_with_notes(&[("deleted.txt", "deleted")]);
+        received_model
+            .notes
+            .get_mut("deleted.txt")
+            .unwrap()
+            .modified = 50;
+
+        assert!(remove_stale_notes(&model, &mut received_model));
+        assert!(!received_model.notes.contains_key("deleted.txt"));
+        assert!(received_model.notes.contains_key(".trash/deleted.txt"));
+        assert_eq!(received_model.tombstones.get("deleted.txt"), Some(&100));
+        assert!(received_model.folders.contains(TRASH_FOLDER));
+
+        // modified after it was deleted here, it comes back
+        let mut received_model = model_with_notes(&[("deleted.txt", "changed")]);
+        received_model
+            .notes
+            .get_mut("deleted.txt")
+            .unwrap()
+            .modified = 200;
+        assert!(!remove_stale_notes(&model, &mut received_model));
+        assert!(received_model.notes.contains_key("deleted.txt"));
+    }
+}
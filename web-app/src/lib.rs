@@ -1,15 +1,59 @@
 pub mod model;
 
+use base64::Engine;
 use seed::{prelude::*, *};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeSet, HashMap};
+use string_join::Join;
+
+/// Author of the revisions made in the web app.
+const AUTHOR: &str = "web app";
+
+/// Edits made within this many seconds of the last revision of a note are part of it.
+const REVISION_INTERVAL: u64 = 60;
+
+/// Image embedded in the example note of the `projects` folder.
+const EXAMPLE_IMAGE: &str = r##"<svg xmlns="http://www.w3.org/2000/svg" width="160" height="60">
+<rect x="1" y="1" width="60" height="40" fill="#cde" stroke="#345"/>
+<rect x="99" y="19" width="60" height="40" fill="#edc" stroke="#543"/>
+<line x1="61" y1="21" x2="99" y2="39" stroke="#333"/>
+</svg>
+"##;
 
 // ------ ------
 //     Init
 // ------ ------
 
-fn init(_: Url, _: &mut impl Orders<Msg>) -> Model {
+fn init(_: Url, orders: &mut impl Orders<Msg>) -> Model {
+    let mut notes = model::Model::new();
+    if let Some(note) = notes.notes.get_mut("projects/note_3.txt") {
+        note.body.push_str("\n\n![A diagram](diagram.svg)");
+    }
+    let time = now();
+    let mut history: Vec<model::Revision> = notes
+        .notes
+        .iter()
+        .map(|(key, note)| model::Revision {
+            key: key.clone(),
+            author: AUTHOR.to_string(),
+            time,
+            note: Some(note.clone()),
+        })
+        .collect();
+    history.sort_by(|a, b| a.key.cmp(&b.key));
+    orders.send_msg(Msg::AttachmentAdded {
+        key: "projects/diagram.svg".to_string(),
+        media_type: "image/svg+xml".to_string(),
+        data: EXAMPLE_IMAGE.as_bytes().to_vec(),
+    });
     Model {
-        notes: model::Model::new(),
+        notes,
         selected_note_filename: "note_1.txt".to_string(),
+        selected_tag: None,
+        collapsed_folders: BTreeSet::new(),
+        blobs: HashMap::new(),
+        history,
+        selected_revision: None,
     }
 }
 
@@ -20,6 +64,15 @@ fn init(_: Url, _: &mut impl Orders<Msg>) -> Model {
 struct Model {
     notes: model::Model,
     selected_note_filename: String,
+    /// Only the notes with this tag are listed, all notes are listed if `None`.
+    selected_tag: Option<String>,
+    collapsed_folders: BTreeSet<String>,
+    /// Contents of the attachments, by hash.
+    blobs: HashMap<String, Vec<u8>>,
+    /// Revisions of every note, oldest first.
+    history: Vec<model::Revision>,
+    /// Index in `history` of the revision whose changes are shown.
+    selected_revision: Option<usize>,
 }
 
 // ------ ------
@@ -29,41 +82,155 @@ struct Model {
 enum Msg {
     TitleChanged(String),
     ContentsChanged(String),
-    NoteSelected(String)
+    TagsChanged(String),
+    PinnedToggled,
+    NoteSelected(String),
+    TagSelected(Option<String>),
+    FolderToggled(String),
+    RevisionSelected(Option<usize>),
+    RevisionRestored(usize),
+    /// Files chosen to be added as attachments, next to the selected note.
+    FilesChosen(Vec<web_sys::File>),
+    AttachmentAdded {
+        key: String,
+        media_type: String,
+        data: Vec<u8>,
+    },
 }
 
-fn update(msg: Msg, model: &mut Model, _: &mut impl Orders<Msg>) {
+fn update(msg: Msg, model: &mut Model, orders: &mut impl Orders<Msg>) {
     match msg {
         Msg::TitleChanged(new_title) => {
-            let current_note = &model
-                .notes
-                .notes[&model.selected_note_filename];
-            let mut updated_note = current_note.clone();
-            updated_note.title = new_title;
-            *model
-                .notes
-                .notes
-                .entry(model.selected_note_filename.to_string())
-                .or_insert(model::Note::new()) = updated_note;
+            update_selected_note(model, |note| note.title = new_title);
         }
         Msg::ContentsChanged(new_contents) => {
-            let current_note = &model
-                .notes
-                .notes[&model.selected_note_filename];
-            let mut updated_note = current_note.clone();
-            updated_note.body = new_contents;
-            *model
-                .notes
-                .notes
-                .entry(model.selected_note_filename.to_string())
-                .or_insert(model::Note::new()) = updated_note;
+            update_selected_note(model, |note| note.body = new_contents);
+        }
+        Msg::TagsChanged(new_tags) => {
+            update_selected_note(model, |note| note.tags = parse_tags(&new_tags));
+        }
+        Msg::PinnedToggled => {
+            update_selected_note(model, |note| note.pinned = !note.pinned);
         }
         Msg::NoteSelected(filename) => {
-            model.selected_note_filename = filename
+            model.selected_note_filename = filename;
+            model.selected_revision = None;
+        }
+        Msg::TagSelected(tag) => model.selected_tag = tag,
+        Msg::FolderToggled(folder) => {
+            if !model.collapsed_folders.remove(&folder) {
+                model.collapsed_folders.insert(folder);
+            }
+        }
+        Msg::RevisionSelected(index) => model.selected_revision = index,
+        Msg::RevisionRestored(index) => {
+            if let Some(restored_note) = model.history[index].note.clone() {
+                update_selected_note(model, |note| {
+                    note.title = restored_note.title;
+                    note.body = restored_note.body;
+                    note.tags = restored_note.tags;
+                    note.pinned = restored_note.pinned;
+                });
+                model.selected_revision = None;
+            }
+        }
+        Msg::FilesChosen(files) => {
+            let folder = model::parent_folder(&model.selected_note_filename).to_string();
+            for file in files {
+                let key = resolve_path(&folder, &file.name());
+                let media_type = match file.type_() {
+                    media_type if media_type.is_empty() => "application/octet-stream".to_string(),
+                    media_type => media_type,
+                };
+                orders.perform_cmd(async move {
+                    let buffer = JsFuture::from(file.array_buffer()).await.ok()?;
+                    Some(Msg::AttachmentAdded {
+                        key,
+                        media_type,
+                        data: js_sys::Uint8Array::new(&buffer).to_vec(),
+                    })
+                });
+            }
+        }
+        Msg::AttachmentAdded {
+            key,
+            media_type,
+            data,
+        } => add_attachment(model, key, media_type, data),
+    }
+}
+
+/// Adds an attachment with its contents, replacing the one with the same key.
+fn add_attachment(model: &mut Model, key: String, media_type: String, data: Vec<u8>) {
+    let hash = format!("{:x}", Sha256::digest(&data));
+    model.notes.attachments.insert(
+        key,
+        model::Attachment {
+            hash: hash.clone(),
+            size: data.len() as u64,
+            media_type,
+        },
+    );
+    model.blobs.insert(hash, data);
+}
+
+/// Applies `change` to the selected note and updates its modification time.
+fn update_selected_note(model: &mut Model, change: impl FnOnce(&mut model::Note)) {
+    let note = model
+        .notes
+        .notes
+        .entry(model.selected_note_filename.to_string())
+        .or_default();
+    change(note);
+    note.modified = now();
+    record_revision(model);
+}
+
+/// Records the selected note in its history, edits following each other closely being merged
+/// into one revision. The first revision of a note is never merged, so that it can always be
+/// restored.
+fn record_revision(model: &mut Model) {
+    let key = &model.selected_note_filename;
+    let note = model.notes.notes.get(key).cloned();
+    let time = now();
+    let indexes: Vec<usize> = (0..model.history.len())
+        .filter(|index| model.history[*index].key == *key)
+        .collect();
+    if let Some(last) = indexes.last() {
+        if model.history[*last].has_note(note.as_ref()) {
+            return;
+        }
+    }
+    match indexes.as_slice() {
+        [.., _, last]
+            if model.history[*last].author == AUTHOR
+                && time.saturating_sub(model.history[*last].time) < REVISION_INTERVAL =>
+        {
+            model.history[*last].note = note;
+            model.history[*last].time = time;
         }
+        _ => model.history.push(model::Revision {
+            key: key.clone(),
+            author: AUTHOR.to_string(),
+            time,
+            note,
+        }),
     }
 }
 
+/// Current time, in seconds since the Unix epoch.
+fn now() -> u64 {
+    (js_sys::Date::now() / 1000.0) as u64
+}
+
+/// Parses a comma-separated list of tags, ignoring empty ones.
+fn parse_tags(text: &str) -> BTreeSet<String> {
+    text.split(',')
+        .map(|tag| tag.trim().to_string())
+        .filter(|tag| !tag.is_empty())
+        .collect()
+}
+
 // ------ ------
 //     View
 // ------ ------
@@ -75,10 +242,11 @@ fn view(model: &Model) -> Node<Msg> {
                 .notes
                 .notes[&model.selected_note_filename],
         ),
-        div![ul![model.notes.notes.keys().map(|filename| {
-            let selected_note_filename = filename.to_string();
-            li![filename, ev(Ev::Click, move |_| Msg::NoteSelected(selected_note_filename))]
-        })]]
+        note_preview(model),
+        attachment_picker(),
+        history_panel(model),
+        tag_filter(model),
+        note_list(model),
     ]
 }
 
@@ -94,9 +262,279 @@ fn note_editor(note: &model::Note) -> Node<Msg> {
             attrs! {At::Value => note.body.clone()},
             input_ev(Ev::Input, |value| { Msg::ContentsChanged(value) })
         ],
+        input![
+            C!["edit", "tags"],
+            attrs! {
+                At::Value => ", ".join(note.tags.iter()),
+                At::Placeholder => "tags, separated by commas",
+            },
+            input_ev(Ev::Input, |value| { Msg::TagsChanged(value) })
+        ],
+        label![
+            input![
+                attrs! {At::Type => "checkbox", At::Checked => note.pinned.as_at_value()},
+                ev(Ev::Change, |_| Msg::PinnedToggled)
+            ],
+            "Pinned"
+        ],
     ]
 }
 
+/// Body of the selected note with its embedded images, written `![description](path)` with a
+/// path relative to the note.
+fn note_preview(model: &Model) -> Node<Msg> {
+    let body = &model.notes.notes[&model.selected_note_filename].body;
+    let folder = model::parent_folder(&model.selected_note_filename);
+    let mut nodes = Vec::new();
+    let mut rest = body.as_str();
+    while let Some((before, description, path, after)) = next_image(rest) {
+        nodes.push(span![before]);
+        nodes.push(embedded_image(
+            model,
+            description,
+            &resolve_path(folder, path),
+        ));
+        rest = after;
+    }
+    nodes.push(span![rest]);
+    div![C!["preview"], style! {St::WhiteSpace => "pre-wrap"}, nodes]
+}
+
+/// Adds the chosen files as attachments in the folder of the selected note, where its body
+/// can embed them by their file name.
+fn attachment_picker() -> Node<Msg> {
+    input![
+        attrs! {At::Type => "file", At::Multiple => true.as_at_value()},
+        ev(Ev::Change, |event| {
+            let input: web_sys::HtmlInputElement = event.target()?.dyn_into().ok()?;
+            let files = input.files()?;
+            Some(Msg::FilesChosen(
+                (0..files.length())
+                    .filter_map(|index| files.get(index))
+                    .collect(),
+            ))
+        })
+    ]
+}
+
+/// Splits the text around its first image: text before, description, path and text after.
+fn next_image(text: &str) -> Option<(&str, &str, &str, &str)> {
+    let start = text.find("![")?;
+    let description_end = start + text[start..].find("](")?;
+    let path_end = description_end + text[description_end..].find(')')?;
+    Some((
+        &text[..start],
+        &text[start + 2..description_end],
+        &text[description_end + 2..path_end],
+        &text[path_end + 1..],
+    ))
+}
+
+fn embedded_image(model: &Model, description: &str, key: &str) -> Node<Msg> {
+    let attachment = model.notes.attachments.get(key);
+    match attachment.and_then(|attachment| Some((attachment, model.blobs.get(&attachment.hash)?))) {
+        Some((attachment, data)) if attachment.media_type.starts_with("image/") => img![attrs! {
+            At::Src => format!(
+                "data:{};base64,{}",
+                attachment.media_type,
+                base64::engine::general_purpose::STANDARD.encode(data)
+            ),
+            At::Alt => description,
+        }],
+        _ => span![C!["missing-image"], format!("[{}]", description)],
+    }
+}
+
+/// Key of the file at `path` relative to `folder`.
+fn resolve_path(folder: &str, path: &str) -> String {
+    let mut components: Vec<&str> = folder
+        .split('/')
+        .filter(|component| !component.is_empty())
+        .collect();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            component => components.push(component),
+        }
+    }
+    components.join("/")
+}
+
+/// Revisions of the selected note, most recent first, with the changes made by the selected
+/// one.
+fn history_panel(model: &Model) -> Node<Msg> {
+    let indexes: Vec<usize> = (0..model.history.len())
+        .filter(|index| model.history[*index].key == model.selected_note_filename)
+        .collect();
+    div![
+        C!["history"],
+        ul![indexes.iter().enumerate().rev().map(|(number, index)| {
+            let index = *index;
+            let revision = &model.history[index];
+            let selected = model.selected_revision == Some(index);
+            li![
+                C![IF!(selected => "selected")],
+                format!(
+                    "#{} {}, {}",
+                    number + 1,
+                    revision.author,
+                    format_time(revision.time)
+                ),
+                IF!(revision.note.is_none() => " (deleted)"),
+                ev(Ev::Click, move |_| {
+                    Msg::RevisionSelected(if selected { None } else { Some(index) })
+                })
+            ]
+        })],
+        model
+            .selected_revision
+            .map(|index| revision_changes(model, &indexes, index)),
+    ]
+}
+
+/// Differences between a revision and the previous one of the same note.
+fn revision_changes(model: &Model, indexes: &[usize], index: usize) -> Node<Msg> {
+    let previous_text = indexes
+        .iter()
+        .take_while(|other| **other < index)
+        .last()
+        .map(|previous| model.history[*previous].text())
+        .unwrap_or_default();
+    let lines = model::diff_lines(&previous_text, &model.history[index].text());
+    div![
+        C!["changes"],
+        style! {St::WhiteSpace => "pre-wrap", St::FontFamily => "monospace"},
+        lines.into_iter().map(|line| match line {
+            model::DiffLine::Same(line) => div![format!("  {}", line)],
+            model::DiffLine::Added(line) => {
+                div![
+                    C!["added"],
+                    style! {St::Color => "green"},
+                    format!("+ {}", line)
+                ]
+            }
+            model::DiffLine::Removed(line) => {
+                div![
+                    C!["removed"],
+                    style! {St::Color => "red"},
+                    format!("- {}", line)
+                ]
+            }
+        }),
+        IF!(model.history[index].note.is_some() => button![
+            "Restore",
+            ev(Ev::Click, move |_| Msg::RevisionRestored(index))
+        ]),
+    ]
+}
+
+/// Date and time in the locale of the browser.
+fn format_time(seconds: u64) -> String {
+    let date = js_sys::Date::new(&JsValue::from_f64(seconds as f64 * 1000.0));
+    String::from(date.to_locale_string("default", &JsValue::UNDEFINED))
+}
+
+fn tag_filter(model: &Model) -> Node<Msg> {
+    div![
+        C!["tags"],
+        ul![
+            li![
+                C![IF!(model.selected_tag.is_none() => "selected")],
+                "All",
+                ev(Ev::Click, |_| Msg::TagSelected(None))
+            ],
+            model.notes.tags().into_iter().map(|tag| {
+                let selected = model.selected_tag.as_ref() == Some(&tag);
+                li![
+                    C![IF!(selected => "selected")],
+                    format!("#{}", tag),
+                    ev(Ev::Click, move |_| Msg::TagSelected(Some(tag)))
+                ]
+            })
+        ]
+    ]
+}
+
+fn note_list(model: &Model) -> Node<Msg> {
+    let folders = model.notes.all_folders();
+    div![C!["folders"], folder_tree(model, &folders, "")]
+}
+
+/// Subfolders then notes of `folder`, the empty string being the root of the workspace.
+fn folder_tree(model: &Model, folders: &BTreeSet<String>, folder: &str) -> Node<Msg> {
+    // pinned notes first, then alphabetical order
+    let mut filenames: Vec<&String> = model
+        .notes
+        .notes
+        .iter()
+        .filter(|(filename, note)| {
+            model::parent_folder(filename) == folder && is_note_visible(model, note)
+        })
+        .map(|(filename, _)| filename)
+        .collect();
+    filenames.sort_by_key(|filename| (!model.notes.notes[*filename].pinned, *filename));
+    ul![
+        folders
+            .iter()
+            .filter(|subfolder| {
+                model::parent_folder(subfolder) == folder && is_folder_visible(model, subfolder)
+            })
+            .map(|subfolder| {
+                let collapsed = model.collapsed_folders.contains(subfolder);
+                let toggled_folder = subfolder.to_string();
+                li![
+                    span![
+                        C!["folder"],
+                        format!(
+                            "{} {}/",
+                            if collapsed { "▸" } else { "▾" },
+                            file_name(subfolder)
+                        ),
+                        ev(Ev::Click, move |_| Msg::FolderToggled(toggled_folder))
+                    ],
+                    IF!(!collapsed => folder_tree(model, folders, subfolder))
+                ]
+            }),
+        filenames.into_iter().map(|filename| {
+            let selected_note_filename = filename.to_string();
+            li![
+                C![IF!(*filename == model.selected_note_filename => "selected")],
+                IF!(model.notes.notes[filename].pinned => "📌 "),
+                file_name(filename),
+                ev(Ev::Click, move |_| Msg::NoteSelected(
+                    selected_note_filename
+                ))
+            ]
+        })
+    ]
+}
+
+fn is_note_visible(model: &Model, note: &model::Note) -> bool {
+    match &model.selected_tag {
+        Some(tag) => note.has_tag(tag),
+        None => true,
+    }
+}
+
+/// Folders are hidden when none of the notes they contain is visible.
+fn is_folder_visible(model: &Model, folder: &str) -> bool {
+    let prefix = format!("{}/", folder);
+    model.selected_tag.is_none()
+        || model
+            .notes
+            .notes
+            .iter()
+            .any(|(filename, note)| filename.starts_with(&prefix) && is_note_visible(model, note))
+}
+
+/// Last component of a note or folder path.
+fn file_name(key: &str) -> &str {
+    key.rsplit('/').next().unwrap_or(key)
+}
+
 // ------ ------
 //     Start
 // ------ ------